@@ -0,0 +1,46 @@
+use ferry_core::{Approver, TransferRequest, format_size};
+use std::io::{BufRead, Write};
+use std::sync::Mutex;
+
+/// Files listed before the rest is summed up
+const SHOWN_FILES: usize = 20;
+
+/// Asks on the `ferry serve` terminal, one request at a time
+#[derive(Debug, Default)]
+pub(crate) struct TerminalApprover {
+    prompt: Mutex<()>,
+}
+
+impl Approver for TerminalApprover {
+    fn approve(&self, request: &TransferRequest) -> bool {
+        let _prompt = self.prompt.lock().unwrap_or_else(|e| e.into_inner());
+        let mut stdout = std::io::stdout().lock();
+        let _ = writeln!(
+            stdout,
+            "{} ({}) wants to send {} files ({}):",
+            request.peer,
+            request.remote,
+            request.files.len(),
+            format_size(request.total_size())
+        );
+        for (path, size) in request.files.iter().take(SHOWN_FILES) {
+            let _ = writeln!(stdout, "  {path}  {}", format_size(*size));
+        }
+        if request.files.len() > SHOWN_FILES {
+            let _ = writeln!(
+                stdout,
+                "  ... and {} more",
+                request.files.len() - SHOWN_FILES
+            );
+        }
+        let _ = write!(stdout, "Accept? [y/N] ");
+        let _ = stdout.flush();
+
+        // No terminal to answer on counts as a no
+        let mut answer = String::new();
+        if std::io::stdin().lock().read_line(&mut answer).is_err() {
+            return false;
+        }
+        matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+    }
+}
@@ -1,7 +1,9 @@
 use anyhow::Result;
-pub fn connect(ip_address: &str, port: u16, server_name: Option<String>)->Result<()>{
-    // let client = ferry_core::Client::new();
-    // let server_name: Option<&str> = server_name.as_deref();
-    // client.connect(ip_address, port, server_name)
-    Ok(())
+pub fn connect(ip_address: &str, port: u16, server_name: Option<String>, code: Option<String>, transport: Option<ferry_core::TransportType>)->Result<()>{
+    let options = ferry_core::ClientOptions { pairing_code: code, ..Default::default() };
+    let client = ferry_core::Client::new(false)
+        .with_transports(&crate::send::transports_to_try(transport, None))
+        .with_options(options);
+    let server_name: Option<&str> = server_name.as_deref();
+    client.connect(ip_address, port, server_name)
 }
\ No newline at end of file
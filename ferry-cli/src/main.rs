@@ -1,10 +1,17 @@
+mod approve;
 mod discover;
 mod connect;
+mod remote;
+mod send;
 
 use crate::discover::discover;
 use clap::{Args, Parser, Subcommand};
 use std::path::PathBuf;
+use std::sync::Arc;
+use crate::approve::TerminalApprover;
 use crate::connect::connect;
+use crate::send::send;
+use crate::remote::{get, ls};
 
 #[derive(Parser)]
 #[command(name = "ferry", version, about, author)]
@@ -18,6 +25,11 @@ pub enum Commands {
     Serve(ServeArgs),
     Discover(DiscoverArgs),
     Connect(ConnectArgs),
+    Send(SendArgs),
+    /// List what a read-only server shares
+    Ls(LsArgs),
+    /// Download from a read-only server
+    Get(GetArgs),
 }
 
 #[derive(Args, Debug)]
@@ -37,28 +49,56 @@ pub struct ServeArgs {
     #[arg(long = "dir", default_value_os = ".")]
     pub dir: PathBuf,
 
+    /// Only listen on TCP + TLS (default: QUIC and TCP on the same port)
     #[arg(long = "tcp")]
     pub is_tcp_mode: bool,
-    // Following to be implemented later:
-    // /// Require this pairing code to accept connections
-    // #[arg(long = "code")]
-    // pub code: Option<String>,
-    //
-    // /// Auto-approve incoming file lists
-    // #[arg(long = "approve-all")]
-    // pub approve_all: bool,
-    //
-    // /// Expose listings but reject writes
-    // #[arg(long = "read-only", conflicts_with = "approve_all")]
-    // pub read_only: bool,
-    //
-    // /// Refuse transfers larger than this
-    // #[arg(long = "max-size")]
-    // pub max_size: Option<String>,
-    //
-    // /// Confirm you understand public exposure when using --host 0.0.0.0 without --code
-    // #[arg(long = "confirm-public")]
-    // pub confirm_public: bool,
+
+    /// Maximum number of clients served at the same time
+    #[arg(long = "max-connections", default_value_t = 16)]
+    pub max_connections: usize,
+
+    /// Require this pairing code to accept connections, a random one is generated when omitted
+    #[arg(long = "code", num_args = 0..=1, default_missing_value = "")]
+    pub code: Option<String>,
+
+    /// Data streams one QUIC client may open at once
+    #[arg(long = "max-streams", default_value_t = 32, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_streams: u32,
+
+    /// Cap on how fast all clients together may send, e.g. 20MB/s
+    #[arg(long = "limit", value_parser = ferry_core::parse_rate)]
+    pub limit: Option<u64>,
+
+    /// Accept incoming files without asking
+    #[arg(long = "approve-all")]
+    pub approve_all: bool,
+
+    /// Share the directory for `ferry ls` / `ferry get` and refuse uploads
+    #[arg(long = "read-only", conflicts_with = "approve_all")]
+    pub read_only: bool,
+
+    /// Refuse transfers larger than this, e.g. 2GB
+    #[arg(long = "max-size", value_parser = ferry_core::parse_size)]
+    pub max_size: Option<u64>,
+
+    /// Confirm you understand public exposure when using --host 0.0.0.0 without --code
+    #[arg(long = "confirm-public")]
+    pub confirm_public: bool,
+
+    /// Don't copy chunks the server already has from earlier uploads, have them sent instead
+    #[arg(long = "no-dedupe")]
+    pub no_dedupe: bool,
+
+    /// Most a client may ask for when a file's name is taken: overwrite, newer-wins, rename,
+    /// skip or fail. Clients asking for more get this.
+    #[arg(long = "on-conflict", value_name = "POLICY", default_value = "overwrite")]
+    pub on_conflict: ferry_core::ConflictPolicy,
+
+    #[command(flatten)]
+    pub preserve: PreserveArgs,
+
+    #[command(flatten)]
+    pub compress: CompressArgs,
 
     // TODO: Ferry server should have a name for discovery
 }
@@ -84,6 +124,215 @@ pub struct ConnectArgs {
     /// Bind port (default: 3625 = DOCK on T9)
     #[arg(short = 'p', long = "port", default_value_t = 3625u16)]
     pub port: u16,
+
+    /// Pairing code shown by the server
+    #[arg(long = "code")]
+    pub code: Option<String>,
+
+    /// Only connect over TCP + TLS (default: QUIC, falling back to TCP)
+    #[arg(long = "tcp", conflicts_with = "is_quic_mode")]
+    pub is_tcp_mode: bool,
+
+    /// Only connect over QUIC
+    #[arg(long = "quic")]
+    pub is_quic_mode: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct SendArgs {
+    /// Files or directories to send, directories are sent with everything in them
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
+
+    /// Server name (as shown by `ferry discover`) or address
+    #[arg(short = 't', long = "to")]
+    pub to: String,
+
+    /// Server port, used when `--to` is an address without one
+    #[arg(short = 'p', long = "port", default_value_t = 3625u16)]
+    pub port: u16,
+
+    /// How long to look for the server when `--to` is a name (ms)
+    #[arg(short = 'i', long = "interval", default_value_t = 2*1000)]
+    pub interval: u64,
+
+    /// Size of the chunks files are split into, e.g. 256KiB or 4MiB
+    #[arg(long = "chunk-size", default_value = "1MiB", value_parser = ferry_core::parse_size)]
+    pub chunk_size: u64,
+
+    /// Cut files where their content changes rather than every --chunk-size bytes (then the
+    /// average), so edited files mostly reuse what the server already has
+    #[arg(long = "cdc")]
+    pub cdc: bool,
+
+    /// Send files the server already has a version of as the differences to it
+    #[arg(long = "delta")]
+    pub delta: bool,
+
+    /// QUIC streams chunks are sent over in parallel, 1 sends everything in order
+    #[arg(long = "streams", default_value_t = 8, value_parser = clap::value_parser!(u64).range(1..))]
+    pub streams: u64,
+
+    /// Cap on how fast files are sent, e.g. 20MB/s
+    #[arg(long = "limit", value_parser = ferry_core::parse_rate)]
+    pub limit: Option<u64>,
+
+    /// Only send files in directories matching this glob, e.g. 'bin/**' (repeatable)
+    #[arg(long = "include", value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Leave out files and directories matching this glob, e.g. '*.o' (repeatable)
+    #[arg(long = "exclude", value_name = "GLOB")]
+    pub exclude: Vec<String>,
+
+    /// Leave out what .gitignore files ignore
+    #[arg(long = "gitignore")]
+    pub gitignore: bool,
+
+    /// Save the receipt the server signs for the files to this JSON file
+    #[arg(long = "receipt", value_name = "FILE")]
+    pub receipt: Option<PathBuf>,
+
+    /// What the server does with files whose name is taken: overwrite, skip, rename (to
+    /// `name (1).ext`), newer-wins or fail
+    #[arg(long = "on-conflict", value_name = "POLICY", default_value = "overwrite")]
+    pub on_conflict: ferry_core::ConflictPolicy,
+
+    #[command(flatten)]
+    pub preserve: PreserveArgs,
+
+    #[command(flatten)]
+    pub compress: CompressArgs,
+
+    /// Pairing code shown by the server
+    #[arg(long = "code")]
+    pub code: Option<String>,
+
+    /// Only connect over TCP + TLS (default: QUIC, falling back to TCP)
+    #[arg(long = "tcp", conflicts_with = "is_quic_mode")]
+    pub is_tcp_mode: bool,
+
+    /// Only connect over QUIC
+    #[arg(long = "quic")]
+    pub is_quic_mode: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct LsArgs {
+    /// `<server>:<path>`, the server being a name (as shown by `ferry discover`) or an address
+    pub remote: String,
+
+    #[command(flatten)]
+    pub server: RemoteArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct GetArgs {
+    /// `<server>:<path>`, the server being a name (as shown by `ferry discover`) or an address
+    pub remote: String,
+
+    /// Directory to download into
+    #[arg(default_value_os = ".")]
+    pub dest: PathBuf,
+
+    #[command(flatten)]
+    pub server: RemoteArgs,
+
+    /// QUIC streams the server may send on in parallel
+    #[arg(long = "streams", default_value_t = 8, value_parser = clap::value_parser!(u64).range(1..))]
+    pub streams: u64,
+
+    /// Cap on how fast files are received, e.g. 20MB/s
+    #[arg(long = "limit", value_parser = ferry_core::parse_rate)]
+    pub limit: Option<u64>,
+
+    /// What to do with files whose name is taken here: overwrite, skip, rename (to
+    /// `name (1).ext`), newer-wins or fail
+    #[arg(long = "on-conflict", value_name = "POLICY", default_value = "overwrite")]
+    pub on_conflict: ferry_core::ConflictPolicy,
+
+    #[command(flatten)]
+    pub preserve: PreserveArgs,
+
+    #[command(flatten)]
+    pub compress: CompressArgs,
+}
+
+/// Which metadata travels with the files, everything but extended attributes by default
+#[derive(Args, Debug)]
+pub struct PreserveArgs {
+    /// Don't carry over permission bits
+    #[arg(long = "no-perms")]
+    pub no_perms: bool,
+
+    /// Don't carry over modification times
+    #[arg(long = "no-times")]
+    pub no_times: bool,
+
+    /// Leave symlinks out instead of recreating them as links
+    #[arg(long = "no-links")]
+    pub no_links: bool,
+
+    /// Carry over extended attributes (user namespace)
+    #[cfg(feature = "xattr")]
+    #[arg(long = "xattrs")]
+    pub xattrs: bool,
+}
+
+impl PreserveArgs {
+    fn preserve(&self) -> ferry_core::Preserve {
+        ferry_core::Preserve {
+            permissions: !self.no_perms,
+            times: !self.no_times,
+            symlinks: !self.no_links,
+            #[cfg(feature = "xattr")]
+            xattrs: self.xattrs,
+            #[cfg(not(feature = "xattr"))]
+            xattrs: false,
+        }
+    }
+}
+
+/// How chunks are compressed on the way, with zstd unless either side turns it off
+#[derive(Args, Debug)]
+pub struct CompressArgs {
+    /// Codec for the chunks we send: zstd, or lz4 when built with it
+    #[arg(long = "compress", default_value = "zstd")]
+    pub compress: ferry_core::Compression,
+
+    /// Send chunks as they are and ask the other side to do the same
+    #[arg(long = "no-compress", conflicts_with = "compress")]
+    pub no_compress: bool,
+}
+
+impl CompressArgs {
+    fn compression(&self) -> Option<ferry_core::Compression> {
+        (!self.no_compress).then_some(self.compress)
+    }
+}
+
+/// How to reach a server for `ls` and `get`
+#[derive(Args, Debug)]
+pub struct RemoteArgs {
+    /// Server port, used when the server is an address without one
+    #[arg(short = 'p', long = "port", default_value_t = 3625u16)]
+    pub port: u16,
+
+    /// How long to look for the server when it is given by name (ms)
+    #[arg(short = 'i', long = "interval", default_value_t = 2*1000)]
+    pub interval: u64,
+
+    /// Pairing code shown by the server
+    #[arg(long = "code")]
+    pub code: Option<String>,
+
+    /// Only connect over TCP + TLS (default: QUIC, falling back to TCP)
+    #[arg(long = "tcp", conflicts_with = "is_quic_mode")]
+    pub is_tcp_mode: bool,
+
+    /// Only connect over QUIC
+    #[arg(long = "quic")]
+    pub is_quic_mode: bool,
 }
 
 fn main() {
@@ -91,7 +340,33 @@ fn main() {
 
     match cli.command {
         Commands::Serve(args) => {
-            let ferry_server = ferry_core::Server::new(args.is_tcp_mode, args.host.clone(), args.port, &args.dir, args.name.clone());
+            let pairing_code = args.code.map(|code| match code.is_empty() {
+                true => ferry_core::generate_pairing_code(),
+                false => code,
+            });
+            if let Some(code) = &pairing_code {
+                println!("Pairing code: {code}");
+            }
+            let options = ferry_core::ServerOptions {
+                max_connections: args.max_connections,
+                pairing_code,
+                max_streams: args.max_streams,
+                limit: args.limit,
+                approver: match args.approve_all || args.read_only {
+                    true => None,
+                    false => Some(Arc::new(TerminalApprover::default())),
+                },
+                read_only: args.read_only,
+                max_size: args.max_size,
+                confirm_public: args.confirm_public,
+                preserve: args.preserve.preserve(),
+                dedupe: !args.no_dedupe,
+                compression: args.compress.compression(),
+                conflict: args.on_conflict,
+                ..Default::default()
+            };
+            let ferry_server = ferry_core::Server::new(args.is_tcp_mode, args.host.clone(), args.port, &args.dir, args.name.clone())
+                .with_options(options);
             let res = ferry_server.serve();
             println!("{res:?}")
         }
@@ -99,8 +374,64 @@ fn main() {
             discover(args.all, args.interval).expect("Failed to run discovery");
         }
         Commands::Connect(args) => {
-            let res = connect(&args.host, args.port, args.name);
+            let res = connect(&args.host, args.port, args.name, args.code, forced_transport(args.is_tcp_mode, args.is_quic_mode));
             println!("{res:?}")
         }
+        Commands::Send(args) => {
+            let options = ferry_core::ClientOptions {
+                chunk_size: args.chunk_size as usize,
+                content_defined: args.cdc,
+                delta: args.delta,
+                pairing_code: args.code,
+                streams: args.streams as usize,
+                limit: args.limit,
+                include: args.include,
+                exclude: args.exclude,
+                gitignore: args.gitignore,
+                preserve: args.preserve.preserve(),
+                compression: args.compress.compression(),
+                conflict: args.on_conflict,
+                ..Default::default()
+            };
+            if let Err(e) = send(&args.paths, &args.to, args.port, args.interval, forced_transport(args.is_tcp_mode, args.is_quic_mode), options, args.receipt.as_deref()) {
+                eprintln!("Error: {e:#}");
+                std::process::exit(1);
+            }
+        }
+        Commands::Ls(args) => {
+            let server = args.server;
+            let options = ferry_core::ClientOptions { pairing_code: server.code, ..Default::default() };
+            let transport = forced_transport(server.is_tcp_mode, server.is_quic_mode);
+            if let Err(e) = ls(&args.remote, server.port, server.interval, transport, options) {
+                eprintln!("Error: {e:#}");
+                std::process::exit(1);
+            }
+        }
+        Commands::Get(args) => {
+            let server = args.server;
+            let options = ferry_core::ClientOptions {
+                pairing_code: server.code,
+                streams: args.streams as usize,
+                limit: args.limit,
+                preserve: args.preserve.preserve(),
+                compression: args.compress.compression(),
+                conflict: args.on_conflict,
+                ..Default::default()
+            };
+            let transport = forced_transport(server.is_tcp_mode, server.is_quic_mode);
+            if let Err(e) = get(&args.remote, &args.dest, server.port, server.interval, transport, options) {
+                eprintln!("Error: {e:#}");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Transport picked with `--tcp` / `--quic`, `None` lets the client try both
+fn forced_transport(is_tcp: bool, is_quic: bool) -> Option<ferry_core::TransportType> {
+    match (is_tcp, is_quic) {
+        (true, _) => Some(ferry_core::TransportType::Tcp),
+        (_, true) => Some(ferry_core::TransportType::Quic),
+        _ => None,
     }
 }
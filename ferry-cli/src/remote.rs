@@ -0,0 +1,96 @@
+use crate::send::client_for;
+use anyhow::bail;
+use ferry_core::TransportType;
+use std::net::SocketAddr;
+use std::path::Path;
+
+/// Splits `<server>:<path>`. The server is a name or an address, addresses may carry a port
+/// (`10.0.0.2:3625:docs`, `[fe80::1]:3625:docs`). The path is relative to the shared directory.
+fn parse_remote(spec: &str) -> anyhow::Result<(&str, &str)> {
+    let with_port = spec
+        .match_indices(':')
+        .map(|(i, _)| i)
+        .chain([spec.len()])
+        .find(|&i| spec[..i].parse::<SocketAddr>().is_ok());
+    let (server, path) = match with_port {
+        Some(i) => (&spec[..i], spec.get(i + 1..).unwrap_or("")),
+        None => spec.split_once(':').unwrap_or((spec, "")),
+    };
+    if server.is_empty() {
+        bail!("expected <server>:<path>, got {spec:?}");
+    }
+    Ok((server, path))
+}
+
+pub(crate) fn ls(
+    spec: &str,
+    port: u16,
+    interval: u64,
+    transport: Option<TransportType>,
+    options: ferry_core::ClientOptions,
+) -> anyhow::Result<()> {
+    let (server, path) = parse_remote(spec)?;
+    let (client, addrs) = client_for(server, port, interval, transport, options)?;
+    for entry in client.list(&addrs, path)? {
+        match entry.is_dir {
+            true => println!("{:>10}  {}/", "-", entry.name),
+            false => println!(
+                "{:>10}  {}",
+                ferry_core::format_size(entry.size),
+                entry.name
+            ),
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn get(
+    spec: &str,
+    dest: &Path,
+    port: u16,
+    interval: u64,
+    transport: Option<TransportType>,
+    options: ferry_core::ClientOptions,
+) -> anyhow::Result<()> {
+    let (server, path) = parse_remote(spec)?;
+    let (client, addrs) = client_for(server, port, interval, transport, options)?;
+    let report = client.get(&addrs, path, dest)?;
+    println!(
+        "Received {} files ({}) in {:.2?} ({:.1} MB/s)",
+        report.files,
+        ferry_core::format_size(report.bytes),
+        report.elapsed,
+        report.rate() / 1_000_000.0
+    );
+    if report.wire < report.bytes {
+        println!(
+            "Compressed: the {} took {} on the connection ({:.1}x)",
+            ferry_core::format_size(report.bytes),
+            ferry_core::format_size(report.wire),
+            report.compression_ratio()
+        );
+    }
+    if report.skipped > 0 {
+        println!(
+            "Resumed: {} were already here",
+            ferry_core::format_size(report.skipped)
+        );
+    }
+    if report.kept() > 0 {
+        println!(
+            "Kept: {} files were already here and left as they are",
+            report.kept()
+        );
+    }
+    for path in report.renamed() {
+        println!("Renamed: the name was taken, saved as {path}");
+    }
+    if report.holes > 0 {
+        println!(
+            "Sparse: {} of the {} were holes and not sent",
+            ferry_core::format_size(report.holes),
+            ferry_core::format_size(report.logical)
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,152 @@
+use anyhow::{Context, bail};
+use ferry_core::TransportType;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub(crate) fn send(
+    paths: &[PathBuf],
+    to: &str,
+    port: u16,
+    interval: u64,
+    transport: Option<TransportType>,
+    options: ferry_core::ClientOptions,
+    receipt: Option<&Path>,
+) -> anyhow::Result<()> {
+    let (client, addrs) = client_for(to, port, interval, transport, options)?;
+    let report = client.send(&addrs, paths)?;
+    println!(
+        "Sent {} files ({}) in {:.2?} ({:.1} MB/s)",
+        report.files,
+        ferry_core::format_size(report.bytes),
+        report.elapsed,
+        report.rate() / 1_000_000.0
+    );
+    if report.wire < report.bytes {
+        println!(
+            "Compressed: the {} took {} on the connection ({:.1}x)",
+            ferry_core::format_size(report.bytes),
+            ferry_core::format_size(report.wire),
+            report.compression_ratio()
+        );
+    }
+    if report.skipped > 0 {
+        println!(
+            "Skipped: {} were already on the server, from an earlier attempt or other files",
+            ferry_core::format_size(report.skipped)
+        );
+    }
+    if report.matched > 0 {
+        println!(
+            "Delta: {} matched what the server had and was not sent",
+            ferry_core::format_size(report.matched)
+        );
+    }
+    if report.kept() > 0 {
+        println!(
+            "Kept: {} files were already on the server and left as they are",
+            report.kept()
+        );
+    }
+    for path in report.renamed() {
+        println!("Renamed: the name was taken, arrived as {path}");
+    }
+    if report.holes > 0 {
+        println!(
+            "Sparse: {} of the {} were holes and not sent",
+            ferry_core::format_size(report.holes),
+            ferry_core::format_size(report.logical)
+        );
+    }
+    if let (Some(path), Some(signed)) = (receipt, &report.receipt) {
+        std::fs::write(path, signed.to_json() + "\n")
+            .with_context(|| format!("cannot save the receipt to {}", path.display()))?;
+        println!(
+            "Receipt signed by {} saved to {}",
+            signed.receiver,
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Finds the server `to` refers to and sets up a client for it, returns the addresses to try
+pub(crate) fn client_for(
+    to: &str,
+    port: u16,
+    interval: u64,
+    transport: Option<TransportType>,
+    mut options: ferry_core::ClientOptions,
+) -> anyhow::Result<(ferry_core::Client, Vec<SocketAddr>)> {
+    let server = resolve_server(to, port, interval)?;
+    options.fingerprint = server.fingerprint;
+    options.host = Some(server.host);
+    let client = ferry_core::Client::new(false)
+        .with_transports(&transports_to_try(transport, server.transports))
+        .with_options(options);
+    Ok((client, server.addrs))
+}
+
+/// The forced transport, or whatever the server advertised, or QUIC with TCP as a fallback
+pub(crate) fn transports_to_try(
+    forced: Option<TransportType>,
+    advertised: Option<Vec<TransportType>>,
+) -> Vec<TransportType> {
+    match (forced, advertised) {
+        (Some(transport), _) => vec![transport],
+        (None, Some(advertised)) => advertised,
+        (None, None) => vec![TransportType::Quic, TransportType::Tcp],
+    }
+}
+
+/// Where to find the server and what it told us about itself over mDNS
+struct ResolvedServer {
+    /// What the server's certificate is pinned under
+    host: String,
+    /// Most promising first, all of them are tried
+    addrs: Vec<SocketAddr>,
+    fingerprint: Option<ferry_core::Fingerprint>,
+    transports: Option<Vec<TransportType>>,
+}
+
+impl ResolvedServer {
+    fn at(addr: SocketAddr) -> Self {
+        Self {
+            host: addr.to_string(),
+            addrs: vec![addr],
+            fingerprint: None,
+            transports: None,
+        }
+    }
+}
+
+/// `to` is either an address (`10.0.0.2`, `10.0.0.2:3625`) or the name of a discoverable server.
+/// Discovered servers also tell us which certificate fingerprint and transports to expect.
+fn resolve_server(to: &str, port: u16, interval: u64) -> anyhow::Result<ResolvedServer> {
+    if let Ok(addr) = to.parse::<SocketAddr>() {
+        return Ok(ResolvedServer::at(addr));
+    }
+    if let Ok(ip) = to.parse::<IpAddr>() {
+        return Ok(ResolvedServer::at(SocketAddr::new(ip, port)));
+    }
+
+    println!("Looking for {to}...");
+    let services = ferry_core::discover_ferry_services(Duration::from_millis(interval))?;
+    let Some(mut service) = services
+        .into_iter()
+        .find(|s| s.instance.eq_ignore_ascii_case(to))
+    else {
+        bail!("no ferry server named {to} found, try `ferry discover`");
+    };
+    service.sort_addrs_by_preference();
+    if service.addrs.is_empty() {
+        bail!("{to} did not advertise any address");
+    }
+    Ok(ResolvedServer {
+        // Names match whatever their case, so pin them one way
+        host: to.to_ascii_lowercase(),
+        addrs: service.addrs.clone(),
+        fingerprint: service.fingerprint(),
+        transports: service.transports(),
+    })
+}
@@ -0,0 +1,475 @@
+mod cdc;
+mod sparse;
+
+use crate::protocol::{ChunkInfo, FileEntry, Hash};
+use anyhow::{Context, bail};
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+pub const MIN_CHUNK_SIZE: usize = 4 * 1024;
+/// Has to stay well below the protocol's frame limit
+pub const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// How a file is cut up: its data in chunks, and the holes between them that are never read
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Layout {
+    pub(crate) size: u64,
+    pub(crate) chunks: Vec<ChunkInfo>,
+    pub(crate) holes: Vec<Range<u64>>,
+    /// BLAKE3 of the whole file, holes read as zeros, the same as `b3sum` gives
+    pub(crate) hash: Hash,
+}
+
+/// Cuts files into chunks and hashes each one
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Chunker {
+    chunk_size: usize,
+    /// Cut where the content says, with `chunk_size` as the average, instead of every
+    /// `chunk_size` bytes
+    content_defined: bool,
+}
+
+impl Chunker {
+    pub(crate) fn new(chunk_size: usize) -> anyhow::Result<Self> {
+        if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&chunk_size) {
+            bail!(
+                "chunk size must be between {MIN_CHUNK_SIZE} and {MAX_CHUNK_SIZE} bytes, got {chunk_size}"
+            );
+        }
+        Ok(Self {
+            chunk_size,
+            content_defined: false,
+        })
+    }
+
+    pub(crate) fn with_content_defined(mut self, content_defined: bool) -> Self {
+        self.content_defined = content_defined;
+        self
+    }
+
+    fn cdc_sizes(&self) -> cdc::Sizes {
+        cdc::Sizes::around(self.chunk_size, MAX_CHUNK_SIZE)
+    }
+
+    /// The most a chunk can hold
+    fn max_len(&self) -> usize {
+        match self.content_defined {
+            true => self.cdc_sizes().max,
+            false => self.chunk_size,
+        }
+    }
+
+    /// Length of the chunk at the start of `data`, which is either `max_len` long or the rest
+    /// of the input
+    fn cut(&self, data: &[u8]) -> usize {
+        match self.content_defined {
+            true => cdc::cut(data, self.cdc_sizes()),
+            false => data.len().min(self.chunk_size),
+        }
+    }
+
+    /// Reads the file once and returns its layout. Holes the filesystem reports are left out,
+    /// only the data around them is read and chunked.
+    pub(crate) fn chunk_file(&self, path: &Path) -> anyhow::Result<Layout> {
+        let file = std::fs::File::open(path).with_context(|| format!("open {}", path.display()))?;
+        self.chunk_sparse(file)
+            .with_context(|| format!("read {}", path.display()))
+    }
+
+    fn chunk_sparse(&self, mut file: std::fs::File) -> anyhow::Result<Layout> {
+        let size = file.metadata()?.len();
+        let holes = sparse::holes(&file, size);
+        let mut chunks = Vec::new();
+        let mut hasher = blake3::Hasher::new();
+        let mut offset = 0;
+        for data in sparse::data_between(&holes, size) {
+            hash_zeros(&mut hasher, data.start - offset);
+            file.seek(SeekFrom::Start(data.start))?;
+            let read = self.chunk_into(
+                (&mut file).take(data.end - data.start),
+                data.start,
+                &mut chunks,
+                &mut hasher,
+            )?;
+            if read != data.end - data.start {
+                bail!("file shrank while being read");
+            }
+            offset = data.end;
+        }
+        hash_zeros(&mut hasher, size - offset);
+        Ok(Layout {
+            size,
+            chunks,
+            holes,
+            hash: *hasher.finalize().as_bytes(),
+        })
+    }
+
+    #[cfg(test)]
+    fn chunk_reader<R: Read>(&self, reader: R) -> anyhow::Result<Vec<ChunkInfo>> {
+        let mut chunks = Vec::new();
+        self.chunk_into(reader, 0, &mut chunks, &mut blake3::Hasher::new())?;
+        Ok(chunks)
+    }
+
+    /// Chunks everything `reader` has, which starts at `start` in the file, onto `chunks` and
+    /// feeds it to `file_hash`. Returns how many bytes that was.
+    fn chunk_into<R: Read>(
+        &self,
+        mut reader: R,
+        start: u64,
+        chunks: &mut Vec<ChunkInfo>,
+        file_hash: &mut blake3::Hasher,
+    ) -> anyhow::Result<u64> {
+        let mut buf = vec![0u8; self.max_len()];
+        let mut filled = 0;
+        let mut offset = start;
+        loop {
+            filled += read_full(&mut reader, &mut buf[filled..])?;
+            if filled == 0 {
+                break;
+            }
+            let len = self.cut(&buf[..filled]);
+            chunks.push(ChunkInfo {
+                offset,
+                len: len as u32,
+                hash: hash(&buf[..len]),
+            });
+            file_hash.update(&buf[..len]);
+            // Whatever follows the cut starts the next chunk
+            buf.copy_within(len..filled, 0);
+            filled -= len;
+            offset += len as u64;
+        }
+        Ok(offset - start)
+    }
+}
+
+impl Default for Chunker {
+    fn default() -> Self {
+        Self {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            content_defined: false,
+        }
+    }
+}
+
+/// Fills `buf` unless the reader runs dry first, returns how much was read
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+pub(crate) fn hash(data: &[u8]) -> Hash {
+    *blake3::hash(data).as_bytes()
+}
+
+/// True when `data` is exactly what the manifest announced for this chunk
+pub(crate) fn verify(info: &ChunkInfo, data: &[u8]) -> bool {
+    data.len() == info.len as usize && hash(data) == info.hash
+}
+
+/// Reads a chunk back from the source file, making sure it still matches the manifest
+pub(crate) async fn read_chunk(
+    file: &mut tokio::fs::File,
+    info: &ChunkInfo,
+) -> anyhow::Result<Vec<u8>> {
+    let mut data = vec![0u8; info.len as usize];
+    file.seek(SeekFrom::Start(info.offset)).await?;
+    file.read_exact(&mut data).await?;
+    if !verify(info, &data) {
+        bail!(
+            "file changed while being sent (chunk at {} no longer matches)",
+            info.offset
+        );
+    }
+    Ok(data)
+}
+
+/// The whole file hash of [`Layout::hash`] for the first `size` bytes of `file`. Only what the
+/// filesystem reports as holes is skipped, so whatever sits where the sender said there
+/// would be holes gets read like the rest.
+pub(crate) async fn hash_file(file: &mut tokio::fs::File, size: u64) -> anyhow::Result<Hash> {
+    let copy = file.try_clone().await?.into_std().await;
+    let holes = tokio::task::spawn_blocking(move || sparse::holes(&copy, size)).await?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0u8; DEFAULT_CHUNK_SIZE];
+    let mut offset = 0;
+    for data in sparse::data_between(&holes, size) {
+        hash_zeros(&mut hasher, data.start - offset);
+        file.seek(SeekFrom::Start(data.start)).await?;
+        let mut left = data.end - data.start;
+        while left > 0 {
+            let len = left.min(buf.len() as u64) as usize;
+            file.read_exact(&mut buf[..len]).await?;
+            hasher.update(&buf[..len]);
+            left -= len as u64;
+        }
+        offset = data.end;
+    }
+    hash_zeros(&mut hasher, size - offset);
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// Feeds `len` zero bytes to `hasher`, which is what holes read as
+fn hash_zeros(hasher: &mut blake3::Hasher, mut len: u64) {
+    static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+    while len > 0 {
+        let n = len.min(ZEROS.len() as u64) as usize;
+        hasher.update(&ZEROS[..n]);
+        len -= n as u64;
+    }
+}
+
+/// Checks that the chunks and holes of an entry are sane and cover the whole file, in order
+pub(crate) fn validate_layout(entry: &FileEntry) -> Result<(), String> {
+    let mut holes = entry.holes.iter().peekable();
+    let mut expected_offset = 0u64;
+    for (index, chunk) in entry.chunks.iter().enumerate() {
+        expected_offset = skip_holes(&mut holes, expected_offset);
+        if chunk.offset != expected_offset {
+            return Err(format!(
+                "chunk {index} of {} starts at {}, expected {expected_offset}",
+                entry.path, chunk.offset
+            ));
+        }
+        if chunk.len == 0 || chunk.len as usize > MAX_CHUNK_SIZE {
+            return Err(format!(
+                "chunk {index} of {} has invalid length {}",
+                entry.path, chunk.len
+            ));
+        }
+        expected_offset += chunk.len as u64;
+    }
+    expected_offset = skip_holes(&mut holes, expected_offset);
+    if holes.next().is_some() {
+        return Err(format!(
+            "holes of {} are empty, out of order or overlap its chunks",
+            entry.path
+        ));
+    }
+    if expected_offset != entry.size {
+        return Err(format!(
+            "chunks of {} cover {expected_offset} bytes, file has {}",
+            entry.path, entry.size
+        ));
+    }
+    Ok(())
+}
+
+/// Moves `offset` past the holes starting right there
+fn skip_holes<'a>(
+    holes: &mut std::iter::Peekable<impl Iterator<Item = &'a Range<u64>>>,
+    mut offset: u64,
+) -> u64 {
+    while let Some(hole) = holes.next_if(|h| h.start == offset && h.end > h.start) {
+        offset = hole.end;
+    }
+    offset
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 256) as u8).collect()
+    }
+
+    #[test]
+    fn chunks_cover_input_exactly() {
+        let chunker = Chunker::new(MIN_CHUNK_SIZE).unwrap();
+        let input = data(MIN_CHUNK_SIZE * 3 + 100);
+        let chunks = chunker.chunk_reader(&input[..]).unwrap();
+
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks[3].offset, (MIN_CHUNK_SIZE * 3) as u64);
+        assert_eq!(chunks[3].len, 100);
+        for c in &chunks {
+            let slice = &input[c.offset as usize..c.offset as usize + c.len as usize];
+            assert!(verify(c, slice));
+        }
+        let entry = FileEntry {
+            path: "f".into(),
+            size: input.len() as u64,
+            chunks,
+            holes: Vec::new(),
+            meta: Default::default(),
+            hash: [0; 32],
+        };
+        assert_eq!(validate_layout(&entry), Ok(()));
+    }
+
+    #[test]
+    fn content_defined_chunks_cover_input_exactly() {
+        let chunker = Chunker::new(MIN_CHUNK_SIZE)
+            .unwrap()
+            .with_content_defined(true);
+        let input: Vec<u8> = (0..200_000u64)
+            .map(|i| (i.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 56) as u8)
+            .collect();
+        let chunks = chunker.chunk_reader(&input[..]).unwrap();
+        assert!(chunks.len() > 10);
+        assert!(chunks.iter().any(|c| c.len as usize != MIN_CHUNK_SIZE));
+        let entry = FileEntry {
+            path: "f".into(),
+            size: input.len() as u64,
+            chunks,
+            holes: Vec::new(),
+            meta: Default::default(),
+            hash: [0; 32],
+        };
+        assert_eq!(validate_layout(&entry), Ok(()));
+        for c in &entry.chunks {
+            assert!(verify(c, &input[c.offset as usize..][..c.len as usize]));
+        }
+    }
+
+    #[test]
+    fn empty_input_has_no_chunks() {
+        let chunks = Chunker::default().chunk_reader(&[][..]).unwrap();
+        assert!(chunks.is_empty());
+    }
+
+    #[test]
+    fn verify_catches_flipped_bit_and_wrong_length() {
+        let input = data(1000);
+        let info = ChunkInfo {
+            offset: 0,
+            len: 1000,
+            hash: hash(&input),
+        };
+        let mut bad = input.clone();
+        bad[500] ^= 1;
+        assert!(!verify(&info, &bad));
+        assert!(!verify(&info, &input[..999]));
+        assert!(verify(&info, &input));
+    }
+
+    #[test]
+    fn chunk_size_is_bounded() {
+        assert!(Chunker::new(MIN_CHUNK_SIZE - 1).is_err());
+        assert!(Chunker::new(MAX_CHUNK_SIZE + 1).is_err());
+    }
+
+    #[test]
+    fn layout_with_gaps_is_rejected() {
+        let chunk = |offset, len| ChunkInfo {
+            offset,
+            len,
+            hash: [0; 32],
+        };
+        let gap = FileEntry {
+            path: "f".into(),
+            size: 20,
+            chunks: vec![chunk(0, 10), chunk(11, 9)],
+            holes: Vec::new(),
+            meta: Default::default(),
+            hash: [0; 32],
+        };
+        assert!(validate_layout(&gap).is_err());
+        let short = FileEntry {
+            path: "f".into(),
+            size: 20,
+            chunks: vec![chunk(0, 10)],
+            holes: Vec::new(),
+            meta: Default::default(),
+            hash: [0; 32],
+        };
+        assert!(validate_layout(&short).is_err());
+    }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn holes_count_towards_the_layout() {
+        let entry = |holes: Vec<Range<u64>>| FileEntry {
+            path: "f".into(),
+            size: 30,
+            chunks: vec![ChunkInfo {
+                offset: 10,
+                len: 10,
+                hash: [0; 32],
+            }],
+            holes,
+            meta: Default::default(),
+            hash: [0; 32],
+        };
+        assert_eq!(validate_layout(&entry(vec![0..10, 20..30])), Ok(()));
+        assert_eq!(validate_layout(&entry(vec![0..5, 5..10, 20..30])), Ok(()));
+        assert!(validate_layout(&entry(vec![0..10])).is_err());
+        assert!(validate_layout(&entry(vec![0..10, 15..30])).is_err());
+        assert!(validate_layout(&entry(vec![0..10, 20..20, 20..30])).is_err());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn holes_are_not_read() {
+        use std::io::Write;
+        let mut file = tempfile::tempfile().unwrap();
+        let hole = 4 * sparse::MIN_HOLE;
+        file.seek(SeekFrom::Start(hole)).unwrap();
+        file.write_all(&data(MIN_CHUNK_SIZE)).unwrap();
+        file.set_len(3 * hole).unwrap();
+
+        let layout = Chunker::new(MIN_CHUNK_SIZE)
+            .unwrap()
+            .chunk_sparse(file)
+            .unwrap();
+        assert_eq!(layout.size, 3 * hole);
+        let mut whole = vec![0u8; 3 * hole as usize];
+        whole[hole as usize..][..MIN_CHUNK_SIZE].copy_from_slice(&data(MIN_CHUNK_SIZE));
+        assert_eq!(layout.hash, hash(&whole));
+        if layout.holes.is_empty() {
+            // The filesystem doesn't do sparse files
+            return;
+        }
+        assert_eq!(layout.holes.first().unwrap().start, 0);
+        assert_eq!(layout.holes.last().unwrap().end, 3 * hole);
+        let data: u64 = layout.chunks.iter().map(|c| c.len as u64).sum();
+        assert!(data < hole, "{data} bytes of data");
+        let entry = FileEntry {
+            path: "f".into(),
+            size: layout.size,
+            chunks: layout.chunks,
+            holes: layout.holes,
+            meta: Default::default(),
+            hash: layout.hash,
+        };
+        assert_eq!(validate_layout(&entry), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn whole_file_hashes_read_what_is_claimed_to_be_holes() {
+        use std::io::Write;
+        let file = tempfile::tempfile().unwrap();
+        let hole = 4 * sparse::MIN_HOLE;
+        file.set_len(2 * hole).unwrap();
+        let mut file = tokio::fs::File::from_std(file);
+        let zeros = vec![0u8; 2 * hole as usize];
+        assert_eq!(hash_file(&mut file, 2 * hole).await.unwrap(), hash(&zeros));
+
+        // Data written into a range the manifest calls a hole still counts
+        let mut std = file.into_std().await;
+        std.seek(SeekFrom::Start(hole)).unwrap();
+        std.write_all(b"not a hole").unwrap();
+        let mut file = tokio::fs::File::from_std(std);
+        let mut expected = zeros;
+        expected[hole as usize..][..10].copy_from_slice(b"not a hole");
+        assert_eq!(
+            hash_file(&mut file, 2 * hole).await.unwrap(),
+            hash(&expected)
+        );
+    }
+}
@@ -0,0 +1,124 @@
+// Content defined chunking after FastCDC (Xia et al., 2016). A gear hash rolls over the data
+// and a chunk ends where its top bits are all zero, so cut points depend on the bytes around them
+// rather than on their offset: inserting a byte only changes the chunks next to it.
+
+/// Random values per byte, fixed so the same data is always cut the same way
+const GEAR: [u64; 256] = {
+    // splitmix64
+    let mut table = [0u64; 256];
+    let mut state = 0u64;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Chunks average `avg` bytes and stay between a quarter and four times that, `max` included
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Sizes {
+    pub(crate) min: usize,
+    pub(crate) avg: usize,
+    pub(crate) max: usize,
+}
+
+impl Sizes {
+    pub(crate) fn around(avg: usize, max: usize) -> Self {
+        Self {
+            min: avg / 4,
+            avg,
+            max: (avg * 4).min(max),
+        }
+    }
+}
+
+/// Length of the chunk at the start of `data`. `data` holds at least `max` bytes unless it is
+/// the end of the input.
+pub(crate) fn cut(data: &[u8], sizes: Sizes) -> usize {
+    let end = data.len().min(sizes.max);
+    if end <= sizes.min {
+        return end;
+    }
+    // Harder to match before the average and easier after, which keeps sizes close to it
+    let bits = sizes.avg.ilog2();
+    let (strict, loose) = (top_bits(bits + 1), top_bits(bits - 1));
+    let mut hash = 0u64;
+    for (i, &byte) in data.iter().enumerate().take(end).skip(sizes.min) {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        let mask = if i < sizes.avg { strict } else { loose };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// The top bits of the hash depend on the last 64 bytes, the low ones only on the last few
+fn top_bits(bits: u32) -> u64 {
+    !0u64 << (64 - bits)
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (state >> 33) as u8
+            })
+            .collect()
+    }
+
+    fn cuts(mut data: &[u8], sizes: Sizes) -> Vec<usize> {
+        let mut lens = Vec::new();
+        while !data.is_empty() {
+            let len = cut(data, sizes);
+            lens.push(len);
+            data = &data[len..];
+        }
+        lens
+    }
+
+    #[test]
+    fn chunks_stay_within_bounds_and_near_the_average() {
+        let sizes = Sizes::around(8192, usize::MAX);
+        let lens = cuts(&noise(4 << 20, 1), sizes);
+        let (last, rest) = lens.split_last().unwrap();
+        assert!(*last <= sizes.max);
+        assert!(rest.iter().all(|len| (sizes.min..=sizes.max).contains(len)));
+        let avg = (4 << 20) / lens.len();
+        assert!((4096..16384).contains(&avg), "average {avg}");
+    }
+
+    #[test]
+    fn an_insertion_only_changes_nearby_chunks() {
+        let sizes = Sizes::around(8192, usize::MAX);
+        let data = noise(1 << 20, 2);
+        let mut edited = data.clone();
+        edited.insert(300_000, 0x42);
+
+        let chunks = |data: &[u8]| {
+            let mut offset = 0;
+            cuts(data, sizes)
+                .into_iter()
+                .map(|len| {
+                    offset += len;
+                    data[offset - len..offset].to_vec()
+                })
+                .collect::<Vec<_>>()
+        };
+        let before = chunks(&data);
+        let after = chunks(&edited);
+        let changed = after.iter().filter(|c| !before.contains(c)).count();
+        assert!(changed <= 3, "{changed} of {} chunks changed", after.len());
+    }
+}
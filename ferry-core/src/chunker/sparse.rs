@@ -0,0 +1,81 @@
+use std::fs::File;
+use std::ops::Range;
+
+/// Holes shorter than this are read like data, cutting chunks around them isn't worth it
+pub(crate) const MIN_HOLE: u64 = 64 * 1024;
+
+/// The holes in the first `size` bytes of `file`, in order. None when the platform or the
+/// filesystem can't tell, the whole file is data then.
+pub(crate) fn holes(file: &File, size: u64) -> Vec<Range<u64>> {
+    find_holes(file, size).unwrap_or_else(|e| {
+        log::debug!("cannot look for holes, reading the whole file: {e}");
+        Vec::new()
+    })
+}
+
+/// What is left of `0..size` between `holes`
+pub(crate) fn data_between(holes: &[Range<u64>], size: u64) -> Vec<Range<u64>> {
+    let mut data = Vec::with_capacity(holes.len() + 1);
+    let mut offset = 0;
+    for hole in holes {
+        if hole.start > offset {
+            data.push(offset..hole.start);
+        }
+        offset = hole.end;
+    }
+    if offset < size {
+        data.push(offset..size);
+    }
+    data
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn find_holes(file: &File, size: u64) -> std::io::Result<Vec<Range<u64>>> {
+    use rustix::fs::{SeekFrom, seek};
+    use rustix::io::Errno;
+
+    let mut holes = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        // Every file ends in a hole, so this only fails when the file shrank under us
+        let start = match seek(file, SeekFrom::Hole(offset)) {
+            Ok(start) => start.min(size),
+            Err(Errno::NXIO) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let end = match seek(file, SeekFrom::Data(start)) {
+            Ok(end) => end.min(size),
+            // Nothing but hole up to the end
+            Err(Errno::NXIO) => size,
+            Err(e) => return Err(e.into()),
+        };
+        if end <= start {
+            break;
+        }
+        if end - start >= MIN_HOLE {
+            holes.push(start..end);
+        }
+        offset = end;
+    }
+    Ok(holes)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn find_holes(_file: &File, _size: u64) -> std::io::Result<Vec<Range<u64>>> {
+    Ok(Vec::new())
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn data_fills_the_gaps_between_holes() {
+        assert_eq!(data_between(&[], 10), [0..10]);
+        assert_eq!(data_between(&[0..4, 6..8], 10), [4..6, 8..10]);
+        assert_eq!(data_between(&[2..10], 10), [0..2]);
+        assert!(data_between(&[0..10], 10).is_empty());
+    }
+}
@@ -1,32 +1,406 @@
 use std::net::SocketAddr;
-use crate::transport::{factory, TransportClient};
-use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use crate::chunker::{Chunker, DEFAULT_CHUNK_SIZE};
+use crate::identity::{self, Fingerprint, KnownHosts, Trust};
+use crate::pairing::{self, Role};
+use crate::receipt;
+use crate::protocol::{exchange_hello, report_error, Compression, ConflictPolicy, ErrorCode, ErrorMessage, GetRequest, Hello, Message, MessageTransport, RemoteEntry};
+use crate::utils::size::format_size;
+use crate::transfer::{self, Filters, Preserve, RateLimiter, TransferReport};
+use crate::transport::factory::TransportType;
+use crate::transport::race::{self, Connected};
+use crate::transport::Transport;
+use anyhow::{Context, Result};
+
+const DEFAULT_CLIENT_NAME: &str = "Default-Client";
 
 pub struct Client{
-    transport_client: Option<Box<dyn TransportClient<Conn=()>>>
+    /// Tried in this order, see [`Client::with_transports`]
+    transports: Vec<TransportType>,
+    options: ClientOptions,
+}
+
+/// Knobs for [`Client`] transfers
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// Size of the pieces files are cut into, each one is hashed and verified separately
+    pub chunk_size: usize,
+    /// Cut files where their content says, `chunk_size` being the average, so an insertion
+    /// only changes the chunks around it and the rest can be found on the server
+    pub content_defined: bool,
+    /// Send files the server already has a copy of as an rsync style delta against that copy
+    pub delta: bool,
+    /// Code shown by a server started with one, proves both sides are who they claim
+    pub pairing_code: Option<String>,
+    /// Fingerprint the server is expected to present, e.g. from its mDNS TXT record
+    pub fingerprint: Option<Fingerprint>,
+    /// What the user asked to connect to, the server's certificate is pinned under it. The address
+    /// that answered when not set, never the name the server gives itself, anyone can claim that one.
+    pub host: Option<String>,
+    /// Where fingerprints of servers seen before are pinned, `None` turns pinning off
+    pub known_hosts: Option<PathBuf>,
+    /// Data streams chunks are spread over when the transport has them (QUIC), 1 keeps
+    /// everything on the control stream
+    pub streams: usize,
+    /// Bytes per second to send at most, see [`crate::parse_rate`]
+    pub limit: Option<u64>,
+    /// Globs a file in a directory being sent must match one of, everything when empty
+    pub include: Vec<String>,
+    /// Globs for files and directories left out when sending a directory
+    pub exclude: Vec<String>,
+    /// Leave out what `.gitignore` files in (and above) a directory being sent ignore
+    pub gitignore: bool,
+    /// Metadata sent along with uploads and applied to downloads
+    pub preserve: Preserve,
+    /// Codec for chunks we send, and whether the server may compress what it sends us, `None`
+    /// turns compression off both ways
+    pub compression: Option<Compression>,
+    /// What the receiver does with files whose name is taken, a server may settle for less
+    pub conflict: ConflictPolicy,
+}
+
+/// Enough to keep a fast link busy while one stream waits on a lost packet
+const DEFAULT_STREAMS: usize = 8;
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            content_defined: false,
+            delta: false,
+            pairing_code: None,
+            fingerprint: None,
+            host: None,
+            known_hosts: identity::default_known_hosts(),
+            streams: DEFAULT_STREAMS,
+            limit: None,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            gitignore: false,
+            preserve: Preserve::default(),
+            compression: Some(Compression::Zstd),
+            conflict: ConflictPolicy::Overwrite,
+        }
+    }
 }
 
 impl Client {
     pub fn new(is_tcp:bool)->Client {
-        if is_tcp{
-            panic!("Not supported yet!")
-        }
+        let transport_type = match is_tcp {
+            true => TransportType::Tcp,
+            false => TransportType::Quic,
+        };
         Client{
-            transport_client: None
+            transports: vec![transport_type],
+            options: ClientOptions::default(),
         }
     }
 
-    pub fn connect(mut self, ip_address:&str, port: u16, name: Option<&str>) -> Result<()>{
-        let mut transport_client = factory::make_quic_client();
-        let socket_addr : SocketAddr = format!("{ip_address}:{port}").parse().expect("df");
+    /// Transports to try, in order of preference. Each one gets a short head start before the
+    /// next joins in, whichever finishes its handshake first is used.
+    pub fn with_transports(mut self, transports: &[TransportType]) -> Client {
+        self.transports = transports.to_vec();
+        self
+    }
+
+    pub fn with_options(mut self, options: ClientOptions) -> Client {
+        self.options = options;
+        self
+    }
+
+    pub fn connect(self, ip_address:&str, port: u16, name: Option<&str>) -> Result<()>{
+        let socket_addr : SocketAddr = format!("{ip_address}:{port}").parse().with_context(|| format!("IP address not valid {ip_address}"))?;
         // TODO: Need to clarify what to do with name. I am thinking should clients even have name?
-        let name = name.unwrap_or("Default-Client");
-        // self.transport_client = Some(Box(transport_client)); // wrong
+        let name = name.unwrap_or(DEFAULT_CLIENT_NAME);
         let rt = tokio::runtime::Runtime::new()?;
-        let transport = rt.block_on(transport_client.connect(socket_addr, name))?;
+        let server_name = rt.block_on(async {
+            let (mut transport, hello, _, transport_type) = self.open(&[socket_addr], name).await?;
+            say_bye(&mut transport).await?;
+            Ok::<_, anyhow::Error>((hello.name, transport_type))
+        })?;
 
         // TODO: Replace with better logging
-        println!("connected to to {ip_address}:{port}");
+        let (server_name, transport_type) = server_name;
+        println!("connected to {server_name} at {ip_address}:{port} over {transport_type}");
+        Ok(())
+    }
+
+    /// Sends the given files and directories to the server listening on `server_addrs`, which
+    /// are tried in order with a short head start each (e.g. a [`crate::FerryService`]'s sorted
+    /// addresses). Directories are recreated on the server with everything the filters let through.
+    /// The report carries the receipt the server signed for them.
+    pub fn send(self, server_addrs: &[SocketAddr], paths: &[PathBuf]) -> Result<TransferReport> {
+        let chunker = Chunker::new(self.options.chunk_size)?.with_content_defined(self.options.content_defined);
+        let filters = Filters::new(&self.options.include, &self.options.exclude, self.options.gitignore)?;
+        let mut sources = transfer::collect_sources(paths, &filters, &self.options.preserve, &chunker)?;
+        sources.delta = self.options.delta;
+        sources.conflict = self.options.conflict;
+        let limiter = self.options.limit.map(|limit| Arc::new(RateLimiter::new(limit)));
+        let rt = tokio::runtime::Runtime::new()?;
+        rt.block_on(async {
+            let (mut transport, hello, server_addr, transport_type) = self.open(server_addrs, DEFAULT_CLIENT_NAME).await?;
+            sources.compression = transfer::negotiate(self.options.compression, &hello.compression);
+            println!("Sending {} files to {} at {server_addr} over {transport_type}", sources.files.len(), hello.name);
+            let report = match transfer::send_files(&mut transport, &sources, self.options.streams, limiter).await {
+                Ok(report) => report,
+                Err(e) if let Some(code) = declined(&e) => {
+                    // The server is still talking to us, part on good terms
+                    let _ = say_bye(&mut transport).await;
+                    match code {
+                        ErrorCode::Rejected => anyhow::bail!("rejected by receiver: {} declined the files", hello.name),
+                        ErrorCode::ReadOnly => anyhow::bail!("{} is read-only, use `ferry get` to download from it", hello.name),
+                        _ => return Err(e.context(format!("{} refused the transfer", hello.name))),
+                    }
+                }
+                Err(e) => {
+                    report_error(&mut transport, &e).await;
+                    let _ = transport.close().await;
+                    return Err(e);
+                }
+            };
+            let receipt = match transport.receive_message().await? {
+                Message::Receipt(receipt) => receipt,
+                other => {
+                    let err = other.unexpected("Receipt");
+                    let _ = transport.close().await;
+                    return Err(err);
+                }
+            };
+            let certificate = transport.peer_certificate().context("server presented no certificate")?;
+            say_bye(&mut transport).await?;
+            // The files are there either way, but a receipt that doesn't check out is worthless
+            receipt::verify(&receipt, sources.files.iter().map(|s| &s.entry), &report.placements, &certificate)
+                .with_context(|| format!("the files arrived but {} sent an invalid receipt", hello.name))?;
+            Ok(TransferReport { receipt: Some(receipt), ..report })
+        })
+    }
+
+    /// Lists `path` on a read-only server, `""` being the top of the directory it shares
+    pub fn list(self, server_addrs: &[SocketAddr], path: &str) -> Result<Vec<RemoteEntry>> {
+        let rt = tokio::runtime::Runtime::new()?;
+        rt.block_on(async {
+            let (mut transport, ..) = self.open(server_addrs, DEFAULT_CLIENT_NAME).await?;
+            transport.send_message(&Message::List(path.to_string())).await?;
+            let entries = match transport.receive_message().await? {
+                Message::Listing(entries) => entries,
+                other => {
+                    let err = other.unexpected("Listing");
+                    let _ = say_bye(&mut transport).await;
+                    return Err(err);
+                }
+            };
+            say_bye(&mut transport).await?;
+            Ok(entries)
+        })
+    }
+
+    /// Downloads `path` from a read-only server into `dest`. Directories come with everything
+    /// below them, and an interrupted download picks up where it stopped when run again.
+    pub fn get(self, server_addrs: &[SocketAddr], path: &str, dest: &Path) -> Result<TransferReport> {
+        let limiter = self.options.limit.map(RateLimiter::new);
+        let rt = tokio::runtime::Runtime::new()?;
+        rt.block_on(async {
+            let (mut transport, hello, server_addr, transport_type) = self.open(server_addrs, DEFAULT_CLIENT_NAME).await?;
+            let request = GetRequest { path: path.to_string(), streams: self.options.streams as u32 };
+            transport.send_message(&Message::Get(request)).await?;
+            let manifest = match transport.receive_message().await? {
+                Message::Manifest(manifest) => manifest,
+                other => {
+                    let err = other.unexpected("Manifest");
+                    let _ = say_bye(&mut transport).await;
+                    return Err(err);
+                }
+            };
+            let size: u64 = manifest.files.iter().map(|f| f.size).sum();
+            println!("Receiving {} files ({}) from {} at {server_addr} over {transport_type}", manifest.files.len(), format_size(size), hello.name);
+            std::fs::create_dir_all(dest).with_context(|| format!("create {}", dest.display()))?;
+            let report = match transfer::receive_files(&mut transport, dest, &manifest, None, limiter.as_ref(), &self.options.preserve, None, self.options.conflict).await {
+                Ok(report) => report,
+                Err(e) => {
+                    report_error(&mut transport, &e).await;
+                    let _ = transport.close().await;
+                    return Err(e);
+                }
+            };
+            say_bye(&mut transport).await?;
+            Ok(report)
+        })
+    }
+
+    /// Connects to the first address and transport that works and exchanges hellos.
+    /// Returns the transport, the server's hello and which address and transport won.
+    async fn open(&self, server_addrs: &[SocketAddr], name: &str) -> Result<(Box<dyn Transport + Send>, Hello, SocketAddr, TransportType)> {
+        let attempts = race::plan(server_addrs, &self.transports);
+        let Connected { mut transport, addr, transport_type } = race::connect_first(&attempts, name).await?;
+        let host = self.options.host.clone().unwrap_or_else(|| addr.to_string());
+        match self.greet(&mut transport, &host, name).await {
+            Ok(hello) => Ok((transport, hello, addr, transport_type)),
+            Err(e) => {
+                let _ = transport.close().await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Exchanges hellos, makes sure the server at `host` is the one seen there before and pairs
+    /// when it asks to. A new server is only pinned once it paired, failing to may well mean
+    /// someone in the middle.
+    async fn greet<T: Transport + Send + ?Sized>(&self, transport: &mut T, host: &str, name: &str) -> Result<Hello> {
+        let ours = Hello { compression: transfer::accepted(self.options.compression), ..Hello::new(name) };
+        let hello = exchange_hello(transport, ours).await?;
+        let cert = transport.peer_certificate().context("server presented no certificate")?;
+        let fingerprint = Fingerprint::of(&cert);
+        let unpinned = self.check_server_identity(fingerprint, host, &hello.name)?;
+        match (hello.pairing_required, &self.options.pairing_code) {
+            (true, Some(code)) => pairing::pair(transport, code, Role::Client).await?,
+            (true, None) => anyhow::bail!("{} requires a pairing code, pass --code", hello.name),
+            // A server that doesn't ask for the code can't prove it knows it either
+            (false, Some(_)) => anyhow::bail!("{} was not started with a pairing code, refusing to talk to it", hello.name),
+            (false, None) => {}
+        }
+        if let Some(mut known_hosts) = unpinned {
+            known_hosts.pin(host, fingerprint)?;
+            println!("Trusting {} at {host} on first use, fingerprint {fingerprint}", hello.name);
+        }
+        Ok(hello)
+    }
+
+    /// Trust on first use: a known `host` must present the same certificate whatever
+    /// `server_name` it says it has. Returns the known hosts to pin a new one in, once it is trusted.
+    fn check_server_identity(&self, fingerprint: Fingerprint, host: &str, server_name: &str) -> Result<Option<KnownHosts>> {
+        if let Some(expected) = self.options.fingerprint && expected != fingerprint {
+            anyhow::bail!(
+                "{server_name} presented certificate {fingerprint} but advertised {expected}, \
+                 someone may be impersonating it"
+            );
+        }
+        let Some(path) = &self.options.known_hosts else {
+            return Ok(None);
+        };
+        let known_hosts = KnownHosts::load(path)?;
+        match known_hosts.check(host, fingerprint) {
+            Trust::Known => Ok(None),
+            Trust::New => Ok(Some(known_hosts)),
+            Trust::Changed { pinned } => anyhow::bail!(
+                "WARNING: the certificate of {host} has changed!\n\
+                 pinned:    {pinned}\n\
+                 presented: {fingerprint} (by {server_name})\n\
+                 Someone could be impersonating it. If the server was reinstalled on purpose, \
+                 remove its line from {}",
+                known_hosts.path().display()
+            ),
+        }
+    }
+}
+
+/// Why the server turned our files down before any data moved, if it did
+fn declined(err: &anyhow::Error) -> Option<ErrorCode> {
+    let code = err.downcast_ref::<ErrorMessage>()?.code;
+    code.is_refusal().then_some(code)
+}
+
+/// Ends the conversation politely and closes the connection
+async fn say_bye<T: Transport + Send>(transport: &mut T) -> Result<()> {
+    transport.send_message(&Message::Bye).await?;
+    match transport.receive_message().await? {
+        Message::Bye => {}
+        other => return Err(other.unexpected("Bye")),
+    }
+    transport.close().await
+}
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::DataStreams;
+    use crate::transport::memory::{self, MemoryTransport};
+    use rustls::pki_types::CertificateDer;
+
+    fn client(dir: &Path, pairing_code: Option<&str>) -> Client {
+        let options = ClientOptions {
+            known_hosts: Some(dir.join("known_hosts")),
+            pairing_code: pairing_code.map(str::to_string),
+            ..Default::default()
+        };
+        Client::new(false).with_options(options)
+    }
+
+    /// Checks `fingerprint` and pins it if it's new, like a connection that went through
+    fn trust(client: &Client, fingerprint: Fingerprint, host: &str, server_name: &str) -> Result<()> {
+        if let Some(mut known_hosts) = client.check_server_identity(fingerprint, host, server_name)? {
+            known_hosts.pin(host, fingerprint)?;
+        }
         Ok(())
     }
+
+    #[test]
+    fn pins_follow_the_host_not_the_name_the_server_gives() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = client(dir.path(), None);
+        let (ours, theirs) = (Fingerprint::of(b"ours"), Fingerprint::of(b"theirs"));
+
+        trust(&client, ours, "10.0.0.2:3625", "trite-metal").unwrap();
+        trust(&client, ours, "10.0.0.2:3625", "trite-metal").unwrap();
+        // Another certificate at the same address doesn't get in by calling itself something new
+        let err = trust(&client, theirs, "10.0.0.2:3625", "left-stem").unwrap_err();
+        assert!(err.to_string().contains("has changed"));
+        // Somewhere else is pinned on its own
+        trust(&client, theirs, "10.0.0.3:3625", "trite-metal").unwrap();
+    }
+
+    /// An in-memory connection whose server shows a certificate
+    struct Presenting(MemoryTransport);
+
+    #[async_trait::async_trait]
+    impl Transport for Presenting {
+        async fn send_data(&mut self, data: &[u8]) -> Result<()> {
+            self.0.send_data(data).await
+        }
+
+        async fn receive_data(&mut self) -> Result<Vec<u8>> {
+            self.0.receive_data().await
+        }
+
+        async fn close(&mut self) -> Result<()> {
+            self.0.close().await
+        }
+
+        fn export_keying_material(&self, output: &mut [u8], label: &[u8], context: &[u8]) -> Result<()> {
+            self.0.export_keying_material(output, label, context)
+        }
+
+        fn peer_certificate(&self) -> Option<CertificateDer<'static>> {
+            Some(CertificateDer::from(b"someone's certificate".to_vec()))
+        }
+
+        fn data_streams(&self) -> Option<Arc<dyn DataStreams>> {
+            None
+        }
+    }
+
+    /// Greets a server that asks for `code` as a client that knows `ours`
+    async fn greet_pairing(dir: &Path, ours: &str, code: &'static str) -> Result<Hello> {
+        let (client_end, mut server) = memory::pair();
+        let server = tokio::spawn(async move {
+            let hello = Hello { pairing_required: true, ..Hello::new("receiver") };
+            exchange_hello(&mut server, hello).await?;
+            pairing::pair(&mut server, code, Role::Server).await
+        });
+        let hello = client(dir, Some(ours)).greet(&mut Presenting(client_end), "10.0.0.2:3625", "sender").await;
+        let _ = server.await.unwrap();
+        hello
+    }
+
+    #[tokio::test]
+    async fn only_servers_that_paired_are_pinned() {
+        let dir = tempfile::tempdir().unwrap();
+        let known_hosts = dir.path().join("known_hosts");
+
+        assert!(greet_pairing(dir.path(), "abrasive-bread-4821", "trite-metal-1234").await.is_err());
+        assert!(!known_hosts.exists(), "a failed pairing pinned the certificate");
+
+        greet_pairing(dir.path(), "abrasive-bread-4821", "abrasive-bread-4821").await.unwrap();
+        assert!(std::fs::read_to_string(&known_hosts).unwrap().starts_with("10.0.0.2:3625 "));
+    }
 }
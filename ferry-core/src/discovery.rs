@@ -4,6 +4,9 @@ mod score_ip;
 
 use crate::discovery::advertisement::start_ferry_advertisement;
 use crate::discovery::find_services::find_ferry_services;
+pub(crate) use crate::discovery::score_ip::{reach, Reach};
+use crate::identity::Fingerprint;
+use crate::transport::factory::TransportType;
 use mdns_sd::ServiceDaemon;
 use std::collections::HashMap;
 use std::net::SocketAddr;
@@ -25,6 +28,18 @@ pub struct FerryService {
 }
 
 impl FerryService {
+    /// Certificate fingerprint the server advertised, if any
+    pub fn fingerprint(&self) -> Option<Fingerprint> {
+        self.txt.get(FINGERPRINT_TXT_KEY)?.parse().ok()
+    }
+
+    /// Transports the server listens on, `None` when it doesn't say (servers that predate TCP)
+    pub fn transports(&self) -> Option<Vec<TransportType>> {
+        let listed = self.txt.get(TRANSPORTS_TXT_KEY)?;
+        let transports: Vec<_> = listed.split(',').filter_map(|t| t.parse().ok()).collect();
+        (!transports.is_empty()).then_some(transports)
+    }
+
     pub fn sort_addrs_by_preference(&mut self) {
         score_ip::sort_addrs_by_preference(self);
     }
@@ -37,14 +52,39 @@ impl FerryService {
 impl Drop for FerryAnnouncement {
     fn drop(&mut self) {
         println!("Dropping FerryAnnouncement for {}", &self.fullname);
-        let _ = self.daemon.unregister(&self.fullname);
+        // Wait for the goodbye packets to go out, otherwise browsers keep listing us until the TTL expires
+        if let Ok(rx) = self.daemon.unregister(&self.fullname) {
+            let _ = rx.recv_timeout(Duration::from_secs(1));
+        }
+        let _ = self.daemon.shutdown();
     }
 }
+/// TXT key carrying the server's certificate fingerprint
+pub const FINGERPRINT_TXT_KEY: &str = "fp";
+/// TXT key listing the transports the server listens on, e.g. `quic,tcp`
+pub const TRANSPORTS_TXT_KEY: &str = "transports";
+
 pub(crate) fn register_for_discovery(
     server_name: &str,
     port: &u16,
+    fingerprint: &Fingerprint,
+    transports: &[TransportType],
 ) -> anyhow::Result<FerryAnnouncement> {
-    start_ferry_advertisement(server_name, *port, &[("port", "1234")])
+    let fingerprint = fingerprint.to_string();
+    let transports = transports
+        .iter()
+        .map(TransportType::as_str)
+        .collect::<Vec<_>>()
+        .join(",");
+    start_ferry_advertisement(
+        server_name,
+        *port,
+        &[
+            ("port", "1234"),
+            (FINGERPRINT_TXT_KEY, &fingerprint),
+            (TRANSPORTS_TXT_KEY, &transports),
+        ],
+    )
 }
 
 pub fn discover_ferry_services(timeout: Duration) -> anyhow::Result<Vec<FerryService>> {
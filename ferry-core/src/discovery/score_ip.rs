@@ -71,6 +71,36 @@ fn score_ipv6(addr: &Ipv6Addr) -> i32 {
     200
 }
 
+/// How widely an address can be reached, using the same buckets the scores sort by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Reach {
+    Loopback,
+    Private,
+    Public,
+}
+
+impl std::fmt::Display for Reach {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Reach::Loopback => "loopback",
+            Reach::Private => "private",
+            Reach::Public => "public",
+        })
+    }
+}
+
+pub(crate) fn reach(ip: &IpAddr) -> Reach {
+    let score = match ip {
+        IpAddr::V4(v4) => score_ipv4(v4),
+        IpAddr::V6(v6) => score_ipv6(v6),
+    };
+    match score {
+        100 | 230 => Reach::Loopback,
+        30 | 200 => Reach::Public,
+        _ => Reach::Private,
+    }
+}
+
 // inline tests
 #[cfg(test)]
 mod tests {
@@ -117,6 +147,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn reach_follows_the_score_buckets() {
+        let cases: Vec<(IpAddr, Reach)> = vec![
+            (Ipv4Addr::new(127, 0, 0, 1).into(), Reach::Loopback),
+            (Ipv6Addr::LOCALHOST.into(), Reach::Loopback),
+            (Ipv4Addr::new(192, 168, 1, 4).into(), Reach::Private),
+            (Ipv4Addr::new(10, 0, 0, 7).into(), Reach::Private),
+            (Ipv4Addr::new(169, 254, 3, 3).into(), Reach::Private),
+            (Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1).into(), Reach::Private),
+            (Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1).into(), Reach::Private),
+            (Ipv4Addr::new(8, 8, 8, 8).into(), Reach::Public),
+            (Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(), Reach::Public),
+        ];
+        for (ip, expected) in &cases {
+            assert_eq!(reach(ip), *expected, "failed for {ip}");
+        }
+    }
+
     fn v4(a: u8, b: u8, c: u8, d: u8, port: u16) -> SocketAddr {
         SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(a, b, c, d), port))
     }
@@ -0,0 +1,244 @@
+use anyhow::{Context, bail};
+use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Servers keep one self-signed certificate for their whole life so clients can recognise them.
+// Clients trust a server the first time they see it and remember its certificate fingerprint in
+// a known-hosts file, keyed by server name, refusing to talk to it if the certificate ever changes.
+
+const CERT_FILE: &str = "server.crt";
+const KEY_FILE: &str = "server.key";
+const KNOWN_HOSTS_FILE: &str = "known_hosts";
+/// Overrides where keys and known hosts are kept
+const CONFIG_DIR_ENV: &str = "FERRY_CONFIG_DIR";
+
+/// `$FERRY_CONFIG_DIR`, or `ferry` in the platform's config directory
+pub(crate) fn config_dir() -> anyhow::Result<PathBuf> {
+    if let Some(dir) = std::env::var_os(CONFIG_DIR_ENV) {
+        return Ok(PathBuf::from(dir));
+    }
+    let base = dirs::config_dir().context("no config directory, set FERRY_CONFIG_DIR")?;
+    Ok(base.join("ferry"))
+}
+
+/// Default location of the client's known hosts
+pub(crate) fn default_known_hosts() -> Option<PathBuf> {
+    config_dir().ok().map(|dir| dir.join(KNOWN_HOSTS_FILE))
+}
+
+/// SHA-256 of a DER encoded certificate, shown as hex
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint([u8; 32]);
+
+impl Fingerprint {
+    pub fn of(cert: &[u8]) -> Self {
+        Self(Sha256::digest(cert).into())
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+impl FromStr for Fingerprint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        if s.len() != 64 || !s.is_ascii() {
+            bail!("fingerprint must be 64 hex digits");
+        }
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+                .context("fingerprint must be 64 hex digits")?;
+        }
+        Ok(Self(bytes))
+    }
+}
+
+/// The certificate and key a server presents on every start
+pub(crate) struct ServerIdentity {
+    pub(crate) cert: CertificateDer<'static>,
+    pub(crate) key: PrivatePkcs8KeyDer<'static>,
+}
+
+impl ServerIdentity {
+    /// Reads the identity kept in `dir`, generating and saving one on first start
+    pub(crate) fn load_or_create(dir: &Path) -> anyhow::Result<Self> {
+        let (cert_path, key_path) = (dir.join(CERT_FILE), dir.join(KEY_FILE));
+        if cert_path.exists() && key_path.exists() {
+            let cert = std::fs::read(&cert_path)
+                .with_context(|| format!("read {}", cert_path.display()))?;
+            let key =
+                std::fs::read(&key_path).with_context(|| format!("read {}", key_path.display()))?;
+            return Ok(Self {
+                cert: CertificateDer::from(cert),
+                key: PrivatePkcs8KeyDer::from(key),
+            });
+        }
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        let identity = Self {
+            cert: CertificateDer::from(cert.cert),
+            key: PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der()),
+        };
+        std::fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+        write_private(&key_path, identity.key.secret_pkcs8_der())
+            .with_context(|| format!("write {}", key_path.display()))?;
+        std::fs::write(&cert_path, identity.cert.as_ref())
+            .with_context(|| format!("write {}", cert_path.display()))?;
+        Ok(identity)
+    }
+
+    pub(crate) fn fingerprint(&self) -> Fingerprint {
+        Fingerprint::of(&self.cert)
+    }
+}
+
+/// Writes a file only the current user can read
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents)
+}
+
+/// What the known hosts say about a server presenting some certificate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Trust {
+    Known,
+    New,
+    Changed { pinned: Fingerprint },
+}
+
+/// Fingerprints of the servers seen so far, one `name fingerprint` line each
+pub(crate) struct KnownHosts {
+    path: PathBuf,
+    hosts: BTreeMap<String, Fingerprint>,
+}
+
+impl KnownHosts {
+    /// A missing file is an empty store
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+        };
+        let mut hosts = BTreeMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parsed = line
+                .rsplit_once(' ')
+                .and_then(|(name, fp)| Some((name.to_string(), fp.parse().ok()?)));
+            let Some((name, fp)) = parsed else {
+                bail!(
+                    "{}:{} is not `name fingerprint`",
+                    path.display(),
+                    number + 1
+                );
+            };
+            hosts.insert(name, fp);
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            hosts,
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn check(&self, name: &str, fingerprint: Fingerprint) -> Trust {
+        match self.hosts.get(name) {
+            None => Trust::New,
+            Some(pinned) if *pinned == fingerprint => Trust::Known,
+            Some(pinned) => Trust::Changed { pinned: *pinned },
+        }
+    }
+
+    /// Remembers `name` and writes the store back
+    pub(crate) fn pin(&mut self, name: &str, fingerprint: Fingerprint) -> anyhow::Result<()> {
+        self.hosts.insert(name.to_string(), fingerprint);
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+        }
+        let contents: String = self
+            .hosts
+            .iter()
+            .map(|(name, fp)| format!("{name} {fp}\n"))
+            .collect();
+        std::fs::write(&self.path, contents)
+            .with_context(|| format!("write {}", self.path.display()))
+    }
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_survives_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = ServerIdentity::load_or_create(dir.path()).unwrap();
+        let second = ServerIdentity::load_or_create(dir.path()).unwrap();
+        assert_eq!(first.fingerprint(), second.fingerprint());
+
+        let other = tempfile::tempdir().unwrap();
+        let stranger = ServerIdentity::load_or_create(other.path()).unwrap();
+        assert_ne!(first.fingerprint(), stranger.fingerprint());
+    }
+
+    #[test]
+    fn fingerprint_roundtrips_through_text() {
+        let fp = Fingerprint::of(b"certificate");
+        assert_eq!(fp.to_string().parse::<Fingerprint>().unwrap(), fp);
+        assert!("abc".parse::<Fingerprint>().is_err());
+        assert!("zz".repeat(32).parse::<Fingerprint>().is_err());
+    }
+
+    #[test]
+    fn known_hosts_pin_on_first_use_and_catch_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join(KNOWN_HOSTS_FILE);
+        let (a, b) = (Fingerprint::of(b"a"), Fingerprint::of(b"b"));
+
+        let mut hosts = KnownHosts::load(&path).unwrap();
+        assert_eq!(hosts.check("trite-metal", a), Trust::New);
+        hosts.pin("trite-metal", a).unwrap();
+
+        let hosts = KnownHosts::load(&path).unwrap();
+        assert_eq!(hosts.check("trite-metal", a), Trust::Known);
+        assert_eq!(hosts.check("trite-metal", b), Trust::Changed { pinned: a });
+        assert_eq!(hosts.check("left-stem", b), Trust::New);
+
+        // Names may have spaces, the fingerprint never does
+        let mut hosts = KnownHosts::load(&path).unwrap();
+        hosts.pin("living room", b).unwrap();
+        let hosts = KnownHosts::load(&path).unwrap();
+        assert_eq!(hosts.check("living room", b), Trust::Known);
+    }
+
+    #[test]
+    fn malformed_known_hosts_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(KNOWN_HOSTS_FILE);
+        std::fs::write(&path, "# comment\n\ntrite-metal not-a-fingerprint\n").unwrap();
+        let err = KnownHosts::load(&path).err().unwrap();
+        assert!(err.to_string().contains(":3"));
+    }
+}
@@ -1,9 +1,24 @@
+mod chunker;
 mod discovery;
 mod server;
 mod utils;
 mod transport;
 mod client;
+mod protocol;
+mod resume;
+mod sandbox;
+mod transfer;
+mod pairing;
+mod identity;
+mod receipt;
 
 pub use discovery::{FerryService, discover_ferry_services};
-pub use server::Server;
-pub use client::Client;
+pub use server::{Approver, Server, ServerOptions, TransferRequest};
+pub use client::{Client, ClientOptions};
+pub use protocol::{Compression, ConflictPolicy, Placement, Receipt, ReceiptEntry, RemoteEntry};
+pub use transfer::{Preserve, TransferReport};
+pub use pairing::generate_pairing_code;
+pub use identity::Fingerprint;
+pub use transport::factory::TransportType;
+
+pub use utils::size::{format_size, parse_rate, parse_size};
\ No newline at end of file
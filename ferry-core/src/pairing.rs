@@ -0,0 +1,180 @@
+use crate::protocol::{ErrorCode, ErrorMessage, Message, MessageTransport, Pairing};
+use crate::transport::Transport;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use names::{Generator, Name};
+use sha2::Sha256;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+
+// Pairing codes authenticate both ends with SPAKE2, a password authenticated key exchange:
+// an eavesdropper learns nothing about the code and an active attacker gets a single guess per
+// connection. The agreed key is mixed with keying material exported from the TLS session before
+// the confirmation MACs are computed, so someone terminating TLS in the middle ends up with
+// different exporter secrets on each side and fails confirmation even with the right code.
+
+const CLIENT_ID: &[u8] = b"ferry-client";
+const SERVER_ID: &[u8] = b"ferry-server";
+const EXPORTER_LABEL: &[u8] = b"EXPORTER-ferry-pairing";
+const CLIENT_CONFIRM: &[u8] = b"ferry pairing client confirm";
+const SERVER_CONFIRM: &[u8] = b"ferry pairing server confirm";
+
+/// Which end of the pairing we are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    Client,
+    Server,
+}
+
+/// A fresh code for `ferry serve --code`, e.g. `abrasive-bread-4821`
+pub fn generate_pairing_code() -> String {
+    Generator::with_naming(Name::Numbered)
+        .next()
+        .expect("Failed to generate pairing code")
+}
+
+/// Codes are typed by humans, so case and separators don't matter
+fn normalize(code: &str) -> String {
+    code.split(|c: char| c.is_whitespace() || c == '-' || c == '_')
+        .filter(|part| !part.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Runs the pairing exchange right after the hellos. Both sides must know the same code and
+/// share the same TLS session, otherwise an [`ErrorCode::AuthFailed`] error is returned.
+pub(crate) async fn pair<T>(transport: &mut T, code: &str, role: Role) -> anyhow::Result<()>
+where
+    T: Transport + Send + ?Sized,
+{
+    let password = Password::new(normalize(code).as_bytes());
+    let (client_id, server_id) = (Identity::new(CLIENT_ID), Identity::new(SERVER_ID));
+    let (spake, ours) = match role {
+        Role::Client => Spake2::<Ed25519Group>::start_a(&password, &client_id, &server_id),
+        Role::Server => Spake2::<Ed25519Group>::start_b(&password, &client_id, &server_id),
+    };
+    transport
+        .send_message(&Message::Pairing(Pairing::Spake2(ours.clone())))
+        .await?;
+    let theirs = match transport.receive_message().await? {
+        Message::Pairing(Pairing::Spake2(msg)) => msg,
+        other => return Err(other.unexpected("pairing message")),
+    };
+    let Ok(key) = spake.finish(&theirs) else {
+        return Err(fail(transport, "malformed pairing message").await);
+    };
+
+    let mut exporter = [0u8; 32];
+    transport.export_keying_material(&mut exporter, EXPORTER_LABEL, b"")?;
+    let (client_msg, server_msg) = match role {
+        Role::Client => (&ours, &theirs),
+        Role::Server => (&theirs, &ours),
+    };
+    let transcript = [client_msg.as_slice(), server_msg.as_slice()].concat();
+    let hkdf = Hkdf::<Sha256>::new(Some(&exporter), &key);
+    let (our_label, their_label) = match role {
+        Role::Client => (CLIENT_CONFIRM, SERVER_CONFIRM),
+        Role::Server => (SERVER_CONFIRM, CLIENT_CONFIRM),
+    };
+
+    let our_mac = confirmation(&hkdf, our_label, &transcript)
+        .finalize()
+        .into_bytes();
+    transport
+        .send_message(&Message::Pairing(Pairing::Confirm(our_mac.to_vec())))
+        .await?;
+    let their_mac = match transport.receive_message().await? {
+        Message::Pairing(Pairing::Confirm(mac)) => mac,
+        other => return Err(other.unexpected("pairing confirmation")),
+    };
+    if confirmation(&hkdf, their_label, &transcript)
+        .verify_slice(&their_mac)
+        .is_err()
+    {
+        return Err(fail(transport, "pairing code does not match").await);
+    }
+    Ok(())
+}
+
+fn confirmation(hkdf: &Hkdf<Sha256>, label: &[u8], transcript: &[u8]) -> Hmac<Sha256> {
+    let mut key = [0u8; 32];
+    hkdf.expand(label, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key).expect("HMAC takes keys of any size");
+    mac.update(transcript);
+    mac
+}
+
+async fn fail<T>(transport: &mut T, reason: &str) -> anyhow::Error
+where
+    T: Transport + Send + ?Sized,
+{
+    let err = ErrorMessage::new(ErrorCode::AuthFailed, reason);
+    let _ = transport.send_message(&Message::Error(err.clone())).await;
+    err.into()
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::memory;
+
+    async fn run(client_code: &str, server_code: &str) -> (anyhow::Result<()>, anyhow::Result<()>) {
+        let (mut client, mut server) = memory::pair();
+        let server_code = server_code.to_string();
+        let server_side =
+            tokio::spawn(async move { pair(&mut server, &server_code, Role::Server).await });
+        let client_res = pair(&mut client, client_code, Role::Client).await;
+        (client_res, server_side.await.unwrap())
+    }
+
+    fn code_of(res: anyhow::Result<()>) -> Option<ErrorCode> {
+        res.err()
+            .and_then(|e| e.downcast_ref::<ErrorMessage>().map(|m| m.code))
+    }
+
+    #[tokio::test]
+    async fn same_code_pairs() {
+        let (client, server) = run("Trite Metal-42", "trite-metal-42").await;
+        client.unwrap();
+        server.unwrap();
+    }
+
+    #[tokio::test]
+    async fn wrong_code_fails_on_both_sides() {
+        let (client, server) = run("trite-metal-42", "trite-metal-43").await;
+        assert_eq!(code_of(client), Some(ErrorCode::AuthFailed));
+        assert_eq!(code_of(server), Some(ErrorCode::AuthFailed));
+    }
+
+    #[tokio::test]
+    async fn different_tls_sessions_fail_even_with_the_right_code() {
+        // Someone relaying between two separate TLS sessions, the code is right but the
+        // exporter secrets differ
+        let (mut client, mut mitm_a) = memory::pair();
+        let (mut mitm_b, mut server) = memory::pair();
+        let relay = tokio::spawn(async move {
+            for _ in 0..2 {
+                let from_client = mitm_a.receive_data().await?;
+                mitm_b.send_data(&from_client).await?;
+                let from_server = mitm_b.receive_data().await?;
+                mitm_a.send_data(&from_server).await?;
+            }
+            Ok::<(), anyhow::Error>(())
+        });
+        let server_side =
+            tokio::spawn(async move { pair(&mut server, "code", Role::Server).await });
+        let client_res = pair(&mut client, "code", Role::Client).await;
+        assert!(client_res.is_err());
+        assert!(server_side.await.unwrap().is_err());
+        relay.abort();
+    }
+
+    #[test]
+    fn generated_codes_survive_normalization() {
+        let code = generate_pairing_code();
+        assert_eq!(normalize(&code), code);
+        assert_eq!(normalize("  Abrasive_Bread  4821 "), "abrasive-bread-4821");
+    }
+}
@@ -0,0 +1,71 @@
+mod codec;
+mod message;
+
+pub(crate) use codec::{FrameReader, decode, encode, write_frame};
+pub use message::{
+    Ack, BlockSignature, Chunk, ChunkId, ChunkInfo, ChunkRanges, Compression, ConflictPolicy,
+    Delta, DeltaOp, ErrorCode, ErrorMessage, FileEntry, FileMeta, GetRequest, Hash, Hello,
+    LinkEntry, Manifest, Message, PROTOCOL_VERSION, Pairing, Placement, Receipt, ReceiptEntry,
+    RemoteEntry, Signature,
+};
+
+use crate::transport::Transport;
+
+/// Typed messages on top of a framed [`Transport`].
+/// Every `Transport` gets this for free, so sessions never touch raw bytes.
+#[async_trait::async_trait]
+pub(crate) trait MessageTransport {
+    async fn send_message(&mut self, message: &Message) -> anyhow::Result<()>;
+    async fn receive_message(&mut self) -> anyhow::Result<Message>;
+}
+
+#[async_trait::async_trait]
+impl<T: Transport + Send + ?Sized> MessageTransport for T {
+    async fn send_message(&mut self, message: &Message) -> anyhow::Result<()> {
+        let payload = encode(message)?;
+        self.send_data(&payload).await
+    }
+
+    async fn receive_message(&mut self) -> anyhow::Result<Message> {
+        let payload = self.receive_data().await?;
+        decode(&payload)
+    }
+}
+
+/// Both sides send their [`Hello`] first and check that the peer speaks the same protocol version.
+/// Returns the peer's hello.
+pub(crate) async fn exchange_hello<T>(transport: &mut T, ours: Hello) -> anyhow::Result<Hello>
+where
+    T: MessageTransport + Send + ?Sized,
+{
+    transport.send_message(&Message::Hello(ours)).await?;
+    match transport.receive_message().await? {
+        Message::Hello(hello) if hello.version == PROTOCOL_VERSION => Ok(hello),
+        Message::Hello(hello) => {
+            let err = ErrorMessage::new(
+                ErrorCode::UnsupportedVersion,
+                format!(
+                    "peer speaks protocol v{}, we speak v{PROTOCOL_VERSION}",
+                    hello.version
+                ),
+            );
+            let _ = transport.send_message(&Message::Error(err.clone())).await;
+            Err(err.into())
+        }
+        other => Err(other.unexpected("Hello")),
+    }
+}
+
+/// Lets the peer know why we are giving up on the conversation.
+/// An [`ErrorMessage`] has either come from the peer or was sent where it was raised, so only
+/// local failures (I/O and friends) are forwarded, as [`ErrorCode::Internal`].
+pub(crate) async fn report_error<T>(transport: &mut T, err: &anyhow::Error)
+where
+    T: MessageTransport + Send + ?Sized,
+{
+    if err.downcast_ref::<ErrorMessage>().is_some() {
+        return;
+    }
+    let msg = ErrorMessage::new(ErrorCode::Internal, format!("{err:#}"));
+    let _ = transport.send_message(&Message::Error(msg)).await;
+}
@@ -0,0 +1,234 @@
+use crate::protocol::Message;
+use anyhow::{Context, bail};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Upper bound for a single frame. Keeps a misbehaving peer from making us allocate gigabytes.
+pub(crate) const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+// Frame layout: [u32 big-endian payload length][payload]
+
+pub(crate) fn encode(message: &Message) -> anyhow::Result<Vec<u8>> {
+    bincode::serialize(message).context("encode message")
+}
+
+pub(crate) fn decode(payload: &[u8]) -> anyhow::Result<Message> {
+    bincode::deserialize(payload).context("decode message")
+}
+
+pub(crate) async fn write_frame<W>(writer: &mut W, payload: &[u8]) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin + Send + ?Sized,
+{
+    if payload.len() > MAX_FRAME_LEN {
+        bail!(
+            "frame of {} bytes exceeds the {MAX_FRAME_LEN} byte limit",
+            payload.len()
+        );
+    }
+    writer.write_u32(payload.len() as u32).await?;
+    writer.write_all(payload).await?;
+    Ok(())
+}
+
+/// Reassembles frames from a byte stream. Partial frames are kept between calls, so `next` can be dropped halfway (e.g. in `select!`) without losing anything.
+#[derive(Debug, Default)]
+pub(crate) struct FrameReader {
+    buf: Vec<u8>,
+}
+
+impl FrameReader {
+    /// Next frame, `None` if the stream ends cleanly between frames
+    pub(crate) async fn next<R>(&mut self, reader: &mut R) -> anyhow::Result<Option<Vec<u8>>>
+    where
+        R: AsyncRead + Unpin + Send + ?Sized,
+    {
+        loop {
+            if let Some(frame) = self.take_frame()? {
+                return Ok(Some(frame));
+            }
+            if reader.read_buf(&mut self.buf).await? == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                bail!("stream closed in the middle of a frame");
+            }
+        }
+    }
+
+    fn take_frame(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(header) = self.buf.first_chunk::<4>() else {
+            self.buf.reserve(READ_AHEAD);
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(*header) as usize;
+        if len > MAX_FRAME_LEN {
+            bail!("peer sent a {len} byte frame, limit is {MAX_FRAME_LEN}");
+        }
+        if self.buf.len() < 4 + len {
+            self.buf.reserve(4 + len + READ_AHEAD - self.buf.len());
+            return Ok(None);
+        }
+        let frame = self.buf[4..4 + len].to_vec();
+        self.buf.drain(..4 + len);
+        Ok(Some(frame))
+    }
+}
+
+/// How much more than the current frame `FrameReader` asks the stream for
+const READ_AHEAD: usize = 64 * 1024;
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::message::{
+        Ack, BlockSignature, Chunk, ChunkId, ChunkInfo, Compression, ConflictPolicy, Delta,
+        DeltaOp, ErrorCode, ErrorMessage, FileEntry, FileMeta, Hello, LinkEntry, Manifest, Pairing,
+        Placement, Receipt, ReceiptEntry, Signature,
+    };
+
+    fn all_messages() -> Vec<Message> {
+        vec![
+            Message::Hello(Hello {
+                compression: vec![Compression::Lz4, Compression::Zstd],
+                ..Hello::new("trite-metal")
+            }),
+            Message::Manifest(Manifest {
+                files: vec![FileEntry {
+                    path: "dir/a.txt".into(),
+                    size: 42,
+                    chunks: vec![ChunkInfo {
+                        offset: 0,
+                        len: 42,
+                        hash: [7; 32],
+                    }],
+                    holes: Vec::new(),
+                    meta: FileMeta {
+                        mode: Some(0o644),
+                        mtime: Some((1_700_000_000, 5)),
+                        xattrs: vec![("user.tag".into(), b"blue".to_vec())],
+                    },
+                    hash: [8; 32],
+                }],
+                dirs: vec!["dir/empty".into()],
+                links: vec![LinkEntry {
+                    path: "dir/latest".into(),
+                    target: "a.txt".into(),
+                }],
+                delta: true,
+                conflict: ConflictPolicy::Rename,
+            }),
+            Message::Chunk(Chunk {
+                file: 0,
+                index: 5,
+                data: vec![1, 2, 3],
+                compression: Some(Compression::Zstd),
+            }),
+            Message::Ack(Ack::Manifest {
+                missing: vec![vec![0..3, 7..9], vec![]],
+                placements: vec![Placement::AsSent, Placement::Renamed("a (1).txt".into())],
+            }),
+            Message::Ack(Ack::File { file: 3 }),
+            Message::Error(ErrorMessage::new(ErrorCode::Internal, "boom")),
+            Message::Bye,
+            Message::Resend(ChunkId { file: 1, index: 2 }),
+            Message::Pairing(Pairing::Confirm(vec![9; 32])),
+            Message::Signature(Signature {
+                file: 0,
+                block_size: 1024,
+                blocks: vec![BlockSignature {
+                    weak: 0xdead_beef,
+                    strong: [3; 16],
+                }],
+            }),
+            Message::Delta(Delta {
+                file: 0,
+                ops: vec![
+                    DeltaOp::Copy { first: 0, count: 4 },
+                    DeltaOp::Literal(b"new".to_vec()),
+                ],
+                done: true,
+            }),
+            Message::Receipt(Receipt {
+                receiver: "trite-metal".into(),
+                time: 1_700_000_000,
+                files: vec![ReceiptEntry {
+                    path: "dir/a.txt".into(),
+                    size: 42,
+                    hash: [5; 32],
+                }],
+                certificate: vec![0x30, 0x82],
+                scheme: 0x0403,
+                signature: vec![1; 64],
+            }),
+        ]
+    }
+
+    #[test]
+    fn encode_decode_roundtrip() {
+        for msg in all_messages() {
+            let bytes = encode(&msg).unwrap();
+            assert_eq!(decode(&bytes).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn decode_rejects_garbage() {
+        assert!(decode(&[0xff, 0xff, 0xff, 0xff, 0x01]).is_err());
+    }
+
+    #[tokio::test]
+    async fn frames_roundtrip_back_to_back() {
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        let writer = tokio::spawn(async move {
+            for msg in all_messages() {
+                write_frame(&mut a, &encode(&msg).unwrap()).await.unwrap();
+            }
+        });
+        let mut reader = FrameReader::default();
+        for expected in all_messages() {
+            let payload = reader.next(&mut b).await.unwrap().unwrap();
+            assert_eq!(decode(&payload).unwrap(), expected);
+        }
+        writer.await.unwrap();
+        // writer dropped its end => clean EOF
+        assert_eq!(reader.next(&mut b).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn frame_reader_survives_cancellation() {
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        let mut reader = FrameReader::default();
+        let payload = encode(&all_messages()[1]).unwrap();
+        let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&payload);
+
+        // Half a frame arrives, then the read is abandoned
+        a.write_all(&frame[..frame.len() / 2]).await.unwrap();
+        let abandoned =
+            tokio::time::timeout(std::time::Duration::from_millis(20), reader.next(&mut b)).await;
+        assert!(abandoned.is_err());
+
+        a.write_all(&frame[frame.len() / 2..]).await.unwrap();
+        drop(a);
+        assert_eq!(reader.next(&mut b).await.unwrap(), Some(payload));
+        assert_eq!(reader.next(&mut b).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn reader_rejects_oversized_length() {
+        let (mut a, mut b) = tokio::io::duplex(64);
+        a.write_u32(MAX_FRAME_LEN as u32 + 1).await.unwrap();
+        let err = FrameReader::default().next(&mut b).await.unwrap_err();
+        assert!(err.to_string().contains("limit"));
+    }
+
+    #[tokio::test]
+    async fn reader_detects_truncated_payload() {
+        let (mut a, mut b) = tokio::io::duplex(64);
+        a.write_u32(10).await.unwrap();
+        a.write_all(&[1, 2, 3]).await.unwrap();
+        drop(a);
+        assert!(FrameReader::default().next(&mut b).await.is_err());
+    }
+}
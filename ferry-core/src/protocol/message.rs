@@ -0,0 +1,432 @@
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// Bumped whenever the wire format changes in a way older peers can't read.
+pub const PROTOCOL_VERSION: u16 = 7;
+
+/// Everything that can travel over a ferry connection.
+/// New variants must only ever be appended, the variant index is on the wire.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Message {
+    Hello(Hello),
+    Manifest(Manifest),
+    Chunk(Chunk),
+    Ack(Ack),
+    Error(ErrorMessage),
+    Bye,
+    Resend(ChunkId),
+    Pairing(Pairing),
+    /// Asks a read-only server what is in a directory of its tree, answered with `Listing`
+    List(String),
+    Listing(Vec<RemoteEntry>),
+    /// Asks a read-only server to send a file or directory, which it does by becoming the sender
+    Get(GetRequest),
+    /// The receiver's copy of a file in a delta manifest, answered with `Delta`s
+    Signature(Signature),
+    Delta(Delta),
+    /// Sent by a server once every file of an upload is verified and in place
+    Receipt(Receipt),
+}
+
+impl Message {
+    /// Variant name, for logs and errors (Debug would dump whole chunks)
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Hello(_) => "Hello",
+            Message::Manifest(_) => "Manifest",
+            Message::Chunk(_) => "Chunk",
+            Message::Ack(_) => "Ack",
+            Message::Error(_) => "Error",
+            Message::Bye => "Bye",
+            Message::Resend(_) => "Resend",
+            Message::Pairing(_) => "Pairing",
+            Message::List(_) => "List",
+            Message::Listing(_) => "Listing",
+            Message::Get(_) => "Get",
+            Message::Signature(_) => "Signature",
+            Message::Delta(_) => "Delta",
+            Message::Receipt(_) => "Receipt",
+        }
+    }
+
+    /// Turns a message that arrived out of turn into an error.
+    /// An `Error` from the peer is passed through as is.
+    pub fn unexpected(self, expected: &str) -> anyhow::Error {
+        match self {
+            Message::Error(err) => err.into(),
+            other => anyhow::anyhow!("expected {expected}, peer sent {}", other.name()),
+        }
+    }
+}
+
+/// First message sent by both sides of a connection
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u16,
+    pub name: String,
+    /// Sent by servers started with a pairing code, clients must pair before anything else
+    pub pairing_required: bool,
+    /// Codecs chunks may be sent to this side in, preferred first. Empty turns compression off
+    /// in both directions.
+    pub compression: Vec<Compression>,
+}
+
+impl Hello {
+    pub fn new(name: &str) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            name: name.to_string(),
+            pairing_required: false,
+            compression: Vec::new(),
+        }
+    }
+}
+
+/// How a chunk's data is compressed on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Compression {
+    Zstd,
+    Lz4,
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::Zstd => write!(f, "zstd"),
+            Compression::Lz4 => write!(f, "lz4"),
+        }
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "zstd" => Ok(Compression::Zstd),
+            "lz4" => Ok(Compression::Lz4),
+            other => anyhow::bail!("unknown compression {other}, expected zstd or lz4"),
+        }
+    }
+}
+
+/// Pairing code exchange, see `pairing.rs`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Pairing {
+    Spake2(Vec<u8>),
+    Confirm(Vec<u8>),
+}
+
+/// One file or directory in a read-only server's tree
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteEntry {
+    pub name: String,
+    /// Zero for directories
+    pub size: u64,
+    pub is_dir: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GetRequest {
+    /// '/' separated path relative to the server's directory, empty for the whole tree
+    pub path: String,
+    /// Data streams the server may send on, capped by its own limit
+    pub streams: u32,
+}
+
+/// List of files the sender is about to stream.
+/// Files are referred to by their index in `files` afterwards.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub files: Vec<FileEntry>,
+    /// Directories to create that no file is sent into, '/' separated like file paths
+    pub dirs: Vec<String>,
+    /// Symlinks to create as they are, without sending what they point to
+    pub links: Vec<LinkEntry>,
+    /// Files the receiver already has a copy of are sent as a delta against it first, see
+    /// [`Signature`]
+    pub delta: bool,
+    /// What the sender wants done with files whose name is taken on the receiver, which may
+    /// settle for less
+    pub conflict: ConflictPolicy,
+}
+
+impl Manifest {
+    /// Identifies this exact set of files and contents, both sides compute it the same way
+    pub fn id(&self) -> Hash {
+        let bytes = bincode::serialize(&self.files).expect("manifest entries always serialize");
+        *blake3::hash(&bytes).as_bytes()
+    }
+}
+
+/// What a receiver does with a file whose name is already taken. Ordered from least to most
+/// willing to replace what is there, so a cap is a `min`.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum ConflictPolicy {
+    /// Refuse the whole transfer before any data is sent
+    Fail,
+    /// Leave the file that is there and drop the one sent
+    Skip,
+    /// Receive the file under a free name, `report (1).pdf`
+    Rename,
+    /// Replace the file that is there if the one sent was modified later, otherwise skip it
+    NewerWins,
+    /// Replace the file that is there
+    #[default]
+    Overwrite,
+}
+
+impl std::fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConflictPolicy::Fail => write!(f, "fail"),
+            ConflictPolicy::Skip => write!(f, "skip"),
+            ConflictPolicy::Rename => write!(f, "rename"),
+            ConflictPolicy::NewerWins => write!(f, "newer-wins"),
+            ConflictPolicy::Overwrite => write!(f, "overwrite"),
+        }
+    }
+}
+
+impl std::str::FromStr for ConflictPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "fail" => Ok(ConflictPolicy::Fail),
+            "skip" => Ok(ConflictPolicy::Skip),
+            "rename" => Ok(ConflictPolicy::Rename),
+            "newer-wins" => Ok(ConflictPolicy::NewerWins),
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            other => anyhow::bail!(
+                "unknown conflict policy {other}, expected overwrite, skip, rename, newer-wins or fail"
+            ),
+        }
+    }
+}
+
+/// Where the receiver puts a file of the manifest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Placement {
+    /// At the path it was sent with
+    AsSent,
+    /// Nowhere, the receiver keeps the file that has its name
+    Kept,
+    /// At this path instead, its own was taken
+    Renamed(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileEntry {
+    /// Relative path, always '/' separated regardless of the sender's OS
+    pub path: String,
+    pub size: u64,
+    /// How the file's data is cut up, in order and covering every byte outside `holes`
+    pub chunks: Vec<ChunkInfo>,
+    /// Byte ranges that are holes in the sender's file, in order. They read as zeros, are never
+    /// sent and stay holes on the receiver where its filesystem allows.
+    pub holes: Vec<Range<u64>>,
+    pub meta: FileMeta,
+    /// BLAKE3 of the whole file, holes read as zeros. The receiver checks the assembled file
+    /// against it before giving it its name.
+    pub hash: Hash,
+}
+
+impl FileEntry {
+    /// Bytes of the file that are holes
+    pub fn hole_len(&self) -> u64 {
+        self.holes.iter().map(|h| h.end - h.start).sum()
+    }
+}
+
+/// What the sender carries over about a file besides its content, whatever it leaves out is
+/// `None` or empty and left to the receiver's defaults
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMeta {
+    /// Unix permission bits
+    pub mode: Option<u32>,
+    /// Modification time as seconds and nanoseconds since the Unix epoch
+    pub mtime: Option<(i64, u32)>,
+    /// Extended attributes, only ever in the `user.` namespace
+    pub xattrs: Vec<(String, Vec<u8>)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkEntry {
+    /// Where the link goes, like [`FileEntry::path`]
+    pub path: String,
+    /// What the link points to, relative to the directory it is in
+    pub target: String,
+}
+
+/// BLAKE3 digest
+pub type Hash = [u8; 32];
+
+/// Checksums of the blocks of the receiver's copy of a file, before it is sent as a delta
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Signature {
+    pub file: u32,
+    pub block_size: u32,
+    /// Every whole block in order, a shorter tail is left out
+    pub blocks: Vec<BlockSignature>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockSignature {
+    /// rsync's rolling checksum, cheap to slide over the sender's file a byte at a time
+    pub weak: u32,
+    /// Start of the block's BLAKE3 hash, checked when the weak one matches
+    pub strong: [u8; 16],
+}
+
+/// Part of a file rebuilt from blocks of the receiver's copy and literal data, in order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delta {
+    pub file: u32,
+    pub ops: Vec<DeltaOp>,
+    /// Last part of this file's delta
+    pub done: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeltaOp {
+    /// `count` blocks of the receiver's copy starting at block `first`
+    Copy {
+        first: u32,
+        count: u32,
+    },
+    Literal(Vec<u8>),
+}
+
+/// The receiver's signed word that it holds these files, see `receipt.rs`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt {
+    /// Name of the server that received the files
+    pub receiver: String,
+    /// Seconds since the Unix epoch when the last file was in place
+    pub time: u64,
+    pub files: Vec<ReceiptEntry>,
+    /// The receiver's certificate (DER), whose key made `signature`
+    pub certificate: Vec<u8>,
+    /// TLS `SignatureScheme` code of `signature`
+    pub scheme: u16,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReceiptEntry {
+    pub path: String,
+    pub size: u64,
+    /// Whole file hash, as in [`FileEntry::hash`]
+    pub hash: Hash,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkInfo {
+    pub offset: u64,
+    pub len: u32,
+    pub hash: Hash,
+}
+
+/// Contents of one chunk announced in the manifest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chunk {
+    pub file: u32,
+    pub index: u32,
+    pub data: Vec<u8>,
+    /// How `data` is compressed, `None` when it is the chunk as is
+    pub compression: Option<Compression>,
+}
+
+/// Half open runs of chunk indexes, e.g. `[0..4, 9..10]`
+pub type ChunkRanges = Vec<Range<u32>>;
+
+/// Points at a chunk of a file in the manifest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ChunkId {
+    pub file: u32,
+    pub index: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Ack {
+    /// Receiver is ready for the files in the manifest and lists, per file, the chunks it
+    /// still needs. Anything not listed is already there from an earlier attempt. Skipped
+    /// files need nothing.
+    Manifest {
+        missing: Vec<ChunkRanges>,
+        /// Where each file goes, see [`ConflictPolicy`]
+        placements: Vec<Placement>,
+    },
+    /// File has been fully written by the receiver
+    File { file: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorMessage {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ErrorMessage {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ErrorMessage {}
+
+/// Machine readable reason carried by [`Message::Error`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    UnsupportedVersion,
+    UnexpectedMessage,
+    Internal,
+    InvalidManifest,
+    ServerBusy,
+    AuthRequired,
+    AuthFailed,
+    /// The receiver declined the files it was offered
+    Rejected,
+    /// The server only shares its files, it takes no uploads
+    ReadOnly,
+    /// The server takes uploads but doesn't share its files
+    NotShared,
+    NotFound,
+    /// The transfer is bigger than the receiver accepts
+    TooLarge,
+    /// The receiver doesn't have room for the transfer
+    InsufficientSpace,
+    /// A file came out different from what the sender hashed, the receiver threw it away
+    IntegrityFailed,
+    /// A file by the same name is already there and the conflict policy is to fail
+    AlreadyExists,
+    /// A file would replace a directory or something else that isn't a file
+    NotAFile,
+}
+
+impl ErrorCode {
+    /// The receiver turned files down before any data moved, the session carries on
+    pub fn is_refusal(self) -> bool {
+        matches!(
+            self,
+            ErrorCode::Rejected
+                | ErrorCode::ReadOnly
+                | ErrorCode::TooLarge
+                | ErrorCode::InsufficientSpace
+                | ErrorCode::AlreadyExists
+                | ErrorCode::NotAFile
+        )
+    }
+}
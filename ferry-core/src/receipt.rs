@@ -0,0 +1,244 @@
+use crate::identity::{Fingerprint, ServerIdentity};
+use crate::protocol::{FileEntry, Placement, Receipt, ReceiptEntry};
+use anyhow::{Context, anyhow, ensure};
+use rustls::SignatureScheme;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::sign::SigningKey;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A server confirms every upload with a receipt: the files it now holds and their whole file
+// hashes, signed with the key of its certificate. The signature covers a plain text rendering of
+// the receipt (`signed_text`), so it can be checked without ferry against the certificate whose
+// fingerprint the client pinned.
+
+/// Tried in order, whatever key the server has supports one of them
+const SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::ED25519,
+    SignatureScheme::RSA_PSS_SHA256,
+];
+
+/// Signs receipts with the key of the server's certificate
+pub(crate) struct ReceiptSigner {
+    name: String,
+    certificate: CertificateDer<'static>,
+    key: Arc<dyn SigningKey>,
+}
+
+impl ReceiptSigner {
+    pub(crate) fn new(name: &str, identity: &ServerIdentity) -> anyhow::Result<Self> {
+        let key = PrivateKeyDer::Pkcs8(identity.key.clone_key());
+        let key = rustls::crypto::ring::sign::any_supported_type(&key)
+            .context("cannot sign with the server key")?;
+        Ok(Self {
+            name: name.to_string(),
+            certificate: identity.cert.clone(),
+            key,
+        })
+    }
+
+    /// A receipt for `files`, once all of them are verified and in place where `placements`
+    /// says. Files that were kept out are left off it.
+    pub(crate) fn sign(
+        &self,
+        files: &[FileEntry],
+        placements: &[Placement],
+    ) -> anyhow::Result<Receipt> {
+        let signer = self
+            .key
+            .choose_scheme(SCHEMES)
+            .context("the server key can't sign receipts")?;
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut receipt = Receipt {
+            receiver: self.name.clone(),
+            time,
+            files: entries(files, placements),
+            certificate: self.certificate.to_vec(),
+            scheme: u16::from(signer.scheme()),
+            signature: Vec::new(),
+        };
+        receipt.signature = signer.sign(signed_text(&receipt).as_bytes())?;
+        Ok(receipt)
+    }
+}
+
+/// Checks that `receipt` lists exactly the files that were `sent`, where the server said it put
+/// them, and was signed by the key of `certificate`, the one the server presented
+pub(crate) fn verify<'a>(
+    receipt: &Receipt,
+    sent: impl IntoIterator<Item = &'a FileEntry>,
+    placements: &[Placement],
+    certificate: &[u8],
+) -> anyhow::Result<()> {
+    ensure!(
+        receipt.certificate == certificate,
+        "receipt is signed with another certificate than the server's"
+    );
+    ensure!(
+        receipt.files == entries(sent, placements),
+        "receipt does not list the files that were sent"
+    );
+    let scheme = SignatureScheme::from(receipt.scheme);
+    let algorithms = rustls::crypto::ring::default_provider()
+        .signature_verification_algorithms
+        .mapping
+        .iter()
+        .find_map(|(s, algorithms)| (*s == scheme).then_some(*algorithms))
+        .with_context(|| format!("receipt is signed with unsupported scheme {scheme:?}"))?;
+    let certificate = CertificateDer::from(certificate);
+    let certificate = webpki::EndEntityCert::try_from(&certificate)
+        .map_err(|e| anyhow!("receipt certificate is invalid: {e}"))?;
+    let text = signed_text(receipt);
+    let valid = algorithms.iter().any(|algorithm| {
+        certificate
+            .verify_signature(*algorithm, text.as_bytes(), &receipt.signature)
+            .is_ok()
+    });
+    ensure!(valid, "receipt signature is not valid");
+    Ok(())
+}
+
+fn entries<'a>(
+    files: impl IntoIterator<Item = &'a FileEntry>,
+    placements: &[Placement],
+) -> Vec<ReceiptEntry> {
+    files
+        .into_iter()
+        .zip(placements)
+        .filter_map(|(f, placement)| {
+            let path = match placement {
+                Placement::AsSent => f.path.clone(),
+                Placement::Renamed(path) => path.clone(),
+                Placement::Kept => return None,
+            };
+            Some(ReceiptEntry {
+                path,
+                size: f.size,
+                hash: f.hash,
+            })
+        })
+        .collect()
+}
+
+/// What the signature covers, one line each: `ferry receipt v1`, `receiver <name>`,
+/// `time <unix seconds>`, then `<blake3 hex> <size> <path>` for every file. Names and paths
+/// are written as JSON strings so no character in them can break the lines up.
+fn signed_text(receipt: &Receipt) -> String {
+    let quote = |s: &str| serde_json::to_string(s).expect("strings always serialize");
+    let mut text = format!(
+        "ferry receipt v1\nreceiver {}\ntime {}\n",
+        quote(&receipt.receiver),
+        receipt.time
+    );
+    for file in &receipt.files {
+        text += &format!("{} {} {}\n", hex(&file.hash), file.size, quote(&file.path));
+    }
+    text
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// How a receipt is kept on disk, binary fields in hex
+#[derive(Serialize)]
+struct StoredReceipt<'a> {
+    receiver: &'a str,
+    time: u64,
+    files: Vec<StoredFile<'a>>,
+    /// SHA-256 of `certificate`, as pinned in `known_hosts`
+    fingerprint: String,
+    certificate: String,
+    scheme: String,
+    signature: String,
+    /// Exactly the text `signature` was made over
+    signed: String,
+}
+
+#[derive(Serialize)]
+struct StoredFile<'a> {
+    path: &'a str,
+    size: u64,
+    blake3: String,
+}
+
+impl Receipt {
+    /// The receipt as pretty printed JSON, with the text its signature covers
+    pub fn to_json(&self) -> String {
+        let stored = StoredReceipt {
+            receiver: &self.receiver,
+            time: self.time,
+            files: self
+                .files
+                .iter()
+                .map(|f| StoredFile {
+                    path: &f.path,
+                    size: f.size,
+                    blake3: hex(&f.hash),
+                })
+                .collect(),
+            fingerprint: Fingerprint::of(&self.certificate).to_string(),
+            certificate: hex(&self.certificate),
+            scheme: format!("{:?}", SignatureScheme::from(self.scheme)),
+            signature: hex(&self.signature),
+            signed: signed_text(self),
+        };
+        serde_json::to_string_pretty(&stored).expect("receipts always serialize")
+    }
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files() -> Vec<FileEntry> {
+        vec![FileEntry {
+            path: "logs/app\n.log".into(),
+            size: 11,
+            chunks: Vec::new(),
+            holes: Vec::new(),
+            meta: Default::default(),
+            hash: crate::chunker::hash(b"hello world"),
+        }]
+    }
+
+    #[test]
+    fn receipts_check_out_until_tampered_with() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = ServerIdentity::load_or_create(dir.path()).unwrap();
+        let signer = ReceiptSigner::new("trite-metal", &identity).unwrap();
+        let files = files();
+        let placed = [Placement::AsSent];
+        let receipt = signer.sign(&files, &placed).unwrap();
+        verify(&receipt, &files, &placed, &identity.cert).unwrap();
+
+        let mut forged = receipt.clone();
+        forged.files[0].size = 12;
+        assert!(verify(&forged, &files, &placed, &identity.cert).is_err());
+        let mut forged = receipt.clone();
+        forged.time += 1;
+        assert!(verify(&forged, &files, &placed, &identity.cert).is_err());
+        let renamed = [Placement::Renamed("logs/app (1).log".into())];
+        assert!(verify(&receipt, &files, &renamed, &identity.cert).is_err());
+
+        let other = tempfile::tempdir().unwrap();
+        let other = ServerIdentity::load_or_create(other.path()).unwrap();
+        assert!(verify(&receipt, &files, &placed, &other.cert).is_err());
+
+        let json: serde_json::Value = serde_json::from_str(&receipt.to_json()).unwrap();
+        assert_eq!(json["fingerprint"], identity.fingerprint().to_string());
+        assert_eq!(json["files"][0]["path"], "logs/app\n.log");
+        assert!(
+            json["signed"]
+                .as_str()
+                .unwrap()
+                .ends_with(" 11 \"logs/app\\n.log\"\n")
+        );
+    }
+}
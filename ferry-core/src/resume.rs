@@ -0,0 +1,296 @@
+use crate::chunker;
+use crate::protocol::{ChunkInfo, ChunkRanges, FileEntry, Hash};
+use crate::sandbox::Sandbox;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+// Sidecar state kept next to partially received files so an interrupted transfer can pick up
+// where it stopped. For `dir/movie.mkv` the data goes to `dir/.movie.mkv.ferry-part` and the
+// state to `dir/.movie.mkv.ferry-state`, the part file is renamed into place once complete.
+
+const PART_SUFFIX: &str = ".ferry-part";
+const STATE_SUFFIX: &str = ".ferry-state";
+/// Bumped when `PartialState` changes shape, older state files are simply ignored
+const STATE_FORMAT: u16 = 1;
+
+/// Which chunks of a file have been received
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Bitmap {
+    bits: Vec<u8>,
+    len: usize,
+}
+
+impl Bitmap {
+    pub(crate) fn new(len: usize) -> Self {
+        Self {
+            bits: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    pub(crate) fn get(&self, index: usize) -> bool {
+        index < self.len && self.bits[index / 8] & (1 << (index % 8)) != 0
+    }
+
+    pub(crate) fn set(&mut self, index: usize) {
+        self.bits[index / 8] |= 1 << (index % 8);
+    }
+
+    pub(crate) fn clear(&mut self, index: usize) {
+        self.bits[index / 8] &= !(1 << (index % 8));
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.bits.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    /// Runs of chunks that are still missing
+    pub(crate) fn missing_ranges(&self) -> ChunkRanges {
+        let mut ranges = Vec::new();
+        let mut start = None;
+        for index in 0..self.len {
+            match (self.get(index), start) {
+                (false, None) => start = Some(index as u32),
+                (true, Some(s)) => {
+                    ranges.push(s..index as u32);
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(s) = start {
+            ranges.push(s..self.len as u32);
+        }
+        ranges
+    }
+}
+
+/// What the sidecar file remembers about a partially received file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct PartialState {
+    format: u16,
+    pub(crate) manifest_id: Hash,
+    pub(crate) path: String,
+    pub(crate) size: u64,
+    pub(crate) chunks: Vec<ChunkInfo>,
+    pub(crate) completed: Bitmap,
+}
+
+impl PartialState {
+    pub(crate) fn new(manifest_id: Hash, entry: &FileEntry) -> Self {
+        Self {
+            format: STATE_FORMAT,
+            manifest_id,
+            path: entry.path.clone(),
+            size: entry.size,
+            chunks: entry.chunks.clone(),
+            completed: Bitmap::new(entry.chunks.len()),
+        }
+    }
+
+    /// The same file content as `entry` is being received, as part of the same manifest
+    fn matches(&self, manifest_id: Hash, entry: &FileEntry) -> bool {
+        self.format == STATE_FORMAT
+            && self.manifest_id == manifest_id
+            && self.size == entry.size
+            && self.chunks == entry.chunks
+            && self.completed.len == entry.chunks.len()
+            && self.completed.bits.len() == self.completed.len.div_ceil(8)
+    }
+}
+
+/// Whether `name` is the part or state file of a transfer in progress
+pub(crate) fn is_partial(name: &str) -> bool {
+    name.starts_with('.') && (name.ends_with(PART_SUFFIX) || name.ends_with(STATE_SUFFIX))
+}
+
+/// Where the pieces of one incoming file live, relative to the [`Sandbox`] they are written in
+#[derive(Debug, Clone)]
+pub(crate) struct Partial {
+    pub(crate) target: PathBuf,
+    pub(crate) part: PathBuf,
+    state: PathBuf,
+}
+
+impl Partial {
+    pub(crate) fn for_target(target: &Path) -> Self {
+        let name = target
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let sibling = |suffix: &str| target.with_file_name(format!(".{name}{suffix}"));
+        Self {
+            target: target.to_path_buf(),
+            part: sibling(PART_SUFFIX),
+            state: sibling(STATE_SUFFIX),
+        }
+    }
+
+    /// Picks up an earlier attempt at receiving `entry` with the manifest `manifest_id`, if
+    /// there is one. Chunks the state claims are done get hashed again so a stale or tampered part file
+    /// can't sneak into the result, the ones that don't match are marked missing.
+    pub(crate) async fn load(
+        &self,
+        sandbox: &Sandbox,
+        manifest_id: Hash,
+        entry: &FileEntry,
+    ) -> Option<PartialState> {
+        let bytes = sandbox.read(&self.state).await.ok()?;
+        let mut state: PartialState = bincode::deserialize(&bytes).ok()?;
+        if !state.matches(manifest_id, entry) {
+            return None;
+        }
+        let mut part = sandbox.open_file(&self.part).await.ok()?;
+        if part.metadata().await.ok()?.len() != entry.size {
+            return None;
+        }
+        let mut buf = Vec::new();
+        for (index, info) in entry.chunks.iter().enumerate() {
+            if !state.completed.get(index) {
+                continue;
+            }
+            buf.resize(info.len as usize, 0);
+            let intact = part.seek(SeekFrom::Start(info.offset)).await.is_ok()
+                && part.read_exact(&mut buf).await.is_ok()
+                && chunker::verify(info, &buf);
+            if !intact {
+                state.completed.clear(index);
+            }
+        }
+        Some(state)
+    }
+
+    /// Writes the state next to the part file, replacing the previous one atomically
+    pub(crate) async fn save(&self, sandbox: &Sandbox, state: &PartialState) -> anyhow::Result<()> {
+        let bytes = bincode::serialize(state)?;
+        let mut tmp = self.state.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        sandbox
+            .write(&tmp, bytes)
+            .await
+            .with_context(|| format!("write {}", sandbox.display(&tmp).display()))?;
+        sandbox.rename(&tmp, &self.state).await?;
+        Ok(())
+    }
+
+    /// Moves the completed part file into place and forgets the state. Unless it may `replace`
+    /// it, something that took the target's name in the meantime makes this fail.
+    pub(crate) async fn finish(&self, sandbox: &Sandbox, replace: bool) -> anyhow::Result<()> {
+        let moved = match replace {
+            true => sandbox.rename(&self.part, &self.target).await,
+            false => sandbox.rename_new(&self.part, &self.target).await,
+        };
+        moved.with_context(|| {
+            format!(
+                "move {} into place",
+                sandbox.display(&self.target).display()
+            )
+        })?;
+        let _ = sandbox.remove_file(&self.state).await;
+        Ok(())
+    }
+
+    /// Throws away the part file and the state, the next attempt starts from scratch
+    pub(crate) async fn discard(&self, sandbox: &Sandbox) {
+        let _ = sandbox.remove_file(&self.part).await;
+        let _ = sandbox.remove_file(&self.state).await;
+    }
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bitmap_with(len: usize, set: &[usize]) -> Bitmap {
+        let mut bitmap = Bitmap::new(len);
+        for i in set {
+            bitmap.set(*i);
+        }
+        bitmap
+    }
+
+    #[test]
+    fn bitmap_tracks_bits() {
+        let mut bitmap = bitmap_with(20, &[0, 9, 19]);
+        assert!(bitmap.get(9));
+        assert!(!bitmap.get(10));
+        assert!(!bitmap.get(25), "out of range reads as missing");
+        assert_eq!(bitmap.count(), 3);
+        bitmap.clear(9);
+        assert_eq!(bitmap.count(), 2);
+        assert!(!bitmap.is_complete());
+    }
+
+    #[test]
+    fn missing_ranges_are_compact() {
+        assert_eq!(Bitmap::new(5).missing_ranges(), vec![0..5]);
+        assert_eq!(
+            bitmap_with(10, &[0, 1, 4, 9]).missing_ranges(),
+            vec![2..4, 5..9]
+        );
+        assert!(bitmap_with(3, &[0, 1, 2]).missing_ranges().is_empty());
+        assert!(Bitmap::new(0).missing_ranges().is_empty());
+    }
+
+    #[test]
+    fn sidecar_names_sit_next_to_target() {
+        let partial = Partial::for_target(Path::new("/srv/in/movie.mkv"));
+        assert_eq!(partial.part, Path::new("/srv/in/.movie.mkv.ferry-part"));
+        assert_eq!(partial.state, Path::new("/srv/in/.movie.mkv.ferry-state"));
+    }
+
+    #[tokio::test]
+    async fn load_drops_chunks_that_no_longer_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let sandbox = Sandbox::open(dir.path()).unwrap();
+        let partial = Partial::for_target(Path::new("f.bin"));
+        let data = vec![7u8; 300];
+        let chunk = |offset: usize| ChunkInfo {
+            offset: offset as u64,
+            len: 100,
+            hash: chunker::hash(&data[offset..offset + 100]),
+        };
+        let entry = FileEntry {
+            path: "f.bin".into(),
+            size: 300,
+            chunks: vec![chunk(0), chunk(100), chunk(200)],
+            holes: Vec::new(),
+            meta: Default::default(),
+            hash: [0; 32],
+        };
+
+        // chunk 0 really is on disk, chunk 1 is claimed but zeroed
+        let mut on_disk = vec![0u8; 300];
+        on_disk[..100].copy_from_slice(&data[..100]);
+        std::fs::write(sandbox.display(&partial.part), &on_disk).unwrap();
+        let mut state = PartialState::new([1; 32], &entry);
+        state.completed.set(0);
+        state.completed.set(1);
+        partial.save(&sandbox, &state).await.unwrap();
+
+        let loaded = partial.load(&sandbox, [1; 32], &entry).await.unwrap();
+        assert!(loaded.completed.get(0));
+        assert!(!loaded.completed.get(1));
+        assert_eq!(loaded.completed.missing_ranges(), vec![1..3]);
+
+        let mut other = entry.clone();
+        other.chunks[2].hash = [0; 32];
+        assert!(
+            partial.load(&sandbox, [1; 32], &other).await.is_none(),
+            "different content starts over"
+        );
+        assert!(
+            partial.load(&sandbox, [2; 32], &entry).await.is_none(),
+            "so does another manifest"
+        );
+    }
+}
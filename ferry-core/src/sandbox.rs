@@ -0,0 +1,573 @@
+use cap_std::ambient_authority;
+use cap_std::fs::{Dir, OpenOptions};
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Peers decide the names of the files we write. Their paths are checked by `normalize` and then
+// only ever opened relative to a handle on the receiving directory (openat and friends), so a
+// symlink or a rename racing with us can't point a write outside of it either.
+
+/// Names Windows treats as devices in every directory, whatever the extension
+const DEVICE_NAMES: &[&str] = &["CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$"];
+/// Followed by a digit these are devices as well, e.g. `COM1`, `LPT9`
+const NUMBERED_DEVICES: &[&str] = &["COM", "LPT"];
+
+/// Turns a '/' separated path from a peer into a relative path, refusing anything that could
+/// land outside the directory it's meant for: `..`, absolute paths and drive letters, device
+/// names, and characters some filesystem reads differently. `.` and repeated slashes are dropped.
+pub(crate) fn normalize(relative: &str) -> Result<PathBuf, String> {
+    if relative.starts_with('/') {
+        return Err(format!("{relative:?} is absolute"));
+    }
+    let mut path = PathBuf::new();
+    for component in relative.split('/') {
+        match component {
+            "" | "." => continue,
+            ".." => return Err(format!("{relative:?} leaves the directory")),
+            name => check_name(name).map_err(|why| format!("{relative:?} {why}"))?,
+        }
+        path.push(component);
+    }
+    if path.as_os_str().is_empty() {
+        return Err(format!("{relative:?} names no file"));
+    }
+    Ok(path)
+}
+
+/// Refuses a symlink at `link` (already normalized) whose `target` would lead out of the
+/// directory it's created in, so the links we create never point anywhere we couldn't write.
+/// `..` may only come first: after a name it would climb out of wherever that name leads, which
+/// is somewhere else entirely when it is a link itself.
+pub(crate) fn check_link(link: &Path, target: &str) -> Result<(), String> {
+    if target.is_empty() || target.starts_with('/') || target.contains(['\\', ':']) {
+        return Err(format!("link {} points to {target:?}", link.display()));
+    }
+    // How deep below the root the directory holding the link is
+    let mut depth = link.components().count().saturating_sub(1);
+    let mut descended = false;
+    for component in target.split('/') {
+        match component {
+            "" | "." => {}
+            ".." if descended => {
+                return Err(format!(
+                    "link {} goes back up after a name: {target:?}",
+                    link.display()
+                ));
+            }
+            ".." if depth == 0 => {
+                return Err(format!(
+                    "link {} points outside: {target:?}",
+                    link.display()
+                ));
+            }
+            ".." => depth -= 1,
+            _ => {
+                depth += 1;
+                descended = true;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Refuses `path` (already normalized) when one of its parents is among `links`, the links the
+/// same transfer creates. Where a link below another one leads depends on where that one does,
+/// which `check_link` can't see.
+pub(crate) fn check_parents(path: &Path, links: &HashSet<PathBuf>) -> Result<(), String> {
+    match path
+        .ancestors()
+        .skip(1)
+        .find(|parent| links.contains(*parent))
+    {
+        Some(link) => Err(format!(
+            "{} goes through link {}",
+            path.display(),
+            link.display()
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Refuses a single path component that isn't an ordinary file name everywhere
+fn check_name(name: &str) -> Result<(), &'static str> {
+    if name.chars().any(|c| c.is_control()) {
+        return Err("contains control characters");
+    }
+    if name.contains(['\\', ':']) {
+        // Separators, drive letters and alternate data streams on Windows
+        return Err("contains '\\' or ':'");
+    }
+    if name.ends_with(['.', ' ']) {
+        // Windows drops these, so `a.` and `a` would be the same file
+        return Err("ends with a dot or a space");
+    }
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    let is_device = DEVICE_NAMES.iter().any(|d| stem.eq_ignore_ascii_case(d))
+        || NUMBERED_DEVICES.iter().any(|d| {
+            let stem = stem.as_bytes();
+            stem.len() == d.len() + 1
+                && stem[..d.len()].eq_ignore_ascii_case(d.as_bytes())
+                && stem[d.len()].is_ascii_digit()
+        });
+    if is_device {
+        return Err("is a device name");
+    }
+    Ok(())
+}
+
+/// A directory that files can only be created, read, renamed and removed beneath. Paths are
+/// resolved relative to a handle on it and anything escaping it, symlinks included, fails.
+#[derive(Debug, Clone)]
+pub(crate) struct Sandbox {
+    dir: Arc<Dir>,
+    root: PathBuf,
+}
+
+impl Sandbox {
+    /// Opens `root`, creating it if needed
+    pub(crate) fn open(root: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(root)?;
+        let dir = Dir::open_ambient_dir(root, ambient_authority())?;
+        Ok(Self {
+            dir: Arc::new(dir),
+            root: root.to_path_buf(),
+        })
+    }
+
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where `relative` is for messages, nothing should be opened through it
+    pub(crate) fn display(&self, relative: &Path) -> PathBuf {
+        self.root.join(relative)
+    }
+
+    /// Opens `relative` for writing and reading back, creating it and its parent directories if
+    /// needed
+    pub(crate) async fn create(
+        &self,
+        relative: &Path,
+        truncate: bool,
+    ) -> io::Result<tokio::fs::File> {
+        let relative = relative.to_path_buf();
+        let file = self
+            .blocking(move |dir| {
+                if let Some(parent) = relative.parent().filter(|p| !p.as_os_str().is_empty()) {
+                    dir.create_dir_all(parent)?;
+                }
+                let mut options = OpenOptions::new();
+                options
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(truncate);
+                dir.open_with(&relative, &options)
+            })
+            .await?;
+        Ok(tokio::fs::File::from_std(file.into_std()))
+    }
+
+    pub(crate) async fn create_dir_all(&self, relative: &Path) -> io::Result<()> {
+        let relative = relative.to_path_buf();
+        self.blocking(move |dir| dir.create_dir_all(&relative))
+            .await
+    }
+
+    /// Creates a symlink at `relative` pointing to `target`, replacing a file or link there if
+    /// `replace` says so. Refused when a directory above it is a link, `target` was checked
+    /// against where the link seems to be and not where that one leads.
+    #[cfg(unix)]
+    pub(crate) async fn symlink(
+        &self,
+        target: &str,
+        relative: &Path,
+        replace: bool,
+    ) -> io::Result<()> {
+        let (target, relative) = (target.to_string(), relative.to_path_buf());
+        self.blocking(move |dir| {
+            for parent in relative.ancestors().skip(1) {
+                let through_link = !parent.as_os_str().is_empty()
+                    && dir
+                        .symlink_metadata(parent)
+                        .is_ok_and(|meta| meta.file_type().is_symlink());
+                if through_link {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{} is a link", parent.display()),
+                    ));
+                }
+            }
+            if let Some(parent) = relative.parent().filter(|p| !p.as_os_str().is_empty()) {
+                dir.create_dir_all(parent)?;
+            }
+            if replace {
+                match dir.remove_file(&relative) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            dir.symlink(&target, &relative)
+        })
+        .await
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) async fn symlink(
+        &self,
+        _target: &str,
+        _relative: &Path,
+        _replace: bool,
+    ) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "symlinks are only created on Unix",
+        ))
+    }
+
+    /// Opens `relative` for reading
+    pub(crate) async fn open_file(&self, relative: &Path) -> io::Result<tokio::fs::File> {
+        let relative = relative.to_path_buf();
+        let file = self.blocking(move |dir| dir.open(&relative)).await?;
+        Ok(tokio::fs::File::from_std(file.into_std()))
+    }
+
+    pub(crate) async fn read(&self, relative: &Path) -> io::Result<Vec<u8>> {
+        let relative = relative.to_path_buf();
+        self.blocking(move |dir| dir.read(&relative)).await
+    }
+
+    pub(crate) async fn write(&self, relative: &Path, bytes: Vec<u8>) -> io::Result<()> {
+        let relative = relative.to_path_buf();
+        self.blocking(move |dir| dir.write(&relative, bytes)).await
+    }
+
+    /// Moves `from` to `to`, replacing whatever file is there
+    pub(crate) async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from, to) = (from.to_path_buf(), to.to_path_buf());
+        self.blocking(move |dir| dir.rename(&from, dir, &to)).await
+    }
+
+    /// Moves `from` to `to` unless something is there already, which fails with `AlreadyExists`.
+    /// Where hard links aren't supported a look just before the move has to do.
+    pub(crate) async fn rename_new(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from, to) = (from.to_path_buf(), to.to_path_buf());
+        self.blocking(move |dir| match dir.hard_link(&from, dir, &to) {
+            Ok(()) => dir.remove_file(&from),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(e),
+            Err(_) if dir.symlink_metadata(&to).is_ok() => {
+                Err(io::Error::from(io::ErrorKind::AlreadyExists))
+            }
+            Err(_) => dir.rename(&from, dir, &to),
+        })
+        .await
+    }
+
+    /// What is at `relative`, a symlink itself rather than what it points to
+    pub(crate) async fn metadata(&self, relative: &Path) -> io::Result<cap_std::fs::Metadata> {
+        let relative = relative.to_path_buf();
+        self.blocking(move |dir| dir.symlink_metadata(&relative))
+            .await
+    }
+
+    pub(crate) async fn remove_file(&self, relative: &Path) -> io::Result<()> {
+        let relative = relative.to_path_buf();
+        self.blocking(move |dir| dir.remove_file(&relative)).await
+    }
+
+    /// Runs `op` on the directory handle off the async threads, like `tokio::fs` does
+    async fn blocking<R, F>(&self, op: F) -> io::Result<R>
+    where
+        F: FnOnce(&Dir) -> io::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let dir = self.dir.clone();
+        tokio::task::spawn_blocking(move || op(&dir))
+            .await
+            .map_err(io::Error::other)?
+    }
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::path::Component;
+
+    #[test]
+    fn plain_paths_are_normalized() {
+        assert_eq!(normalize("a/b.txt").unwrap(), Path::new("a/b.txt"));
+        assert_eq!(normalize("./a//b/").unwrap(), Path::new("a/b"));
+        assert_eq!(
+            normalize("cons/console.txt").unwrap(),
+            Path::new("cons/console.txt")
+        );
+    }
+
+    #[test]
+    fn escaping_and_odd_paths_are_refused() {
+        for path in [
+            "",
+            ".",
+            "/etc/passwd",
+            "a/../../b",
+            "..",
+            "C:/Windows",
+            "c:evil",
+            "a\\..\\b",
+            "NUL",
+            "dir/con.txt",
+            "Com1",
+            "lpt9.log",
+            "name.",
+            "name ",
+            "tab\there",
+        ] {
+            assert!(normalize(path).is_err(), "{path:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn links_may_only_point_within_the_root() {
+        let link = |path: &str, target: &str| check_link(Path::new(path), target);
+        assert!(link("a/b/link", "../../file").is_ok());
+        assert!(link("a/link", "./b/c").is_ok());
+        assert!(link("a/link", "./b/../c").is_err());
+        assert!(link("link", "sub/file").is_ok());
+        assert!(link("a/link", "../../file").is_err());
+        assert!(link("link", "..").is_err());
+        assert!(link("link", "/etc/passwd").is_err());
+        assert!(link("link", "").is_err());
+
+        // Each fine on its own, but `x/l` leads to the root, so `x/l/l2` is really `l2`
+        let links: HashSet<PathBuf> = ["x/l", "x/l/l2"].into_iter().map(PathBuf::from).collect();
+        assert!(link("x/l", "..").is_ok() && link("x/l/l2", "../../etc").is_ok());
+        assert!(check_parents(Path::new("x/l"), &links).is_ok());
+        assert!(check_parents(Path::new("x/l/l2"), &links).is_err());
+        assert!(check_parents(Path::new("x/l/sub/file"), &links).is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn links_are_never_checked_through_other_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let sandbox = Sandbox::open(&root).unwrap();
+        check_link(Path::new("sub/x"), "..").unwrap();
+        sandbox
+            .symlink("..", Path::new("sub/x"), false)
+            .await
+            .unwrap();
+
+        // `sub/x` leads to the root, so `sub/x/..` is the directory above it
+        assert!(check_link(Path::new("a"), "sub/x/..").is_err());
+        // A later transfer can't hang a link below it either
+        check_link(Path::new("sub/x/l2"), "../../etc").unwrap();
+        assert!(
+            sandbox
+                .symlink("../../etc", Path::new("sub/x/l2"), false)
+                .await
+                .is_err()
+        );
+        assert!(std::fs::symlink_metadata(root.join("l2")).is_err());
+    }
+
+    #[tokio::test]
+    async fn symlinks_out_of_the_root_are_not_followed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (root, outside) = (dir.path().join("root"), dir.path().join("outside"));
+        std::fs::create_dir_all(&outside).unwrap();
+        let sandbox = Sandbox::open(&root).unwrap();
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+            std::os::unix::fs::symlink(outside.join("f"), root.join("file-link")).unwrap();
+        }
+
+        assert!(sandbox.create(Path::new("link/f"), true).await.is_err());
+        assert!(sandbox.create(Path::new("file-link"), true).await.is_err());
+        assert!(
+            sandbox
+                .rename(Path::new("missing"), Path::new("link/f"))
+                .await
+                .is_err()
+        );
+        assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+
+        sandbox
+            .create(Path::new("in/side.txt"), true)
+            .await
+            .unwrap();
+        assert!(root.join("in/side.txt").is_file());
+    }
+
+    /// Path components a hostile peer might try, glued together in any order
+    fn hostile_path() -> impl Strategy<Value = String> {
+        let component = prop_oneof![
+            Just("..".to_string()),
+            Just(".".to_string()),
+            Just(String::new()),
+            Just("link".to_string()),
+            Just("CON".to_string()),
+            Just("C:".to_string()),
+            Just("a\\..".to_string()),
+            "[a-z]{1,4}",
+            "\\PC{1,6}",
+        ];
+        (any::<bool>(), prop::collection::vec(component, 0..6)).prop_map(|(absolute, parts)| {
+            let path = parts.join("/");
+            match absolute {
+                true => format!("/{path}"),
+                false => path,
+            }
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn normalized_paths_stay_below_the_root(path in hostile_path()) {
+            if let Ok(normal) = normalize(&path) {
+                prop_assert!(normal.components().all(|c| matches!(c, Component::Normal(_))));
+                prop_assert!(Path::new("/srv/root").join(&normal).starts_with("/srv/root"));
+            }
+        }
+
+        #[test]
+        fn normalize_never_panics(path in "\\PC*") {
+            let _ = normalize(&path);
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn nothing_is_written_outside_the_root(paths in prop::collection::vec(hostile_path(), 1..8)) {
+            let dir = tempfile::tempdir().unwrap();
+            let (root, outside) = (dir.path().join("root"), dir.path().join("outside"));
+            std::fs::create_dir_all(&outside).unwrap();
+            let sandbox = Sandbox::open(&root).unwrap();
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            rt.block_on(async {
+                for path in &paths {
+                    // Whatever gets past `normalize` must still be held in by the handle
+                    let relative = normalize(path).unwrap_or_else(|_| PathBuf::from(path));
+                    let _ = sandbox.create(&relative, true).await;
+                    let _ = sandbox.rename(&relative, Path::new("moved")).await;
+                }
+            });
+            prop_assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+            prop_assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn accepted_links_point_below_the_root(transfers in hostile_links()) {
+            let dir = tempfile::tempdir().unwrap();
+            let root = dir.path().join("root");
+            let sandbox = Sandbox::open(&root).unwrap();
+
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            for links in &transfers {
+                // Checked the way a manifest is, one bad link refuses them all
+                let checked: Result<Vec<_>, String> = links
+                    .iter()
+                    .map(|(path, target)| {
+                        let path = normalize(path)?;
+                        check_link(&path, target)?;
+                        Ok((path, target))
+                    })
+                    .collect();
+                let Ok(checked) = checked else {
+                    continue;
+                };
+                let paths: HashSet<PathBuf> =
+                    checked.iter().map(|(path, _)| path.clone()).collect();
+                if checked.iter().any(|(path, _)| check_parents(path, &paths).is_err()) {
+                    continue;
+                }
+                rt.block_on(async {
+                    for (path, target) in &checked {
+                        let _ = sandbox.symlink(target, path, true).await;
+                    }
+                });
+            }
+
+            let root = root.canonicalize().unwrap();
+            for (link, target) in links_below(&root) {
+                let resolved = resolve(link.parent().unwrap(), &target);
+                prop_assert!(
+                    resolved.starts_with(&root),
+                    "{} points to {}",
+                    link.display(),
+                    resolved.display()
+                );
+            }
+        }
+    }
+
+    /// Two transfers of links with paths and targets made of a few names, so they end up below
+    /// each other, or links that only get out through one another
+    fn hostile_links() -> impl Strategy<Value = Vec<Vec<(String, String)>>> {
+        let component = prop_oneof![Just(".."), Just("."), Just("x"), Just("l"), Just("l2")];
+        let path = prop::collection::vec(component.clone(), 1..4);
+        let target = prop::collection::vec(component, 1..5);
+        let link = (path, target).prop_map(|(path, target)| (path.join("/"), target.join("/")));
+        let links = |list: &[(&str, &str)]| -> Vec<(String, String)> {
+            list.iter()
+                .map(|(path, target)| (path.to_string(), target.to_string()))
+                .collect()
+        };
+        let below_root = vec![links(&[("x/l", ".."), ("x/l/l2", "../../etc")])];
+        let later_below_root = vec![links(&[("x/l", "..")]), links(&[("x/l/l2", "../../etc")])];
+        let up_through = vec![links(&[("sub/x", ".."), ("a", "sub/x/..")])];
+        prop_oneof![
+            1 => Just(below_root),
+            1 => Just(later_below_root),
+            1 => Just(up_through),
+            7 => prop::collection::vec(prop::collection::vec(link, 1..6), 1..3),
+        ]
+    }
+
+    /// Where `target` leads from `dir`, following every link on the way like the kernel would.
+    /// Past a part that doesn't exist it is only text.
+    fn resolve(dir: &Path, target: &Path) -> PathBuf {
+        let mut at = dir.canonicalize().unwrap();
+        for component in target.components() {
+            match component {
+                Component::ParentDir => {
+                    at.pop();
+                }
+                Component::Normal(name) => {
+                    at.push(name);
+                    if let Ok(real) = at.canonicalize() {
+                        at = real;
+                    }
+                }
+                _ => {}
+            }
+        }
+        at
+    }
+
+    /// Every symlink below `dir` and its target, without following any of them
+    fn links_below(dir: &Path) -> Vec<(PathBuf, PathBuf)> {
+        let mut links = Vec::new();
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let kind = std::fs::symlink_metadata(&path).unwrap().file_type();
+            if kind.is_symlink() {
+                links.push((path.clone(), std::fs::read_link(&path).unwrap()));
+            } else if kind.is_dir() {
+                links.extend(links_below(&path));
+            }
+        }
+        links
+    }
+}
@@ -1,19 +1,105 @@
-mod tcp;
+mod approval;
+mod exposure;
+mod session;
+mod sessions;
+mod share;
+
+pub use approval::{Approver, TransferRequest};
 
 use std::net::SocketAddr;
-use crate::discovery::register_for_discovery;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::discovery::{register_for_discovery, FerryAnnouncement};
+use crate::identity::{self, ServerIdentity};
 use crate::utils;
 use std::path::{Path, PathBuf};
 use anyhow::Context;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use crate::server::sessions::{SessionGuard, Sessions};
+use crate::protocol::{Compression, ConflictPolicy, ErrorMessage};
+use crate::receipt::ReceiptSigner;
+use crate::transfer::{ChunkIndex, Preserve, RateLimiter};
 use crate::transport;
-use crate::transport::TransportServer;
+use crate::transport::dual::Both;
+use crate::transport::factory::TransportType;
+use crate::transport::{PendingConnection, TransportServer};
+
+/// Below the config directory, holds one chunk index per directory served
+const CHUNK_INDEX_DIR: &str = "chunks";
+/// Pause after a failed accept, so a shortage of file descriptors doesn't spin
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+/// Peers told the server is busy at the same time, any more are hung up on without a word
+const TURNING_AWAY: usize = 16;
 
 pub struct Server{
     ip: String,
     port: u16,
     name: Option<String>,
     is_tcp_mode: bool,
-    dir: PathBuf
+    dir: PathBuf,
+    options: ServerOptions,
+}
+
+/// Knobs for [`Server`] beyond where it listens
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// Peers served or shaking hands at the same time, extra ones are told the server is busy
+    pub max_connections: usize,
+    /// Clients must prove they know this code before anything else, see [`crate::generate_pairing_code`]
+    pub pairing_code: Option<String>,
+    /// Where the server's key pair is kept, the ferry config directory when `None`
+    pub identity_dir: Option<PathBuf>,
+    /// Data streams one QUIC client may have open at once, caps the client's `streams`
+    pub max_streams: u32,
+    /// Bytes per second all clients together may send, see [`crate::parse_rate`]
+    pub limit: Option<u64>,
+    /// Asked about every incoming file list before any data is accepted, `None` takes everything
+    pub approver: Option<Arc<dyn Approver>>,
+    /// Share the directory for listing and downloading instead of taking uploads
+    pub read_only: bool,
+    /// Largest transfer accepted, see [`crate::parse_size`]. Transfers that don't fit on the disk
+    /// are refused either way.
+    pub max_size: Option<u64>,
+    /// Serve on a wildcard or public address without a pairing code
+    pub confirm_public: bool,
+    /// Metadata applied to uploads and sent along with downloads
+    pub preserve: Preserve,
+    /// Copy chunks identical to ones already received from earlier uploads instead of having
+    /// them sent again
+    pub dedupe: bool,
+    /// Codec for chunks going out, and whether clients may compress what they send, `None`
+    /// turns compression off both ways
+    pub compression: Option<Compression>,
+    /// The most a client may ask to have done with files whose name is taken, clients asking
+    /// for more get this
+    pub conflict: ConflictPolicy,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self { max_connections: 16, pairing_code: None, identity_dir: None, max_streams: 32, limit: None, approver: None, read_only: false, max_size: None, confirm_public: false, preserve: Preserve::default(), dedupe: true, compression: Some(Compression::Zstd), conflict: ConflictPolicy::Overwrite }
+    }
+}
+
+/// What every session needs to know about the server it belongs to
+pub(crate) struct ServerContext {
+    name: String,
+    dir: PathBuf,
+    pairing_code: Option<String>,
+    /// Shared by every session so the limit holds for the server as a whole
+    limiter: Option<Arc<RateLimiter>>,
+    approver: Option<Arc<dyn Approver>>,
+    read_only: bool,
+    max_streams: u32,
+    max_size: Option<u64>,
+    preserve: Preserve,
+    /// Chunks of what was received into `dir` so far, shared by every session
+    index: Option<Arc<ChunkIndex>>,
+    compression: Option<Compression>,
+    conflict: ConflictPolicy,
+    /// Confirms every upload to the client once its files are in place
+    signer: ReceiptSigner,
 }
 
 impl Server{
@@ -23,15 +109,36 @@ impl Server{
             port,
             name,
             is_tcp_mode,
-            dir: dir.to_path_buf()
+            dir: dir.to_path_buf(),
+            options: ServerOptions::default(),
         }
     }
+
+    pub fn with_options(mut self, options: ServerOptions) -> Server {
+        self.options = options;
+        self
+    }
+
     pub fn serve(mut self) -> anyhow::Result<()> {
         let name: String = self.name.clone()
             .unwrap_or_else(utils::name_generator::get_random_name);
 
-        let res = register_for_discovery(&name, &self.port);
-        let _ann = res?;
+        let identity_dir = match &self.options.identity_dir {
+            Some(dir) => dir.clone(),
+            None => identity::config_dir()?,
+        };
+        let identity = ServerIdentity::load_or_create(&identity_dir)?;
+
+        let bind_address:SocketAddr = format!("{}:{}",self.ip, self.port).parse().with_context(|| format!("IP address not valid {}", self.ip))?;
+        let exposed = exposure::is_exposed(bind_address.ip());
+        exposure::check(bind_address.ip(), self.options.pairing_code.is_some(), self.options.confirm_public)?;
+
+        let transports = match self.is_tcp_mode {
+            true => vec![TransportType::Tcp],
+            false => vec![TransportType::Quic, TransportType::Tcp],
+        };
+        let res = register_for_discovery(&name, &self.port, &identity.fingerprint(), &transports);
+        let announcement = res?;
 
         self.name = Some(name.clone());
 
@@ -42,19 +149,49 @@ impl Server{
             self.dir.display(),
             name
         );
+        println!("Certificate fingerprint {}", identity.fingerprint());
+        println!("Listening over {}", transports.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(" and "));
+        if exposed {
+            println!("Reachable on:");
+            for r in exposure::reachable(bind_address.ip()) {
+                println!("  {:<12} {} ({})", r.interface, r.ip, r.reach);
+            }
+        }
 
-        let bind_address:SocketAddr = format!("{}:{}",self.ip, self.port).parse().with_context(|| format!("IP address not valid {}", self.ip))?;
-
-        let mut transport_server = match self.is_tcp_mode {
-            false => transport::factory::make_quic_server(bind_address, None)?,
-            true => anyhow::bail!("TCP Server is not supported yet")
-        };
+        let signer = ReceiptSigner::new(&name, &identity)?;
+        let ctx = Arc::new(ServerContext {
+            name,
+            dir: self.dir.clone(),
+            pairing_code: self.options.pairing_code.clone(),
+            limiter: self.options.limit.map(|limit| Arc::new(RateLimiter::new(limit))),
+            approver: self.options.approver.clone(),
+            read_only: self.options.read_only,
+            max_streams: self.options.max_streams,
+            max_size: self.options.max_size,
+            preserve: self.options.preserve,
+            index: (self.options.dedupe && !self.options.read_only)
+                .then(|| Arc::new(ChunkIndex::open(&identity_dir.join(CHUNK_INDEX_DIR), &self.dir))),
+            compression: self.options.compression,
+            conflict: self.options.conflict,
+            signer,
+        });
+        let sessions = Sessions::new(self.options.max_connections);
 
         let rt = tokio::runtime::Runtime::new()?;
         let fut: anyhow::Result<()> = rt.block_on(async {
-            transport_server.bind()?;
-            transport_server.accept().await?;
-            Ok(())
+            match self.is_tcp_mode {
+                false => {
+                    // UDP and TCP ports are separate, both listen on the same number
+                    let config = transport::factory::make_quic_server_config(&identity, self.options.max_streams)?;
+                    let quic_server = transport::factory::make_quic_server(bind_address, Some(config))?;
+                    let tcp_server = transport::factory::make_tcp_server(bind_address, &identity)?;
+                    run(Both(quic_server, tcp_server), announcement, &ctx, &sessions).await
+                }
+                true => {
+                    let transport_server = transport::factory::make_tcp_server(bind_address, &identity)?;
+                    run(transport_server, announcement, &ctx, &sessions).await
+                }
+            }
         });
         fut?;
 
@@ -62,5 +199,202 @@ impl Server{
     }
 }
 
+/// Serves on `transport_server` until Ctrl-C, then lets running sessions finish
+async fn run<S: TransportServer>(
+    mut transport_server: S,
+    announcement: FerryAnnouncement,
+    ctx: &Arc<ServerContext>,
+    sessions: &Arc<Sessions>,
+) -> anyhow::Result<()> {
+    transport_server.bind()?;
+    let tasks = accept_loop(&mut transport_server, ctx, sessions).await?;
+
+    // Stop being discoverable and reachable before waiting on whoever is still connected
+    sessions.close();
+    drop(transport_server);
+    drop(announcement);
+    drain(tasks, sessions).await;
+    Ok(())
+}
+
+/// Accepts peers and serves each of them on its own task until Ctrl-C is pressed.
+/// Returns the tasks that are still running at that point.
+async fn accept_loop<S: TransportServer>(
+    transport_server: &mut S,
+    ctx: &Arc<ServerContext>,
+    sessions: &Arc<Sessions>,
+) -> anyhow::Result<JoinSet<()>> {
+    let mut tasks = JoinSet::new();
+    let turning_away = Arc::new(Semaphore::new(TURNING_AWAY));
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            res = &mut shutdown => {
+                res.context("listen for Ctrl-C")?;
+                println!("Shutting down");
+                return Ok(tasks);
+            }
+            pending = transport_server.accept() => {
+                let pending = match pending {
+                    Ok(pending) => pending,
+                    Err(e) => {
+                        // Running out of file descriptors or a peer giving up mid-accept passes
+                        println!("Accepting a connection failed: {e:#}");
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                };
+                let remote = pending.remote_addr();
+                // The slot is taken before the handshake, so handshakes count towards the limit too
+                match sessions.try_start(remote) {
+                    Ok(session) => {
+                        tasks.spawn(serve(pending, ctx.clone(), session));
+                    }
+                    Err(reason) => {
+                        println!("Turning away {remote}: {}", reason.message);
+                        // Telling them takes a handshake as well, only so many of those at once
+                        if let Ok(permit) = turning_away.clone().try_acquire_owned() {
+                            tasks.spawn(async move {
+                                turn_away(pending, reason).await;
+                                drop(permit);
+                            });
+                        }
+                    }
+                }
+            }
+            Some(res) = tasks.join_next(), if !tasks.is_empty() => {
+                if let Err(e) = res {
+                    println!("Session task crashed: {e}");
+                }
+            }
+        }
+    }
+}
+
+/// Finishes the handshake with `pending` and serves it. A peer that takes longer than
+/// [`session::GREETING_TIMEOUT`] to get through the handshake gives its slot up.
+async fn serve<P: PendingConnection>(pending: P, ctx: Arc<ServerContext>, session: SessionGuard) {
+    let remote = session.remote;
+    let transport = match tokio::time::timeout(session::GREETING_TIMEOUT, pending.establish()).await {
+        Ok(Ok(transport)) => transport,
+        Ok(Err(e)) => {
+            println!("Handshake with {remote} failed: {e}");
+            return;
+        }
+        Err(_) => {
+            println!("Handshake with {remote} timed out");
+            return;
+        }
+    };
+    if let Err(e) = session::handle_connection(transport, &ctx, &session).await {
+        println!("[#{} {remote}] Session failed: {e:#}", session.id);
+    }
+}
+
+/// Tells `pending` why it isn't served once its handshake is done, instead of just hanging up
+async fn turn_away<P: PendingConnection>(pending: P, reason: ErrorMessage) {
+    let _ = tokio::time::timeout(session::GREETING_TIMEOUT, async {
+        if let Ok(transport) = pending.establish().await {
+            session::turn_away(transport, reason).await;
+        }
+    })
+    .await;
+}
+
+/// Lets running sessions finish, a second Ctrl-C cuts them off
+async fn drain(mut tasks: JoinSet<()>, sessions: &Sessions) {
+    if tasks.is_empty() {
+        return;
+    }
+    println!(
+        "Waiting for {} active sessions to finish, press Ctrl-C again to abort",
+        sessions.len()
+    );
+    for (id, info) in sessions.active() {
+        println!("  #{id} {} (connected {:.0?} ago)", info.remote, info.started.elapsed());
+    }
+    tokio::select! {
+        _ = async { while tasks.join_next().await.is_some() {} } => {}
+        _ = tokio::signal::ctrl_c() => {
+            let remaining = sessions.len();
+            tasks.abort_all();
+            println!("Aborted {remaining} sessions");
+        }
+    }
+}
 
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{exchange_hello, ErrorCode, Hello, Message, MessageTransport};
+    use crate::transport::memory::{self, MemoryTransport};
 
+    /// A peer on an in-memory connection, its handshake is already done
+    struct Knock(MemoryTransport);
+
+    #[async_trait::async_trait]
+    impl PendingConnection for Knock {
+        type Conn = MemoryTransport;
+
+        fn remote_addr(&self) -> SocketAddr {
+            SocketAddr::from(([127, 0, 0, 1], 1))
+        }
+
+        async fn establish(self) -> anyhow::Result<MemoryTransport> {
+            Ok(self.0)
+        }
+    }
+
+    fn context(dir: &Path) -> Arc<ServerContext> {
+        let identity = ServerIdentity::load_or_create(&dir.join("keys")).unwrap();
+        Arc::new(ServerContext {
+            name: "receiver".into(),
+            dir: dir.to_path_buf(),
+            pairing_code: None,
+            limiter: None,
+            approver: None,
+            read_only: false,
+            max_streams: 8,
+            max_size: None,
+            preserve: Preserve::default(),
+            index: None,
+            compression: None,
+            conflict: ConflictPolicy::Overwrite,
+            signer: ReceiptSigner::new("receiver", &identity).unwrap(),
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_peers_give_their_slot_up_and_latecomers_hear_why() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = context(dir.path());
+        let sessions = Sessions::new(1);
+
+        let (_silent, server) = memory::pair();
+        let knock = Knock(server);
+        let session = sessions.try_start(knock.remote_addr()).unwrap();
+        let held = tokio::spawn(serve(knock, ctx.clone(), session));
+
+        // The one slot is taken, the next peer is told so
+        let (mut late, server) = memory::pair();
+        let reason = sessions.try_start(SocketAddr::from(([127, 0, 0, 1], 2))).err().unwrap();
+        tokio::spawn(turn_away(Knock(server), reason));
+        let err = exchange_hello(&mut late, Hello::new("late")).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<ErrorMessage>().unwrap().code, ErrorCode::ServerBusy);
+
+        // Once the silent one runs out of time there's room again
+        held.await.unwrap();
+        assert_eq!(sessions.len(), 0);
+        let (mut client, server) = memory::pair();
+        let knock = Knock(server);
+        let session = sessions.try_start(knock.remote_addr()).unwrap();
+        let served = tokio::spawn(serve(knock, ctx, session));
+        exchange_hello(&mut client, Hello::new("sender")).await.unwrap();
+        client.send_message(&Message::Bye).await.unwrap();
+        client.receive_message().await.unwrap();
+        served.await.unwrap();
+    }
+}
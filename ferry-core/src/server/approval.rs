@@ -0,0 +1,45 @@
+use crate::protocol::Manifest;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Files a peer wants to send, shown to whoever decides on them before any data moves
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferRequest {
+    /// Name the peer gave in its hello
+    pub peer: String,
+    pub remote: SocketAddr,
+    /// Relative path and size of every file
+    pub files: Vec<(String, u64)>,
+}
+
+impl TransferRequest {
+    pub(crate) fn new(peer: &str, remote: SocketAddr, manifest: &Manifest) -> Self {
+        Self {
+            peer: peer.to_string(),
+            remote,
+            files: manifest
+                .files
+                .iter()
+                .map(|f| (f.path.clone(), f.size))
+                .collect(),
+        }
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|(_, size)| size).sum()
+    }
+}
+
+/// Decides whether the server takes an incoming transfer. Called on a blocking thread, so it
+/// may wait for someone at a terminal; one call at a time unless the implementation allows more.
+pub trait Approver: fmt::Debug + Send + Sync {
+    fn approve(&self, request: &TransferRequest) -> bool;
+}
+
+/// Asks `approver` without holding up the runtime
+pub(crate) async fn review(approver: Arc<dyn Approver>, request: TransferRequest) -> bool {
+    tokio::task::spawn_blocking(move || approver.approve(&request))
+        .await
+        .unwrap_or(false)
+}
@@ -0,0 +1,90 @@
+use crate::discovery::{Reach, reach};
+use std::net::IpAddr;
+
+/// An address peers can use to reach the server
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Reachable {
+    /// Interface name, empty when no local interface carries the address
+    pub(crate) interface: String,
+    pub(crate) ip: IpAddr,
+    pub(crate) reach: Reach,
+}
+
+/// Addresses a server bound to `bind` answers on. A wildcard bind covers every interface.
+pub(crate) fn reachable(bind: IpAddr) -> Vec<Reachable> {
+    let interfaces = if_addrs::get_if_addrs().unwrap_or_else(|e| {
+        log::warn!("Could not list network interfaces: {e}");
+        Vec::new()
+    });
+    if !bind.is_unspecified() {
+        let interface = interfaces
+            .iter()
+            .find(|i| i.ip() == bind)
+            .map(|i| i.name.clone())
+            .unwrap_or_default();
+        return vec![Reachable { interface, ip: bind, reach: reach(&bind) }];
+    }
+    interfaces
+        .into_iter()
+        // `::` normally accepts IPv4 as well, `0.0.0.0` never accepts IPv6
+        .filter(|i| bind.is_ipv6() || i.ip().is_ipv4())
+        .map(|i| Reachable { ip: i.ip(), reach: reach(&i.ip()), interface: i.name })
+        .collect()
+}
+
+/// Whether strangers may be able to connect to a server bound to `bind`
+pub(crate) fn is_exposed(bind: IpAddr) -> bool {
+    bind.is_unspecified() || reach(&bind) == Reach::Public
+}
+
+/// Refuses to listen on an exposed address unless a pairing code guards the server or the
+/// operator confirmed they mean it
+pub(crate) fn check(bind: IpAddr, has_pairing_code: bool, confirmed: bool) -> anyhow::Result<()> {
+    if !is_exposed(bind) || has_pairing_code || confirmed {
+        return Ok(());
+    }
+    let why = match bind.is_unspecified() {
+        true => "listens on every network interface",
+        false => "is a public address",
+    };
+    anyhow::bail!(
+        "refusing to serve on {bind} without a pairing code: it {why}. \
+         Set a pairing code with --code, or pass --confirm-public if anyone who can reach it may send files"
+    )
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn wildcard_and_public_binds_are_exposed() {
+        assert!(is_exposed(Ipv4Addr::UNSPECIFIED.into()));
+        assert!(is_exposed(Ipv6Addr::UNSPECIFIED.into()));
+        assert!(is_exposed(Ipv4Addr::new(8, 8, 8, 8).into()));
+        assert!(!is_exposed(Ipv4Addr::LOCALHOST.into()));
+        assert!(!is_exposed(Ipv4Addr::new(192, 168, 1, 20).into()));
+        assert!(!is_exposed(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1).into()));
+    }
+
+    #[test]
+    fn exposed_binds_need_a_code_or_confirmation() {
+        let wildcard: IpAddr = Ipv4Addr::UNSPECIFIED.into();
+        assert!(check(wildcard, false, false).is_err());
+        assert!(check(wildcard, true, false).is_ok());
+        assert!(check(wildcard, false, true).is_ok());
+        assert!(check(Ipv4Addr::new(192, 168, 1, 20).into(), false, false).is_ok());
+    }
+
+    #[test]
+    fn ipv4_wildcard_only_covers_ipv4_interfaces() {
+        let all = reachable(Ipv4Addr::UNSPECIFIED.into());
+        assert!(all.iter().all(|r| r.ip.is_ipv4()));
+
+        let single = reachable(Ipv4Addr::LOCALHOST.into());
+        assert_eq!(single.len(), 1);
+        assert_eq!(single[0].reach, Reach::Loopback);
+    }
+}
@@ -0,0 +1,541 @@
+use crate::chunker::Chunker;
+use crate::pairing::{self, Role};
+use crate::protocol::{
+    ErrorCode, ErrorMessage, GetRequest, Hello, Manifest, Message, MessageTransport,
+    exchange_hello, report_error,
+};
+use crate::server::approval::{self, TransferRequest};
+use crate::server::sessions::SessionGuard;
+use crate::server::{ServerContext, share};
+use crate::transfer::{self, TransferReport};
+use crate::transport::Transport;
+use crate::utils::size::format_size;
+use std::time::Duration;
+
+/// Wait after a failed pairing so codes can't be guessed at network speed
+const PAIRING_FAILURE_DELAY: Duration = Duration::from_secs(1);
+/// How long a peer has to say hello and pair, so one that stays silent doesn't keep its slot
+pub(super) const GREETING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs the conversation with a single connected peer until it says Bye
+pub(super) async fn handle_connection<T: Transport + Send>(
+    mut transport: T,
+    ctx: &ServerContext,
+    session: &SessionGuard,
+) -> anyhow::Result<()> {
+    let tag = format!("[#{} {}]", session.id, session.remote);
+    let peer = match tokio::time::timeout(GREETING_TIMEOUT, greet(&mut transport, ctx, &tag)).await
+    {
+        Ok(peer) => peer?,
+        Err(_) => {
+            let _ = transport.close().await;
+            anyhow::bail!("no hello and pairing within {GREETING_TIMEOUT:?}");
+        }
+    };
+    println!("{tag} {} connected", peer.name);
+
+    loop {
+        match transport.receive_message().await? {
+            Message::Manifest(_) if ctx.read_only => {
+                println!("{tag} Refused an upload from {}", peer.name);
+                let err = ErrorMessage::new(ErrorCode::ReadOnly, "server is read-only");
+                transport.send_message(&Message::Error(err)).await?;
+            }
+            Message::Manifest(manifest) => {
+                upload(&mut transport, ctx, session, &tag, &peer, &manifest).await?
+            }
+            Message::List(_) | Message::Get(_) if !ctx.read_only => {
+                let err = ErrorMessage::new(
+                    ErrorCode::NotShared,
+                    "server takes uploads only, it shares files when started with --read-only",
+                );
+                transport.send_message(&Message::Error(err)).await?;
+            }
+            Message::List(path) => {
+                let reply = match share::list(&ctx.dir, &path) {
+                    Ok(entries) => Message::Listing(entries),
+                    Err(err) => Message::Error(err),
+                };
+                transport.send_message(&reply).await?;
+            }
+            Message::Get(request) => download(&mut transport, ctx, &tag, &peer, request).await?,
+            Message::Bye => {
+                transport.send_message(&Message::Bye).await?;
+                break;
+            }
+            Message::Error(err) => {
+                println!("{tag} {} reported an error: {err}", peer.name);
+                break;
+            }
+            other => {
+                let err = ErrorMessage::new(
+                    ErrorCode::UnexpectedMessage,
+                    format!("{} is not expected here", other.name()),
+                );
+                transport.send_message(&Message::Error(err)).await?;
+            }
+        }
+    }
+    transport.close().await?;
+    println!("{tag} {} disconnected", peer.name);
+    Ok(())
+}
+
+/// Tells a peer we won't serve it and hangs up. Its hello is taken first, hanging up on
+/// something unread would tear the connection down before the answer gets there.
+pub(super) async fn turn_away<T: Transport + Send>(mut transport: T, reason: ErrorMessage) {
+    let _ = transport.receive_message().await;
+    let _ = transport.send_message(&Message::Error(reason)).await;
+    let _ = transport.close().await;
+}
+
+/// Exchanges hellos and pairs when the server has a code, returns the peer's hello
+async fn greet<T: Transport + Send>(
+    transport: &mut T,
+    ctx: &ServerContext,
+    tag: &str,
+) -> anyhow::Result<Hello> {
+    let hello = Hello {
+        pairing_required: ctx.pairing_code.is_some(),
+        compression: transfer::accepted(ctx.compression),
+        ..Hello::new(&ctx.name)
+    };
+    let peer = exchange_hello(transport, hello).await?;
+    if let Some(code) = &ctx.pairing_code
+        && let Err(e) = pairing::pair(transport, code, Role::Server).await
+    {
+        println!("{tag} {} failed to pair: {e}", peer.name);
+        tokio::time::sleep(PAIRING_FAILURE_DELAY).await;
+        let _ = transport.close().await;
+        return Err(e);
+    }
+    Ok(peer)
+}
+
+/// Takes the files in `manifest`, once the server's approver agrees
+async fn upload<T: Transport + Send>(
+    transport: &mut T,
+    ctx: &ServerContext,
+    session: &SessionGuard,
+    tag: &str,
+    peer: &Hello,
+    manifest: &Manifest,
+) -> anyhow::Result<()> {
+    if let Some(approver) = &ctx.approver {
+        let request = TransferRequest::new(&peer.name, session.remote, manifest);
+        if !approval::review(approver.clone(), request).await {
+            println!(
+                "{tag} Rejected {} files from {}",
+                manifest.files.len(),
+                peer.name
+            );
+            let err = ErrorMessage::new(ErrorCode::Rejected, "rejected by receiver");
+            transport.send_message(&Message::Error(err)).await?;
+            return Ok(());
+        }
+    }
+    match transfer::receive_files(
+        transport,
+        &ctx.dir,
+        manifest,
+        ctx.max_size,
+        ctx.limiter.as_deref(),
+        &ctx.preserve,
+        ctx.index.as_deref(),
+        manifest.conflict.min(ctx.conflict),
+    )
+    .await
+    {
+        Ok(report) => {
+            println!(
+                "{tag} Received {} files ({}) from {} in {:.2?}",
+                report.files,
+                amounts(&report),
+                peer.name,
+                report.elapsed
+            );
+            let renamed = report.renamed().count();
+            if report.kept() + renamed > 0 {
+                println!(
+                    "{tag} Names already taken: kept {} files as they were, renamed {renamed}",
+                    report.kept()
+                );
+            }
+            let receipt = match ctx.signer.sign(&manifest.files, &report.placements) {
+                Ok(receipt) => receipt,
+                Err(e) => {
+                    report_error(transport, &e).await;
+                    return Err(e);
+                }
+            };
+            transport.send_message(&Message::Receipt(receipt)).await
+        }
+        Err(e) => match e.downcast_ref::<ErrorMessage>() {
+            Some(err) if err.code.is_refusal() => {
+                println!("{tag} Refused files from {}: {err}", peer.name);
+                Ok(())
+            }
+            _ => {
+                report_error(transport, &e).await;
+                Err(e)
+            }
+        },
+    }
+}
+
+/// How much of a transfer went over the connection and how much didn't need to, e.g.
+/// `42.0 KiB sent, 9.1 KiB once compressed, 2.8 MiB already there (2.8 MiB deduplicated)`
+fn amounts(report: &TransferReport) -> String {
+    let mut amounts = format!("{} sent", format_size(report.bytes));
+    if report.wire < report.bytes {
+        amounts += &format!(", {} once compressed", format_size(report.wire));
+    }
+    if report.skipped > 0 {
+        amounts += &format!(", {} already there", format_size(report.skipped));
+        let mut how = Vec::new();
+        if report.deduped > 0 {
+            how.push(format!("{} deduplicated", format_size(report.deduped)));
+        }
+        if report.matched > 0 {
+            how.push(format!("{} matched by delta", format_size(report.matched)));
+        }
+        if !how.is_empty() {
+            amounts += &format!(" ({})", how.join(", "));
+        }
+    }
+    if report.holes > 0 {
+        amounts += &format!(", {} in holes", format_size(report.holes));
+    }
+    amounts
+}
+
+/// Sends what the peer asked for out of the shared tree
+async fn download<T: Transport + Send>(
+    transport: &mut T,
+    ctx: &ServerContext,
+    tag: &str,
+    peer: &Hello,
+    request: GetRequest,
+) -> anyhow::Result<()> {
+    let (root, preserve) = (ctx.dir.clone(), ctx.preserve);
+    let sources = tokio::task::spawn_blocking(move || {
+        let files = share::files_to_send(&root, &request.path)?;
+        transfer::collect_tree(files, &preserve, &Chunker::default())
+    })
+    .await?;
+    let mut sources = match sources {
+        Ok(sources) => sources,
+        Err(e) => {
+            // Nothing was sent yet, the peer can carry on
+            match e.downcast::<ErrorMessage>() {
+                Ok(err) => transport.send_message(&Message::Error(err)).await?,
+                Err(e) => report_error(transport, &e).await,
+            }
+            return Ok(());
+        }
+    };
+    sources.compression = transfer::negotiate(ctx.compression, &peer.compression);
+    let streams = request.streams.min(ctx.max_streams) as usize;
+    let limiter = ctx.limiter.clone();
+    match transfer::send_files(transport, &sources, streams, limiter).await {
+        Ok(report) => {
+            println!(
+                "{tag} Sent {} files ({}) to {} in {:.2?}",
+                report.files,
+                amounts(&report),
+                peer.name,
+                report.elapsed
+            );
+            Ok(())
+        }
+        Err(e) => {
+            report_error(transport, &e).await;
+            Err(e)
+        }
+    }
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker;
+    use crate::identity::ServerIdentity;
+    use crate::protocol::{ConflictPolicy, FileEntry, Manifest, Placement};
+    use crate::receipt::{self, ReceiptSigner};
+    use crate::server::Approver;
+    use crate::server::sessions::Sessions;
+    use crate::transfer::Filters;
+    use crate::transfer::Preserve;
+    use crate::transport::memory;
+    use std::sync::Arc;
+
+    fn signer() -> ReceiptSigner {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = ServerIdentity::load_or_create(dir.path()).unwrap();
+        ReceiptSigner::new("server", &identity).unwrap()
+    }
+
+    #[derive(Debug)]
+    struct Refuse;
+
+    impl Approver for Refuse {
+        fn approve(&self, request: &TransferRequest) -> bool {
+            assert_eq!(request.files, vec![("notes.txt".to_string(), 0)]);
+            false
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_files_are_never_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = ServerContext {
+            name: "receiver".into(),
+            dir: dir.path().to_path_buf(),
+            pairing_code: None,
+            limiter: None,
+            approver: Some(Arc::new(Refuse)),
+            read_only: false,
+            max_streams: 8,
+            max_size: None,
+            preserve: Preserve::default(),
+            index: None,
+            compression: None,
+            conflict: ConflictPolicy::Overwrite,
+            signer: signer(),
+        };
+        let sessions = Sessions::new(1);
+        let guard = sessions.try_start("127.0.0.1:1".parse().unwrap()).unwrap();
+        let (mut client, server) = memory::pair();
+        let session = tokio::spawn(async move { handle_connection(server, &ctx, &guard).await });
+
+        exchange_hello(&mut client, Hello::new("sender"))
+            .await
+            .unwrap();
+        let manifest = Manifest {
+            files: vec![FileEntry {
+                path: "notes.txt".into(),
+                size: 0,
+                chunks: vec![],
+                holes: Vec::new(),
+                meta: Default::default(),
+                hash: [0; 32],
+            }],
+            dirs: vec![],
+            links: vec![],
+            delta: false,
+            conflict: Default::default(),
+        };
+        client
+            .send_message(&Message::Manifest(manifest))
+            .await
+            .unwrap();
+        match client.receive_message().await.unwrap() {
+            Message::Error(e) => assert_eq!(e.code, ErrorCode::Rejected),
+            other => panic!("expected a rejection, got {}", other.name()),
+        }
+
+        // The session carries on after a rejection
+        client.send_message(&Message::Bye).await.unwrap();
+        assert!(matches!(
+            client.receive_message().await.unwrap(),
+            Message::Bye
+        ));
+        session.await.unwrap().unwrap();
+        assert!(!dir.path().join("notes.txt").exists());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_peers_are_hung_up_on() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = ServerContext {
+            name: "receiver".into(),
+            dir: dir.path().to_path_buf(),
+            pairing_code: Some("abrasive-bread-4821".into()),
+            limiter: None,
+            approver: None,
+            read_only: false,
+            max_streams: 8,
+            max_size: None,
+            preserve: Preserve::default(),
+            index: None,
+            compression: None,
+            conflict: ConflictPolicy::Overwrite,
+            signer: signer(),
+        };
+        let sessions = Sessions::new(1);
+        let guard = sessions.try_start("127.0.0.1:1".parse().unwrap()).unwrap();
+        let (mut client, server) = memory::pair();
+        let session = tokio::spawn(async move { handle_connection(server, &ctx, &guard).await });
+
+        // Says hello but never pairs
+        exchange_hello(&mut client, Hello::new("sender"))
+            .await
+            .unwrap();
+        let started = tokio::time::Instant::now();
+        let err = session.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("within"), "{err:#}");
+        assert!(started.elapsed() <= GREETING_TIMEOUT);
+        assert_eq!(sessions.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn uploads_are_confirmed_with_a_signed_receipt() {
+        let src = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let keys = tempfile::tempdir().unwrap();
+        let identity = ServerIdentity::load_or_create(keys.path()).unwrap();
+        let notes = src.path().join("notes.txt");
+        std::fs::write(&notes, b"remember the milk").unwrap();
+        let ctx = ServerContext {
+            name: "receiver".into(),
+            dir: dir.path().to_path_buf(),
+            pairing_code: None,
+            limiter: None,
+            approver: None,
+            read_only: false,
+            max_streams: 8,
+            max_size: None,
+            preserve: Preserve::default(),
+            index: None,
+            compression: None,
+            conflict: ConflictPolicy::Overwrite,
+            signer: ReceiptSigner::new("receiver", &identity).unwrap(),
+        };
+        let sessions = Sessions::new(1);
+        let guard = sessions.try_start("127.0.0.1:1".parse().unwrap()).unwrap();
+        let (mut client, server) = memory::pair();
+        let session = tokio::spawn(async move { handle_connection(server, &ctx, &guard).await });
+
+        exchange_hello(&mut client, Hello::new("sender"))
+            .await
+            .unwrap();
+        let sources = transfer::collect_sources(
+            &[notes],
+            &Filters::default(),
+            &Preserve::default(),
+            &Chunker::default(),
+        )
+        .unwrap();
+        transfer::send_files(&mut client, &sources, 1, None)
+            .await
+            .unwrap();
+        let receipt = match client.receive_message().await.unwrap() {
+            Message::Receipt(receipt) => receipt,
+            other => panic!("expected a receipt, got {}", other.name()),
+        };
+        assert_eq!(receipt.receiver, "receiver");
+        assert_eq!(receipt.files[0].hash, chunker::hash(b"remember the milk"));
+        receipt::verify(
+            &receipt,
+            sources.files.iter().map(|s| &s.entry),
+            &[Placement::AsSent],
+            &identity.cert,
+        )
+        .unwrap();
+
+        client.send_message(&Message::Bye).await.unwrap();
+        assert!(matches!(
+            client.receive_message().await.unwrap(),
+            Message::Bye
+        ));
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_only_servers_share_instead_of_receiving() {
+        let shared = tempfile::tempdir().unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        std::fs::create_dir(shared.path().join("docs")).unwrap();
+        std::fs::write(shared.path().join("docs/a.txt"), b"alpha").unwrap();
+        std::fs::write(shared.path().join("docs/b.txt"), vec![9u8; 300_000]).unwrap();
+        let ctx = ServerContext {
+            name: "library".into(),
+            dir: shared.path().to_path_buf(),
+            pairing_code: None,
+            limiter: None,
+            approver: None,
+            read_only: true,
+            max_streams: 8,
+            max_size: None,
+            preserve: Preserve::default(),
+            index: None,
+            compression: None,
+            conflict: ConflictPolicy::Overwrite,
+            signer: signer(),
+        };
+        let sessions = Sessions::new(1);
+        let guard = sessions.try_start("127.0.0.1:1".parse().unwrap()).unwrap();
+        let (mut client, server) = memory::pair();
+        let session = tokio::spawn(async move { handle_connection(server, &ctx, &guard).await });
+        exchange_hello(&mut client, Hello::new("reader"))
+            .await
+            .unwrap();
+
+        client
+            .send_message(&Message::Manifest(Manifest::default()))
+            .await
+            .unwrap();
+        match client.receive_message().await.unwrap() {
+            Message::Error(e) => assert_eq!(e.code, ErrorCode::ReadOnly),
+            other => panic!("expected a refusal, got {}", other.name()),
+        }
+
+        client
+            .send_message(&Message::List("docs".into()))
+            .await
+            .unwrap();
+        match client.receive_message().await.unwrap() {
+            Message::Listing(entries) => {
+                let names: Vec<_> = entries.iter().map(|e| e.name.as_str()).collect();
+                assert_eq!(names, ["a.txt", "b.txt"]);
+            }
+            other => panic!("expected a listing, got {}", other.name()),
+        }
+
+        let request = GetRequest {
+            path: "docs".into(),
+            streams: 4,
+        };
+        client.send_message(&Message::Get(request)).await.unwrap();
+        let manifest = match client.receive_message().await.unwrap() {
+            Message::Manifest(manifest) => manifest,
+            other => panic!("expected a manifest, got {}", other.name()),
+        };
+        transfer::receive_files(
+            &mut client,
+            dest.path(),
+            &manifest,
+            None,
+            None,
+            &Preserve::default(),
+            None,
+            ConflictPolicy::Overwrite,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            std::fs::read(dest.path().join("docs/b.txt")).unwrap(),
+            vec![9u8; 300_000]
+        );
+
+        client
+            .send_message(&Message::Get(GetRequest {
+                path: "../etc".into(),
+                streams: 1,
+            }))
+            .await
+            .unwrap();
+        match client.receive_message().await.unwrap() {
+            Message::Error(e) => assert_eq!(e.code, ErrorCode::NotFound),
+            other => panic!("expected an error, got {}", other.name()),
+        }
+
+        client.send_message(&Message::Bye).await.unwrap();
+        assert!(matches!(
+            client.receive_message().await.unwrap(),
+            Message::Bye
+        ));
+        session.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+use crate::protocol::{ErrorCode, ErrorMessage};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Book-keeping of the peers currently being served
+pub(crate) struct Sessions {
+    limit: usize,
+    next_id: AtomicU64,
+    closed: AtomicBool,
+    active: Mutex<HashMap<u64, SessionInfo>>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SessionInfo {
+    pub(crate) remote: SocketAddr,
+    pub(crate) started: Instant,
+}
+
+/// Keeps a session registered for as long as it is alive
+pub(crate) struct SessionGuard {
+    pub(crate) id: u64,
+    pub(crate) remote: SocketAddr,
+    sessions: Arc<Sessions>,
+}
+
+impl Sessions {
+    pub(crate) fn new(limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            next_id: AtomicU64::new(1),
+            closed: AtomicBool::new(false),
+            active: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Registers a new session unless the server is full or shutting down
+    pub(crate) fn try_start(
+        self: &Arc<Self>,
+        remote: SocketAddr,
+    ) -> Result<SessionGuard, ErrorMessage> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(ErrorMessage::new(
+                ErrorCode::ServerBusy,
+                "server is shutting down",
+            ));
+        }
+        let mut active = self.active.lock().expect("sessions lock poisoned");
+        if active.len() >= self.limit {
+            return Err(ErrorMessage::new(
+                ErrorCode::ServerBusy,
+                format!(
+                    "server is already serving {} peers, try again later",
+                    self.limit
+                ),
+            ));
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        active.insert(
+            id,
+            SessionInfo {
+                remote,
+                started: Instant::now(),
+            },
+        );
+        Ok(SessionGuard {
+            id,
+            remote,
+            sessions: self.clone(),
+        })
+    }
+
+    /// Stops new sessions from starting, the running ones are left alone
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn active(&self) -> Vec<(u64, SessionInfo)> {
+        let active = self.active.lock().expect("sessions lock poisoned");
+        let mut list: Vec<_> = active
+            .iter()
+            .map(|(id, info)| (*id, info.clone()))
+            .collect();
+        list.sort_by_key(|(id, _)| *id);
+        list
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.active.lock().expect("sessions lock poisoned").len()
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        if let Ok(mut active) = self.sessions.active.lock() {
+            active.remove(&self.id);
+        }
+    }
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn limit_is_enforced_and_freed_on_drop() {
+        let sessions = Sessions::new(2);
+        let a = sessions.try_start(addr(1)).unwrap();
+        let _b = sessions.try_start(addr(2)).unwrap();
+        let err = sessions.try_start(addr(3)).err().unwrap();
+        assert_eq!(err.code, ErrorCode::ServerBusy);
+        assert_eq!(sessions.len(), 2);
+
+        drop(a);
+        assert_eq!(sessions.len(), 1);
+        let c = sessions.try_start(addr(3)).unwrap();
+        assert_eq!(sessions.active().last().unwrap().1.remote, addr(3));
+        assert!(c.id > 2, "ids are never reused");
+    }
+
+    #[test]
+    fn closed_sessions_refuse_newcomers() {
+        let sessions = Sessions::new(8);
+        let running = sessions.try_start(addr(1)).unwrap();
+        sessions.close();
+        assert!(sessions.try_start(addr(2)).is_err());
+        assert_eq!(sessions.active()[0].0, running.id);
+    }
+}
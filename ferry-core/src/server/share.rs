@@ -0,0 +1,161 @@
+use crate::protocol::{ErrorCode, ErrorMessage, RemoteEntry};
+use crate::resume;
+use crate::transfer::{self, Filters, Tree};
+use std::path::{Path, PathBuf};
+
+// A read-only server shares everything under its directory. Clients name things with '/'
+// separated paths relative to it, which are refused when they climb out of it, whether with
+// `..` or through a symlink. Symlinks are left out of listings and downloads altogether, and so
+// are the leftovers of transfers in progress.
+
+/// Maps a client's path onto the shared tree
+pub(super) fn resolve(root: &Path, path: &str) -> Result<PathBuf, ErrorMessage> {
+    let not_found = || ErrorMessage::new(ErrorCode::NotFound, format!("{path:?} is not shared"));
+    let mut local = root.to_path_buf();
+    for part in path.split('/').filter(|p| !p.is_empty() && *p != ".") {
+        if part == ".." || part.contains('\\') {
+            return Err(not_found());
+        }
+        local.push(part);
+    }
+    let root = root.canonicalize().map_err(|_| not_found())?;
+    let local = local.canonicalize().map_err(|_| not_found())?;
+    match local.starts_with(&root) {
+        true => Ok(local),
+        false => Err(not_found()),
+    }
+}
+
+/// What is in the directory at `path`, or the file itself when it is one
+pub(super) fn list(root: &Path, path: &str) -> Result<Vec<RemoteEntry>, ErrorMessage> {
+    let local = resolve(root, path)?;
+    let meta = std::fs::metadata(&local).map_err(internal)?;
+    if meta.is_file() {
+        return Ok(vec![RemoteEntry {
+            name: name_of(&local).unwrap_or_default(),
+            size: meta.len(),
+            is_dir: false,
+        }]);
+    }
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(&local).map_err(internal)? {
+        let entry = entry.map_err(internal)?;
+        let Some(name) = shared_name(&entry.path()) else {
+            continue;
+        };
+        let meta = entry.metadata().map_err(internal)?;
+        if meta.is_symlink() {
+            continue;
+        }
+        entries.push(RemoteEntry {
+            name,
+            size: if meta.is_dir() { 0 } else { meta.len() },
+            is_dir: meta.is_dir(),
+        });
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// Every file to send for `path`, with the name it gets on the client: a file keeps its own
+/// name, a directory brings its name along as the top of the paths below it
+pub(super) fn files_to_send(root: &Path, path: &str) -> Result<Tree, ErrorMessage> {
+    let local = resolve(root, path)?;
+    let meta = std::fs::metadata(&local).map_err(internal)?;
+    let prefix = match local == root.canonicalize().map_err(internal)? {
+        true => String::new(),
+        false => name_of(&local).ok_or_else(|| {
+            ErrorMessage::new(ErrorCode::NotFound, format!("{path:?} is not shared"))
+        })?,
+    };
+    if !meta.is_dir() && !meta.is_file() {
+        return Err(ErrorMessage::new(
+            ErrorCode::NotFound,
+            format!("{path:?} is not shared"),
+        ));
+    }
+    transfer::walk(&local, &prefix, &Filters::default(), false)
+        .map_err(|e| ErrorMessage::new(ErrorCode::Internal, format!("{e:#}")))
+}
+
+/// File name as it is shown to clients, `None` for what isn't shared
+fn shared_name(path: &Path) -> Option<String> {
+    name_of(path).filter(|name| !resume::is_partial(name))
+}
+
+fn name_of(path: &Path) -> Option<String> {
+    path.file_name()?.to_str().map(str::to_string)
+}
+
+fn internal(err: std::io::Error) -> ErrorMessage {
+    ErrorMessage::new(ErrorCode::Internal, err.to_string())
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("shared");
+        std::fs::create_dir_all(root.join("photos/2024")).unwrap();
+        std::fs::write(root.join("notes.txt"), b"hello").unwrap();
+        std::fs::write(root.join("photos/a.jpg"), b"a").unwrap();
+        std::fs::write(root.join("photos/2024/b.jpg"), b"bb").unwrap();
+        std::fs::write(root.join("photos/.c.jpg.ferry-part"), b"").unwrap();
+        std::fs::write(dir.path().join("secret"), b"no").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.path().join("secret"), root.join("escape")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn paths_cannot_leave_the_shared_tree() {
+        let dir = tree();
+        let root = dir.path().join("shared");
+        assert!(resolve(&root, "photos/a.jpg").is_ok());
+        assert!(resolve(&root, "/photos/./a.jpg").is_ok());
+        for path in ["../secret", "photos/../../secret", "missing", "escape"] {
+            let err = resolve(&root, path).unwrap_err();
+            assert_eq!(err.code, ErrorCode::NotFound, "{path}");
+        }
+    }
+
+    #[test]
+    fn listings_skip_symlinks_and_partial_files() {
+        let dir = tree();
+        let root = dir.path().join("shared");
+        let names: Vec<_> = list(&root, "")
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.name, e.size, e.is_dir))
+            .collect();
+        assert_eq!(
+            names,
+            vec![("notes.txt".into(), 5, false), ("photos".into(), 0, true)]
+        );
+        let photos = list(&root, "photos").unwrap();
+        assert_eq!(photos.len(), 2);
+    }
+
+    #[test]
+    fn directories_are_sent_with_their_name() {
+        let dir = tree();
+        let root = dir.path().join("shared");
+        let names = |path| -> Vec<String> {
+            files_to_send(&root, path)
+                .unwrap()
+                .files
+                .into_iter()
+                .map(|(_, name)| name)
+                .collect()
+        };
+        assert_eq!(names("photos"), vec!["photos/2024/b.jpg", "photos/a.jpg"]);
+        assert_eq!(names("photos/a.jpg"), vec!["a.jpg"]);
+        assert_eq!(
+            names(""),
+            vec!["notes.txt", "photos/2024/b.jpg", "photos/a.jpg"]
+        );
+    }
+}
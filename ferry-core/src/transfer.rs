@@ -0,0 +1,1057 @@
+mod compress;
+mod concurrency;
+mod conflict;
+mod dedupe;
+mod delta;
+mod metadata;
+mod rate;
+mod receiver;
+mod sender;
+mod walk;
+
+pub(crate) use compress::{accepted, negotiate};
+pub(crate) use dedupe::ChunkIndex;
+pub use metadata::Preserve;
+pub(crate) use rate::RateLimiter;
+pub(crate) use receiver::receive_files;
+pub(crate) use sender::{collect_sources, collect_tree, send_files};
+pub(crate) use walk::{Filters, Tree, walk};
+
+use crate::protocol::{Placement, Receipt};
+use std::time::Duration;
+
+/// Summary of a finished transfer, from either side's point of view
+#[derive(Debug, Clone, Default)]
+pub struct TransferReport {
+    pub files: usize,
+    /// File content that went over the connection, as chunks or as literal data in deltas
+    pub bytes: u64,
+    /// What `bytes` took on the connection, less than it when chunks were compressed
+    pub wire: u64,
+    /// File content the receiver already had, from an interrupted attempt or as identical chunks
+    pub skipped: u64,
+    /// Of `skipped`, what the receiver copied from identical chunks it had elsewhere. Only the
+    /// receiver knows, it is zero for the sender.
+    pub deduped: u64,
+    /// Content a delta took from the receiver's existing copies instead of sending it
+    pub matched: u64,
+    /// Size of the files, holes included
+    pub logical: u64,
+    /// Of that, holes recreated on the receiver without sending anything
+    pub holes: u64,
+    /// Where the receiver put each file, in the order they were sent
+    pub placements: Vec<Placement>,
+    /// The server's signed confirmation of an upload, checked against its certificate
+    pub receipt: Option<Receipt>,
+    pub elapsed: Duration,
+}
+
+impl TransferReport {
+    /// Files left out because the receiver kept its own by that name
+    pub fn kept(&self) -> usize {
+        self.placements
+            .iter()
+            .filter(|p| **p == Placement::Kept)
+            .count()
+    }
+
+    /// Paths files were received under instead of their own, which was taken
+    pub fn renamed(&self) -> impl Iterator<Item = &str> {
+        self.placements.iter().filter_map(|p| match p {
+            Placement::Renamed(path) => Some(path.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Average throughput in bytes per second
+    pub fn rate(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        self.bytes as f64 / secs
+    }
+
+    /// How many times smaller compression made what was sent, 1 when it didn't help
+    pub fn compression_ratio(&self) -> f64 {
+        if self.wire == 0 {
+            return 1.0;
+        }
+        self.bytes as f64 / self.wire as f64
+    }
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::{Chunker, MIN_CHUNK_SIZE};
+    use crate::protocol::{
+        Ack, ChunkInfo, ConflictPolicy, ErrorCode, ErrorMessage, FileEntry, LinkEntry, Manifest,
+        Message, MessageTransport, decode, encode,
+    };
+    use crate::transport::Transport;
+    use crate::transport::memory::{self, MemoryTransport};
+    use std::path::{Path, PathBuf};
+
+    fn write(dir: &std::path::Path, name: &str, contents: &[u8]) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn files_arrive_intact() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let big: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        let paths = vec![
+            write(src.path(), "big.bin", &big),
+            write(src.path(), "small.txt", b"hello"),
+            write(src.path(), "empty", b""),
+        ];
+        let sources = collect_sources(
+            &paths,
+            &Filters::default(),
+            &Preserve::default(),
+            &Chunker::new(MIN_CHUNK_SIZE).unwrap(),
+        )
+        .unwrap();
+
+        let (mut client, server) = memory::pair();
+        let receiver = spawn_receiver(server, dst.path().to_path_buf());
+
+        let sent = send_files(&mut client, &sources, 1, None).await.unwrap();
+        let received = receiver.await.unwrap().unwrap();
+
+        assert_eq!(sent.files, 3);
+        assert_eq!(sent.bytes, big.len() as u64 + 5);
+        assert_eq!(received.bytes, sent.bytes);
+        assert_eq!(std::fs::read(dst.path().join("big.bin")).unwrap(), big);
+        assert_eq!(
+            std::fs::read(dst.path().join("small.txt")).unwrap(),
+            b"hello"
+        );
+        assert_eq!(std::fs::read(dst.path().join("empty")).unwrap(), b"");
+    }
+
+    #[tokio::test]
+    async fn directories_arrive_with_their_structure() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let build = src.path().join("build");
+        std::fs::create_dir_all(build.join("bin")).unwrap();
+        std::fs::create_dir_all(build.join("logs/old")).unwrap();
+        write(&build.join("bin"), "app", b"binary");
+        write(&build, "build.log", b"log");
+        write(&build, "VERSION", b"1.0");
+        let filters = Filters::new(&[], &["*.log".into()], false).unwrap();
+        let sources = collect_sources(
+            &[build],
+            &filters,
+            &Preserve::default(),
+            &Chunker::default(),
+        )
+        .unwrap();
+
+        let (mut client, server) = memory::pair();
+        let receiver = spawn_receiver(server, dst.path().to_path_buf());
+        send_files(&mut client, &sources, 1, None).await.unwrap();
+        receiver.await.unwrap().unwrap();
+
+        let out = dst.path().join("build");
+        assert_eq!(std::fs::read(out.join("bin/app")).unwrap(), b"binary");
+        assert_eq!(std::fs::read(out.join("VERSION")).unwrap(), b"1.0");
+        assert!(!out.join("build.log").exists());
+        assert!(out.join("logs/old").is_dir());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn modes_times_and_links_come_along() {
+        use std::os::unix::fs::PermissionsExt;
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let tool = src.path().join("kit/tool.sh");
+        std::fs::create_dir_all(tool.parent().unwrap()).unwrap();
+        std::fs::write(&tool, b"#!/bin/sh").unwrap();
+        std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o750)).unwrap();
+        let mtime = std::time::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        std::fs::File::options()
+            .write(true)
+            .open(&tool)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        std::os::unix::fs::symlink("tool.sh", src.path().join("kit/run")).unwrap();
+        let sources = collect_sources(
+            &[src.path().join("kit")],
+            &Filters::default(),
+            &Preserve::default(),
+            &Chunker::default(),
+        )
+        .unwrap();
+
+        let (mut client, server) = memory::pair();
+        let receiver = spawn_receiver(server, dst.path().to_path_buf());
+        send_files(&mut client, &sources, 1, None).await.unwrap();
+        receiver.await.unwrap().unwrap();
+
+        let out = dst.path().join("kit/tool.sh");
+        let meta = std::fs::metadata(&out).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o750);
+        assert_eq!(meta.modified().unwrap(), mtime);
+        let link = dst.path().join("kit/run");
+        assert_eq!(std::fs::read_link(&link).unwrap(), Path::new("tool.sh"));
+        assert_eq!(std::fs::read(&link).unwrap(), b"#!/bin/sh");
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[tokio::test]
+    async fn holes_stay_holes() {
+        use std::io::{Seek, SeekFrom, Write};
+        use std::os::unix::fs::MetadataExt;
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let path = src.path().join("disk.img");
+        let mut image = std::fs::File::create(&path).unwrap();
+        image.write_all(b"boot").unwrap();
+        image.seek(SeekFrom::Start(8 << 20)).unwrap();
+        image.write_all(b"data").unwrap();
+        image.set_len(16 << 20).unwrap();
+        drop(image);
+        let sources = collect_sources(
+            std::slice::from_ref(&path),
+            &Filters::default(),
+            &Preserve::default(),
+            &Chunker::default(),
+        )
+        .unwrap();
+        if sources.files[0].entry.holes.is_empty() {
+            // The filesystem doesn't do sparse files
+            return;
+        }
+
+        let (mut client, server) = memory::pair();
+        let receiver = spawn_receiver(server, dst.path().to_path_buf());
+        let sent = send_files(&mut client, &sources, 1, None).await.unwrap();
+        let received = receiver.await.unwrap().unwrap();
+
+        assert_eq!(sent.logical, 16 << 20);
+        assert!(sent.holes > 14 << 20, "{} bytes of holes", sent.holes);
+        assert_eq!(sent.bytes, sent.logical - sent.holes);
+        assert_eq!((received.bytes, received.holes), (sent.bytes, sent.holes));
+        let out = dst.path().join("disk.img");
+        assert_eq!(std::fs::read(&out).unwrap(), std::fs::read(&path).unwrap());
+        let allocated = std::fs::metadata(&out).unwrap().blocks() * 512;
+        assert!(allocated < 4 << 20, "{allocated} bytes allocated");
+    }
+
+    #[test]
+    fn collect_sources_rejects_duplicate_names() {
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        let paths = vec![write(a.path(), "same", b"1"), write(b.path(), "same", b"2")];
+        assert!(
+            collect_sources(
+                &paths,
+                &Filters::default(),
+                &Preserve::default(),
+                &Chunker::default()
+            )
+            .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn receiver_refuses_paths_outside_root() {
+        let dst = tempfile::tempdir().unwrap();
+        let (mut client, mut server) = memory::pair();
+        let manifest = Manifest {
+            files: vec![FileEntry {
+                path: "../escape".into(),
+                size: 0,
+                chunks: vec![],
+                holes: Vec::new(),
+                meta: Default::default(),
+                hash: [0; 32],
+            }],
+            dirs: vec![],
+            links: vec![],
+            delta: false,
+            conflict: Default::default(),
+        };
+        let err = receive_files(
+            &mut server,
+            dst.path(),
+            &manifest,
+            None,
+            None,
+            &Preserve::default(),
+            None,
+            ConflictPolicy::Overwrite,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.downcast_ref::<ErrorMessage>().is_some());
+        match client.receive_message().await.unwrap() {
+            Message::Error(e) => assert_eq!(e.code, ErrorCode::InvalidManifest),
+            other => panic!("expected error, got {}", other.name()),
+        }
+        assert!(!dst.path().parent().unwrap().join("escape").exists());
+    }
+
+    #[tokio::test]
+    async fn receiver_refuses_paths_named_twice() {
+        let file = |path: &str| FileEntry {
+            path: path.into(),
+            size: 0,
+            chunks: vec![],
+            holes: Vec::new(),
+            meta: Default::default(),
+            hash: [0; 32],
+        };
+        let link = |path: &str| LinkEntry {
+            path: path.into(),
+            target: "b".into(),
+        };
+        for (files, dirs, links) in [
+            (vec![file("a"), file("./a")], vec![], vec![]),
+            (vec![file("a")], vec!["a/".to_string()], vec![]),
+            (vec![file("a")], vec![], vec![link("a")]),
+            (vec![], vec!["a".to_string()], vec![link("./a")]),
+        ] {
+            let dst = tempfile::tempdir().unwrap();
+            let (mut client, mut server) = memory::pair();
+            let manifest = Manifest {
+                files,
+                dirs,
+                links,
+                delta: false,
+                conflict: Default::default(),
+            };
+            let err = receive_files(
+                &mut server,
+                dst.path(),
+                &manifest,
+                None,
+                None,
+                &Preserve::default(),
+                None,
+                ConflictPolicy::Overwrite,
+            )
+            .await
+            .unwrap_err();
+            assert!(err.to_string().contains("more than once"), "{err:#}");
+            match client.receive_message().await.unwrap() {
+                Message::Error(e) => assert_eq!(e.code, ErrorCode::InvalidManifest),
+                other => panic!("expected error, got {}", other.name()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_asking_for_more_than_was_sent_are_refused() {
+        let src = tempfile::tempdir().unwrap();
+        let paths = vec![write(src.path(), "a.txt", b"hello")];
+        let sources = collect_sources(
+            &paths,
+            &Filters::default(),
+            &Preserve::default(),
+            &Chunker::default(),
+        )
+        .unwrap();
+
+        for (missing, placement, why) in [
+            (vec![0..1; 2], Placement::AsSent, "twice"),
+            (vec![0..1; 1], Placement::Kept, "keeps"),
+        ] {
+            let (mut client, mut server) = memory::pair();
+            let receiver = tokio::spawn(async move {
+                server.receive_message().await.unwrap();
+                let ack = Ack::Manifest {
+                    missing: vec![missing],
+                    placements: vec![placement],
+                };
+                server.send_message(&Message::Ack(ack)).await.unwrap();
+                server
+            });
+            let err = send_files(&mut client, &sources, 1, None)
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains(why), "{err:#}");
+            receiver.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_transfers_are_refused_before_any_data() {
+        let dst = tempfile::tempdir().unwrap();
+        let (mut client, mut server) = memory::pair();
+        let manifest = Manifest {
+            files: vec![FileEntry {
+                path: "huge.bin".into(),
+                size: 5_000,
+                chunks: vec![ChunkInfo {
+                    offset: 0,
+                    len: 5_000,
+                    hash: [0; 32],
+                }],
+                holes: Vec::new(),
+                meta: Default::default(),
+                hash: [0; 32],
+            }],
+            dirs: vec![],
+            links: vec![],
+            delta: false,
+            conflict: Default::default(),
+        };
+        let err = receive_files(
+            &mut server,
+            dst.path(),
+            &manifest,
+            Some(4_000),
+            None,
+            &Preserve::default(),
+            None,
+            ConflictPolicy::Overwrite,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ErrorMessage>().unwrap().code,
+            ErrorCode::TooLarge
+        );
+        match client.receive_message().await.unwrap() {
+            Message::Error(e) => assert_eq!(e.code, ErrorCode::TooLarge),
+            other => panic!("expected error, got {}", other.name()),
+        }
+        let leftovers: Vec<_> = std::fs::read_dir(dst.path()).unwrap().collect();
+        assert!(leftovers.is_empty(), "no part files are created");
+    }
+
+    /// Flips a byte in the first `damaged` chunks that go through it
+    struct Corrupting {
+        inner: MemoryTransport,
+        damaged: usize,
+    }
+
+    #[async_trait::async_trait]
+    impl Transport for Corrupting {
+        async fn send_data(&mut self, data: &[u8]) -> anyhow::Result<()> {
+            match decode(data)? {
+                Message::Chunk(mut chunk) if self.damaged > 0 => {
+                    self.damaged -= 1;
+                    chunk.data[0] ^= 0xff;
+                    self.inner.send_data(&encode(&Message::Chunk(chunk))?).await
+                }
+                _ => self.inner.send_data(data).await,
+            }
+        }
+
+        async fn receive_data(&mut self) -> anyhow::Result<Vec<u8>> {
+            self.inner.receive_data().await
+        }
+
+        async fn close(&mut self) -> anyhow::Result<()> {
+            self.inner.close().await
+        }
+
+        fn export_keying_material(
+            &self,
+            output: &mut [u8],
+            label: &[u8],
+            context: &[u8],
+        ) -> anyhow::Result<()> {
+            self.inner.export_keying_material(output, label, context)
+        }
+
+        fn peer_certificate(&self) -> Option<rustls::pki_types::CertificateDer<'static>> {
+            self.inner.peer_certificate()
+        }
+
+        fn data_streams(&self) -> Option<std::sync::Arc<dyn crate::transport::DataStreams>> {
+            self.inner.data_streams()
+        }
+    }
+
+    async fn send_through_corruption(
+        damaged: usize,
+    ) -> (
+        anyhow::Result<TransferReport>,
+        anyhow::Result<TransferReport>,
+        tempfile::TempDir,
+    ) {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let contents: Vec<u8> = (0..20_000u32).map(|i| (i % 7) as u8).collect();
+        let paths = vec![write(src.path(), "data.bin", &contents)];
+        let sources = collect_sources(
+            &paths,
+            &Filters::default(),
+            &Preserve::default(),
+            &Chunker::new(MIN_CHUNK_SIZE).unwrap(),
+        )
+        .unwrap();
+
+        let (client, mut server) = memory::pair();
+        let mut client = Corrupting {
+            inner: client,
+            damaged,
+        };
+        let root = dst.path().to_path_buf();
+        let receiver = tokio::spawn(async move {
+            let manifest = match server.receive_message().await? {
+                Message::Manifest(m) => m,
+                other => return Err(other.unexpected("Manifest")),
+            };
+            receive_files(
+                &mut server,
+                &root,
+                &manifest,
+                None,
+                None,
+                &Preserve::default(),
+                None,
+                ConflictPolicy::Overwrite,
+            )
+            .await
+        });
+        let sent = send_files(&mut client, &sources, 1, None).await;
+        (sent, receiver.await.unwrap(), dst)
+    }
+
+    #[tokio::test]
+    async fn damaged_chunks_are_requested_again() {
+        let (sent, received, dst) = send_through_corruption(2).await;
+        let sent = sent.unwrap();
+        received.unwrap();
+        // 20_000 bytes in 4KiB chunks, two of them went twice
+        assert_eq!(sent.bytes, 20_000 + 2 * MIN_CHUNK_SIZE as u64);
+        let expected: Vec<u8> = (0..20_000u32).map(|i| (i % 7) as u8).collect();
+        assert_eq!(
+            std::fs::read(dst.path().join("data.bin")).unwrap(),
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn chunks_that_never_arrive_intact_fail_the_transfer() {
+        let (sent, received, _dst) = send_through_corruption(usize::MAX).await;
+        assert!(sent.is_err());
+        assert!(received.is_err());
+    }
+
+    #[tokio::test]
+    async fn files_that_differ_from_their_hash_never_take_their_name() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let path = write(src.path(), "data.bin", b"the chunks are fine");
+        let mut sources = collect_sources(
+            &[path],
+            &Filters::default(),
+            &Preserve::default(),
+            &Chunker::default(),
+        )
+        .unwrap();
+        sources.files[0].entry.hash = crate::chunker::hash(b"but the whole is not");
+
+        let (mut client, server) = memory::pair();
+        let receiver = spawn_receiver(server, dst.path().to_path_buf());
+        let sent = send_files(&mut client, &sources, 1, None).await;
+        let received = receiver.await.unwrap();
+
+        assert!(sent.unwrap_err().to_string().contains("does not match"));
+        assert!(received.is_err());
+        let leftovers: Vec<_> = std::fs::read_dir(dst.path()).unwrap().collect();
+        assert!(leftovers.is_empty(), "nothing is kept of a damaged file");
+    }
+
+    #[tokio::test]
+    async fn taken_names_are_kept_renamed_or_refused_as_asked() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        write(dst.path(), "a.txt", b"theirs");
+        let paths = vec![
+            write(src.path(), "a.txt", b"ours"),
+            write(src.path(), "b.txt", b"new"),
+        ];
+        let mut sources = collect_sources(
+            &paths,
+            &Filters::default(),
+            &Preserve::default(),
+            &Chunker::default(),
+        )
+        .unwrap();
+        let read = |name: &str| std::fs::read(dst.path().join(name)).unwrap();
+
+        sources.conflict = ConflictPolicy::Skip;
+        let (mut client, server) = memory::pair();
+        let receiver = spawn_receiver(server, dst.path().to_path_buf());
+        let sent = send_files(&mut client, &sources, 1, None).await.unwrap();
+        receiver.await.unwrap().unwrap();
+        assert_eq!(sent.kept(), 1);
+        assert_eq!((sent.bytes, sent.skipped), (3, 0));
+        assert_eq!(read("a.txt"), b"theirs");
+        assert_eq!(read("b.txt"), b"new");
+
+        sources.conflict = ConflictPolicy::Rename;
+        let (mut client, server) = memory::pair();
+        let receiver = spawn_receiver(server, dst.path().to_path_buf());
+        let sent = send_files(&mut client, &sources, 1, None).await.unwrap();
+        let received = receiver.await.unwrap().unwrap();
+        assert_eq!(sent.placements, received.placements);
+        assert_eq!(
+            received.renamed().collect::<Vec<_>>(),
+            ["a (1).txt", "b (1).txt"]
+        );
+        assert_eq!(read("a (1).txt"), b"ours");
+        assert_eq!(read("a.txt"), b"theirs");
+
+        sources.conflict = ConflictPolicy::Fail;
+        let (mut client, server) = memory::pair();
+        let receiver = spawn_receiver(server, dst.path().to_path_buf());
+        let err = send_files(&mut client, &sources, 1, None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ErrorMessage>().unwrap().code,
+            ErrorCode::AlreadyExists
+        );
+        assert!(receiver.await.unwrap().is_err());
+        assert_eq!(std::fs::read_dir(dst.path()).unwrap().count(), 4);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn links_leave_taken_names_alone_unless_asked() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(src.path().join("kit")).unwrap();
+        std::os::unix::fs::symlink("elsewhere", src.path().join("kit/a.txt")).unwrap();
+        std::fs::create_dir_all(dst.path().join("kit")).unwrap();
+        write(&dst.path().join("kit"), "a.txt", b"theirs");
+        let mut sources = collect_sources(
+            &[src.path().join("kit")],
+            &Filters::default(),
+            &Preserve::default(),
+            &Chunker::default(),
+        )
+        .unwrap();
+        let taken = dst.path().join("kit/a.txt");
+
+        sources.conflict = ConflictPolicy::Fail;
+        let (mut client, server) = memory::pair();
+        let receiver = spawn_receiver(server, dst.path().to_path_buf());
+        let err = send_files(&mut client, &sources, 1, None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ErrorMessage>().unwrap().code,
+            ErrorCode::AlreadyExists
+        );
+        assert!(receiver.await.unwrap().is_err());
+        assert_eq!(std::fs::read(&taken).unwrap(), b"theirs");
+
+        sources.conflict = ConflictPolicy::Skip;
+        let (mut client, server) = memory::pair();
+        let receiver = spawn_receiver(server, dst.path().to_path_buf());
+        send_files(&mut client, &sources, 1, None).await.unwrap();
+        receiver.await.unwrap().unwrap();
+        assert_eq!(std::fs::read(&taken).unwrap(), b"theirs");
+
+        sources.conflict = ConflictPolicy::Overwrite;
+        let (mut client, server) = memory::pair();
+        let receiver = spawn_receiver(server, dst.path().to_path_buf());
+        send_files(&mut client, &sources, 1, None).await.unwrap();
+        receiver.await.unwrap().unwrap();
+        assert_eq!(std::fs::read_link(&taken).unwrap(), Path::new("elsewhere"));
+    }
+
+    /// Hangs up after `budget` chunks went through, like a dropped connection
+    struct Interrupting {
+        inner: MemoryTransport,
+        budget: usize,
+    }
+
+    #[async_trait::async_trait]
+    impl Transport for Interrupting {
+        async fn send_data(&mut self, data: &[u8]) -> anyhow::Result<()> {
+            if let Message::Chunk(_) = decode(data)? {
+                if self.budget == 0 {
+                    self.inner.close().await?;
+                    anyhow::bail!("connection lost");
+                }
+                self.budget -= 1;
+            }
+            self.inner.send_data(data).await
+        }
+
+        async fn receive_data(&mut self) -> anyhow::Result<Vec<u8>> {
+            self.inner.receive_data().await
+        }
+
+        async fn close(&mut self) -> anyhow::Result<()> {
+            self.inner.close().await
+        }
+
+        fn export_keying_material(
+            &self,
+            output: &mut [u8],
+            label: &[u8],
+            context: &[u8],
+        ) -> anyhow::Result<()> {
+            self.inner.export_keying_material(output, label, context)
+        }
+
+        fn peer_certificate(&self) -> Option<rustls::pki_types::CertificateDer<'static>> {
+            self.inner.peer_certificate()
+        }
+
+        fn data_streams(&self) -> Option<std::sync::Arc<dyn crate::transport::DataStreams>> {
+            self.inner.data_streams()
+        }
+    }
+
+    fn spawn_receiver<T: Transport + Send + 'static>(
+        server: T,
+        root: PathBuf,
+    ) -> tokio::task::JoinHandle<anyhow::Result<TransferReport>> {
+        spawn_indexed_receiver(server, root, None)
+    }
+
+    fn spawn_indexed_receiver<T: Transport + Send + 'static>(
+        mut server: T,
+        root: PathBuf,
+        index: Option<std::sync::Arc<ChunkIndex>>,
+    ) -> tokio::task::JoinHandle<anyhow::Result<TransferReport>> {
+        tokio::spawn(async move {
+            let manifest = match server.receive_message().await? {
+                Message::Manifest(m) => m,
+                other => return Err(other.unexpected("Manifest")),
+            };
+            receive_files(
+                &mut server,
+                &root,
+                &manifest,
+                None,
+                None,
+                &Preserve::default(),
+                index.as_deref(),
+                manifest.conflict,
+            )
+            .await
+        })
+    }
+
+    #[tokio::test]
+    async fn changed_files_are_sent_as_a_delta() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let old: Vec<u8> = (0..2_000_000u64)
+            .map(|i| (i.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 56) as u8)
+            .collect();
+        let mut new = old.clone();
+        new.splice(1_000_000..1_000_000, *b"shifted everything after it");
+        write(dst.path(), "db.bin", &old);
+        let path = write(src.path(), "db.bin", &new);
+        let mut sources = collect_sources(
+            &[path],
+            &Filters::default(),
+            &Preserve::default(),
+            &Chunker::default(),
+        )
+        .unwrap();
+        sources.delta = true;
+
+        let (mut client, server) = memory::pair();
+        let receiver = spawn_receiver(server, dst.path().to_path_buf());
+        let sent = send_files(&mut client, &sources, 1, None).await.unwrap();
+        let received = receiver.await.unwrap().unwrap();
+
+        assert_eq!(std::fs::read(dst.path().join("db.bin")).unwrap(), new);
+        assert!(sent.bytes < 10_000, "{} bytes sent", sent.bytes);
+        assert_eq!(sent.matched + sent.bytes, new.len() as u64);
+        assert_eq!(
+            (received.bytes, received.matched),
+            (sent.bytes, sent.matched)
+        );
+    }
+
+    #[tokio::test]
+    async fn text_is_compressed_and_noise_is_not() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let log: Vec<u8> = (0..20_000)
+            .flat_map(|i| format!("{i} GET /index.html 200\n").into_bytes())
+            .collect();
+        let mut state = 1u64;
+        let noise: Vec<u8> = (0..300_000)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (state >> 33) as u8
+            })
+            .collect();
+        let paths = vec![
+            write(src.path(), "access.log", &log),
+            write(src.path(), "photo.raw", &noise),
+        ];
+        let mut sources = collect_sources(
+            &paths,
+            &Filters::default(),
+            &Preserve::default(),
+            &Chunker::new(64 * 1024).unwrap(),
+        )
+        .unwrap();
+        sources.compression = Some(crate::protocol::Compression::Zstd);
+
+        let (mut client, server) = memory::pair();
+        let receiver = spawn_receiver(server, dst.path().to_path_buf());
+        let sent = send_files(&mut client, &sources, 1, None).await.unwrap();
+        let received = receiver.await.unwrap().unwrap();
+
+        assert_eq!(std::fs::read(dst.path().join("access.log")).unwrap(), log);
+        assert_eq!(std::fs::read(dst.path().join("photo.raw")).unwrap(), noise);
+        assert_eq!(sent.bytes, (log.len() + noise.len()) as u64);
+        // The noise goes as it is, the log shrinks to a fraction
+        let log_wire = sent.wire - noise.len() as u64;
+        assert!(
+            log_wire < log.len() as u64 / 5,
+            "{log_wire} bytes for the log"
+        );
+        assert_eq!((received.bytes, received.wire), (sent.bytes, sent.wire));
+    }
+
+    #[tokio::test]
+    async fn chunks_the_receiver_has_are_copied_instead_of_sent() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let config = tempfile::tempdir().unwrap();
+        let original: Vec<u8> = (0..400_000u64)
+            .map(|i| (i.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 56) as u8)
+            .collect();
+        let mut edited = original.clone();
+        edited.splice(200_000..200_000, *b"a few new bytes");
+        let chunker = Chunker::new(MIN_CHUNK_SIZE)
+            .unwrap()
+            .with_content_defined(true);
+        let index = std::sync::Arc::new(ChunkIndex::open(config.path(), dst.path()));
+
+        let mut reports = Vec::new();
+        for (name, contents) in [("v1.bin", &original), ("v2.bin", &edited)] {
+            let path = write(src.path(), name, contents);
+            let sources =
+                collect_sources(&[path], &Filters::default(), &Preserve::default(), &chunker)
+                    .unwrap();
+            let (mut client, server) = memory::pair();
+            let receiver =
+                spawn_indexed_receiver(server, dst.path().to_path_buf(), Some(index.clone()));
+            let sent = send_files(&mut client, &sources, 1, None).await.unwrap();
+            reports.push((sent, receiver.await.unwrap().unwrap()));
+        }
+
+        let (sent, received) = &reports[1];
+        assert!(
+            received.deduped > 300_000,
+            "{} deduplicated",
+            received.deduped
+        );
+        assert_eq!(received.bytes + received.deduped, edited.len() as u64);
+        assert_eq!(sent.skipped, received.deduped);
+        assert_eq!(std::fs::read(dst.path().join("v2.bin")).unwrap(), edited);
+        // The index outlives the server
+        let reopened = ChunkIndex::open(config.path(), dst.path());
+        let sandbox = crate::sandbox::Sandbox::open(dst.path()).unwrap();
+        let layout = chunker.chunk_file(&dst.path().join("v2.bin")).unwrap();
+        assert!(reopened.read(&sandbox, &layout.chunks[0]).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn renamed_files_are_indexed_where_they_landed() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let config = tempfile::tempdir().unwrap();
+        write(dst.path(), "a.txt", b"theirs");
+        let path = write(src.path(), "a.txt", b"ours, kept under a new name");
+        let mut sources = collect_sources(
+            &[path],
+            &Filters::default(),
+            &Preserve::default(),
+            &Chunker::default(),
+        )
+        .unwrap();
+        sources.conflict = ConflictPolicy::Rename;
+        let index = std::sync::Arc::new(ChunkIndex::open(config.path(), dst.path()));
+
+        let (mut client, server) = memory::pair();
+        let receiver =
+            spawn_indexed_receiver(server, dst.path().to_path_buf(), Some(index.clone()));
+        send_files(&mut client, &sources, 1, None).await.unwrap();
+        receiver.await.unwrap().unwrap();
+
+        let sandbox = crate::sandbox::Sandbox::open(dst.path()).unwrap();
+        let chunk = &sources.files[0].entry.chunks[0];
+        assert_eq!(
+            index.read(&sandbox, chunk).await.unwrap(),
+            b"ours, kept under a new name"
+        );
+    }
+
+    #[tokio::test]
+    async fn interrupted_transfer_resumes_with_missing_chunks_only() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let contents: Vec<u8> = (0..40_000u32).map(|i| (i % 13) as u8).collect();
+        let paths = vec![write(src.path(), "data.bin", &contents)];
+        let sources = collect_sources(
+            &paths,
+            &Filters::default(),
+            &Preserve::default(),
+            &Chunker::new(MIN_CHUNK_SIZE).unwrap(),
+        )
+        .unwrap();
+
+        let (client, server) = memory::pair();
+        let receiver = spawn_receiver(server, dst.path().to_path_buf());
+        let mut client = Interrupting {
+            inner: client,
+            budget: 4,
+        };
+        assert!(send_files(&mut client, &sources, 1, None).await.is_err());
+        assert!(receiver.await.unwrap().is_err());
+        assert!(!dst.path().join("data.bin").exists());
+        assert!(dst.path().join(".data.bin.ferry-part").exists());
+        assert!(dst.path().join(".data.bin.ferry-state").exists());
+
+        let (mut client, server) = memory::pair();
+        let receiver = spawn_receiver(server, dst.path().to_path_buf());
+        let sent = send_files(&mut client, &sources, 1, None).await.unwrap();
+        let received = receiver.await.unwrap().unwrap();
+
+        let done = 4 * MIN_CHUNK_SIZE as u64;
+        assert_eq!(sent.skipped, done);
+        assert_eq!(sent.bytes, 40_000 - done);
+        assert_eq!(received.skipped, done);
+        assert_eq!(
+            std::fs::read(dst.path().join("data.bin")).unwrap(),
+            contents
+        );
+        let leftovers: Vec<_> = std::fs::read_dir(dst.path()).unwrap().collect();
+        assert_eq!(leftovers.len(), 1, "part and state files are cleaned up");
+    }
+
+    #[tokio::test]
+    async fn many_small_files_over_one_stream_dont_stall() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let paths: Vec<_> = (0..3000u32)
+            .map(|n| write(src.path(), &format!("{n}.txt"), n.to_string().as_bytes()))
+            .collect();
+        let sources = collect_sources(
+            &paths,
+            &Filters::default(),
+            &Preserve::default(),
+            &Chunker::default(),
+        )
+        .unwrap();
+
+        // Far less buffer than the acks take, so they have to be read while chunks go out
+        let (mut client, server) = memory::pair_buffered(4096);
+        let receiver = spawn_receiver(server, dst.path().to_path_buf());
+        let sent = tokio::time::timeout(
+            std::time::Duration::from_secs(30),
+            send_files(&mut client, &sources, 1, None),
+        )
+        .await
+        .expect("transfer stalled")
+        .unwrap();
+        receiver.await.unwrap().unwrap();
+
+        assert_eq!(sent.files, 3000);
+        assert_eq!(std::fs::read(dst.path().join("2999.txt")).unwrap(), b"2999");
+    }
+
+    #[tokio::test]
+    async fn limited_transfer_holds_the_rate() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let contents = vec![7u8; 200_000];
+        let paths = vec![write(src.path(), "slow.bin", &contents)];
+        let sources = collect_sources(
+            &paths,
+            &Filters::default(),
+            &Preserve::default(),
+            &Chunker::new(MIN_CHUNK_SIZE).unwrap(),
+        )
+        .unwrap();
+
+        let (mut client, server) = memory::pair();
+        let receiver = spawn_receiver(server, dst.path().to_path_buf());
+        let limiter = std::sync::Arc::new(RateLimiter::new(400_000));
+        let sent = send_files(&mut client, &sources, 1, Some(limiter))
+            .await
+            .unwrap();
+        receiver.await.unwrap().unwrap();
+
+        // 100KB burst, the rest at 400KB/s
+        assert!(sent.elapsed >= std::time::Duration::from_millis(200));
+        assert_eq!(
+            std::fs::read(dst.path().join("slow.bin")).unwrap(),
+            contents
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn chunks_spread_over_quic_streams_arrive_intact() {
+        use crate::identity::ServerIdentity;
+        use crate::transport::factory::{TransportType, connect_with};
+        use crate::transport::quic::client::QuicClient;
+        use crate::transport::quic::server::{QuicServer, make_server_config_for};
+        use crate::transport::{PendingConnection, TransportServer};
+
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let keys = tempfile::tempdir().unwrap();
+        let paths: Vec<_> = (0..5u32)
+            .map(|n| {
+                let contents: Vec<u8> = (0..50_000 * n).map(|i| (i % (n + 7)) as u8).collect();
+                write(src.path(), &format!("{n}.bin"), &contents)
+            })
+            .collect();
+        let sources = collect_sources(
+            &paths,
+            &Filters::default(),
+            &Preserve::default(),
+            &Chunker::new(MIN_CHUNK_SIZE).unwrap(),
+        )
+        .unwrap();
+
+        let identity = ServerIdentity::load_or_create(keys.path()).unwrap();
+        let config = make_server_config_for(&identity, 4).unwrap();
+        let mut server = QuicServer::new("127.0.0.1:0".parse().unwrap(), config);
+        server.bind().unwrap();
+        let addr = server.endpoint.as_ref().unwrap().local_addr().unwrap();
+        let root = dst.path().to_path_buf();
+        let receiver = tokio::spawn(async move {
+            let conn = server.accept().await?.establish().await?;
+            spawn_receiver(conn, root).await?
+        });
+
+        let quic = QuicClient::bound().unwrap();
+        let mut client = connect_with(TransportType::Quic, &quic, addr, "localhost")
+            .await
+            .unwrap();
+        assert!(client.data_streams().is_some());
+        let sent = send_files(&mut client, &sources, 4, None).await.unwrap();
+        let received = receiver.await.unwrap().unwrap();
+
+        assert_eq!(received.bytes, sent.bytes);
+        for path in &paths {
+            let name = path.file_name().unwrap();
+            assert_eq!(
+                std::fs::read(dst.path().join(name)).unwrap(),
+                std::fs::read(path).unwrap()
+            );
+        }
+    }
+}
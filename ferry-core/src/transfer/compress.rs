@@ -0,0 +1,106 @@
+use crate::protocol::Compression;
+use anyhow::ensure;
+
+/// zstd's default level, quick enough to keep a fast link busy from one core
+const ZSTD_LEVEL: i32 = 3;
+/// A compressed chunk has to save at least 1/16th of its size, otherwise (media, archives) it
+/// goes as is and the receiver has nothing to undo
+const MIN_SAVING: usize = 16;
+
+/// Codecs this build can take chunks in, preferred first
+pub(crate) fn supported() -> Vec<Compression> {
+    let lz4 = cfg!(feature = "lz4").then_some(Compression::Lz4);
+    std::iter::once(Compression::Zstd).chain(lz4).collect()
+}
+
+/// What to advertise in our hello, `preferred` first. Nothing when compression is off.
+pub(crate) fn accepted(preferred: Option<Compression>) -> Vec<Compression> {
+    let Some(preferred) = preferred else {
+        return Vec::new();
+    };
+    let mut codecs = supported();
+    codecs.sort_by_key(|codec| *codec != preferred);
+    codecs
+}
+
+/// Codec to send chunks to a peer that takes `peer` in: `preferred` if both sides have it,
+/// otherwise the peer's favourite that we have. `None` when either side turned compression off.
+pub(crate) fn negotiate(
+    preferred: Option<Compression>,
+    peer: &[Compression],
+) -> Option<Compression> {
+    let preferred = preferred?;
+    let ours = supported();
+    if ours.contains(&preferred) && peer.contains(&preferred) {
+        return Some(preferred);
+    }
+    peer.iter().copied().find(|codec| ours.contains(codec))
+}
+
+/// `data` compressed with `codec`, `None` when that doesn't make it noticeably smaller
+pub(crate) fn compress(codec: Compression, data: &[u8]) -> Option<Vec<u8>> {
+    let packed = match codec {
+        Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).ok()?,
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => lz4_flex::block::compress(data),
+        #[cfg(not(feature = "lz4"))]
+        Compression::Lz4 => return None,
+    };
+    (packed.len() < data.len() - data.len() / MIN_SAVING).then_some(packed)
+}
+
+/// Undoes [`compress`] for a chunk of `len` bytes, never producing more than that
+pub(crate) fn decompress(codec: Compression, data: &[u8], len: usize) -> anyhow::Result<Vec<u8>> {
+    let raw = match codec {
+        Compression::Zstd => zstd::bulk::decompress(data, len)?,
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => lz4_flex::block::decompress(data, len)?,
+        #[cfg(not(feature = "lz4"))]
+        Compression::Lz4 => anyhow::bail!("lz4 is not supported by this build"),
+    };
+    ensure!(
+        raw.len() == len,
+        "decompressed to {} bytes instead of {len}",
+        raw.len()
+    );
+    Ok(raw)
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_shrinks_and_noise_is_left_alone() {
+        let text = "GET /index.html 200\n".repeat(4096).into_bytes();
+        for codec in supported() {
+            let packed = compress(codec, &text).unwrap();
+            assert!(packed.len() < text.len() / 10);
+            assert_eq!(decompress(codec, &packed, text.len()).unwrap(), text);
+            // A chunk claiming to be shorter than it unpacks to is refused
+            assert!(decompress(codec, &packed, text.len() - 1).is_err());
+        }
+
+        let mut state = 1u64;
+        let noise: Vec<u8> = (0..65536)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (state >> 33) as u8
+            })
+            .collect();
+        assert_eq!(compress(Compression::Zstd, &noise), None);
+        assert_eq!(compress(Compression::Zstd, &[]), None);
+    }
+
+    #[test]
+    fn both_sides_need_compression_on() {
+        let zstd = Compression::Zstd;
+        assert_eq!(negotiate(Some(zstd), &accepted(Some(zstd))), Some(zstd));
+        assert_eq!(negotiate(Some(zstd), &accepted(None)), None);
+        assert_eq!(negotiate(None, &accepted(Some(zstd))), None);
+        // A codec the peer lacks falls back to one it has
+        assert_eq!(negotiate(Some(Compression::Lz4), &[zstd]), Some(zstd));
+        assert_eq!(accepted(Some(zstd))[0], zstd);
+    }
+}
@@ -0,0 +1,109 @@
+use crate::transport::LinkStats;
+use std::time::Duration;
+
+/// How often the controller looks at the connection
+pub(crate) const SAMPLE_EVERY: Duration = Duration::from_millis(500);
+/// Round trips this much above the quietest one seen mean queues are building up
+const QUEUEING_RTT: f64 = 2.0;
+/// Throughput below this share of the last sample counts as a drop
+const THROUGHPUT_DROP: f64 = 0.8;
+
+/// Picks how many data streams carry chunks at once. Probes one stream further while the link
+/// keeps up, steps back when throughput drops and backs off hard when round trips grow, which
+/// means the extra streams only fill buffers along the path.
+#[derive(Debug)]
+pub(crate) struct Controller {
+    limit: usize,
+    max: usize,
+    min_rtt: Option<Duration>,
+    last: Option<(LinkStats, f64)>,
+}
+
+impl Controller {
+    /// Starts halfway to `max` and never goes past it
+    pub(crate) fn new(max: usize) -> Self {
+        let max = max.max(1);
+        Self {
+            limit: max.div_ceil(2),
+            max,
+            min_rtt: None,
+            last: None,
+        }
+    }
+
+    pub(crate) fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Feeds the connection stats taken `elapsed` after the previous ones, returns the new limit
+    pub(crate) fn update(&mut self, stats: LinkStats, elapsed: Duration) -> usize {
+        let Some((previous, last_throughput)) = self.last else {
+            self.last = Some((stats, 0.0));
+            return self.limit;
+        };
+        let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+        let throughput = stats.bytes_sent.saturating_sub(previous.bytes_sent) as f64 / secs;
+        self.last = Some((stats, throughput));
+
+        let min_rtt = match self.min_rtt {
+            Some(min) if min <= stats.rtt => min,
+            _ => stats.rtt,
+        };
+        self.min_rtt = Some(min_rtt);
+
+        if stats.rtt.as_secs_f64() > min_rtt.as_secs_f64() * QUEUEING_RTT {
+            self.limit = (self.limit * 3 / 4).max(1);
+        } else if throughput < last_throughput * THROUGHPUT_DROP {
+            self.limit = (self.limit - 1).max(1);
+        } else if self.limit < self.max {
+            self.limit += 1;
+        }
+        self.limit
+    }
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(rtt_ms: u64, bytes_sent: u64) -> LinkStats {
+        LinkStats {
+            rtt: Duration::from_millis(rtt_ms),
+            bytes_sent,
+        }
+    }
+
+    #[test]
+    fn grows_while_the_link_keeps_up() {
+        let mut controller = Controller::new(8);
+        assert_eq!(controller.limit(), 4);
+        let mut sent = 0;
+        for _ in 0..10 {
+            sent += 1_000_000;
+            controller.update(stats(20, sent), SAMPLE_EVERY);
+        }
+        assert_eq!(controller.limit(), 8, "capped at the maximum");
+    }
+
+    #[test]
+    fn backs_off_when_round_trips_grow() {
+        let mut controller = Controller::new(16);
+        controller.update(stats(20, 0), SAMPLE_EVERY);
+        assert_eq!(controller.update(stats(20, 1_000_000), SAMPLE_EVERY), 9);
+        assert_eq!(controller.update(stats(80, 2_000_000), SAMPLE_EVERY), 6);
+        assert_eq!(controller.update(stats(90, 3_000_000), SAMPLE_EVERY), 4);
+        assert_eq!(controller.update(stats(25, 4_000_000), SAMPLE_EVERY), 5);
+    }
+
+    #[test]
+    fn steps_back_when_throughput_drops() {
+        let mut controller = Controller::new(4);
+        controller.update(stats(20, 0), SAMPLE_EVERY);
+        controller.update(stats(20, 1_000_000), SAMPLE_EVERY);
+        assert_eq!(controller.limit(), 3);
+        assert_eq!(controller.update(stats(20, 1_500_000), SAMPLE_EVERY), 2);
+        assert_eq!(controller.update(stats(20, 1_500_001), SAMPLE_EVERY), 1);
+        assert_eq!(controller.update(stats(20, 1_500_001), SAMPLE_EVERY), 1);
+    }
+}
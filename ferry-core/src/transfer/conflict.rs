@@ -0,0 +1,351 @@
+use crate::protocol::{ConflictPolicy, ErrorCode, ErrorMessage, FileEntry, Manifest, Placement};
+use crate::sandbox::Sandbox;
+use crate::transfer::metadata;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Where one incoming file goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Placed {
+    pub(crate) placement: Placement,
+    /// The file's own path, or the free one it was renamed to
+    pub(crate) target: PathBuf,
+    /// Whatever is at `target` by the time the file is complete may be replaced
+    pub(crate) replace: bool,
+}
+
+impl Placed {
+    /// Where `entry` ends up, '/' separated like its own path
+    pub(crate) fn path<'a>(&'a self, entry: &'a FileEntry) -> &'a str {
+        match &self.placement {
+            Placement::Renamed(path) => path,
+            Placement::AsSent | Placement::Kept => &entry.path,
+        }
+    }
+}
+
+/// Decides where each file and link of `manifest` goes under `sandbox` when its own path (in
+/// `targets` and `links`) is taken, as `policy` says. Renamed ones never take a path the transfer
+/// itself uses, `dirs` being its directories. With [`ConflictPolicy::Fail`] a single taken name
+/// refuses the transfer, and so does replacing a directory or anything else that isn't a file
+/// or a link, which would only fail once all the data is in. Links carry no modification time,
+/// so with [`ConflictPolicy::NewerWins`] what is there stays.
+pub(crate) async fn place(
+    sandbox: &Sandbox,
+    manifest: &Manifest,
+    targets: &[PathBuf],
+    links: &[PathBuf],
+    dirs: &[PathBuf],
+    policy: ConflictPolicy,
+) -> Result<(Vec<Placed>, Vec<Placed>), ErrorMessage> {
+    let mut placer = Placer {
+        sandbox,
+        policy,
+        used: targets.iter().chain(links).chain(dirs).cloned().collect(),
+        taken: Vec::new(),
+        not_files: Vec::new(),
+    };
+    let mut files = Vec::with_capacity(manifest.files.len());
+    for (entry, target) in manifest.files.iter().zip(targets) {
+        files.push(placer.place(&entry.path, entry.meta.mtime, target).await);
+    }
+    let mut placed_links = Vec::with_capacity(manifest.links.len());
+    for (entry, target) in manifest.links.iter().zip(links) {
+        placed_links.push(placer.place(&entry.path, None, target).await);
+    }
+    if let Some(path) = placer.not_files.first() {
+        return Err(ErrorMessage::new(
+            ErrorCode::NotAFile,
+            format!("{path} is a directory or another thing that isn't a file"),
+        ));
+    }
+    match placer.taken.as_slice() {
+        [] => Ok((files, placed_links)),
+        [path] => Err(ErrorMessage::new(
+            ErrorCode::AlreadyExists,
+            format!("{path} already exists"),
+        )),
+        [path, rest @ ..] => Err(ErrorMessage::new(
+            ErrorCode::AlreadyExists,
+            format!("{path} and {} more already exist", rest.len()),
+        )),
+    }
+}
+
+/// What [`place`] keeps track of from one entry to the next
+struct Placer<'a> {
+    sandbox: &'a Sandbox,
+    policy: ConflictPolicy,
+    /// Paths renamed entries must stay away from
+    used: HashSet<PathBuf>,
+    /// Entries whose name is taken, with [`ConflictPolicy::Fail`]
+    taken: Vec<String>,
+    /// Entries that would replace a directory or the like
+    not_files: Vec<String>,
+}
+
+impl Placer<'_> {
+    /// Where the entry at manifest `path`, last modified at `mtime`, goes instead of `target`
+    async fn place(&mut self, path: &str, mtime: Option<(i64, u32)>, target: &Path) -> Placed {
+        let as_sent = |replace| Placed {
+            placement: Placement::AsSent,
+            target: target.to_path_buf(),
+            replace,
+        };
+        let Ok(existing) = self.sandbox.metadata(target).await else {
+            return as_sent(false);
+        };
+        let file_type = existing.file_type();
+        if !file_type.is_file()
+            && !file_type.is_symlink()
+            && replaces(self.policy, mtime, &existing)
+        {
+            self.not_files.push(path.to_string());
+        }
+        match self.policy {
+            ConflictPolicy::Overwrite => as_sent(true),
+            ConflictPolicy::NewerWins if is_newer(mtime, &existing) => as_sent(true),
+            ConflictPolicy::NewerWins | ConflictPolicy::Skip => Placed {
+                placement: Placement::Kept,
+                target: target.to_path_buf(),
+                replace: false,
+            },
+            ConflictPolicy::Rename => {
+                let (path, target) = free_name(self.sandbox, path, target, &self.used).await;
+                self.used.insert(target.clone());
+                Placed {
+                    placement: Placement::Renamed(path),
+                    target,
+                    replace: false,
+                }
+            }
+            ConflictPolicy::Fail => {
+                self.taken.push(path.to_string());
+                as_sent(false)
+            }
+        }
+    }
+}
+
+/// `policy` would have the sent entry take the place of what is there
+fn replaces(
+    policy: ConflictPolicy,
+    mtime: Option<(i64, u32)>,
+    existing: &cap_std::fs::Metadata,
+) -> bool {
+    match policy {
+        ConflictPolicy::Overwrite => true,
+        ConflictPolicy::NewerWins => is_newer(mtime, existing),
+        ConflictPolicy::Fail | ConflictPolicy::Skip | ConflictPolicy::Rename => false,
+    }
+}
+
+/// The sent entry, modified at `mtime`, is newer than what is there. Without a modification
+/// time to go by it isn't.
+fn is_newer(mtime: Option<(i64, u32)>, existing: &cap_std::fs::Metadata) -> bool {
+    let sent = mtime.and_then(metadata::from_unix);
+    let existing = existing.modified().ok().map(|time| time.into_std());
+    matches!((sent, existing), (Some(sent), Some(existing)) if sent > existing)
+}
+
+/// The first of `report (1).pdf`, `report (2).pdf`, ... next to `target` that is neither on disk
+/// nor `used`, as a manifest path and a target
+async fn free_name(
+    sandbox: &Sandbox,
+    path: &str,
+    target: &Path,
+    used: &HashSet<PathBuf>,
+) -> (String, PathBuf) {
+    let (dir, name) = match path.rsplit_once('/') {
+        Some((dir, name)) => (Some(dir), name),
+        None => (None, path),
+    };
+    for n in 1.. {
+        let numbered = numbered(name, n);
+        let candidate = target.with_file_name(&numbered);
+        if !used.contains(&candidate) && sandbox.metadata(&candidate).await.is_err() {
+            let path = match dir {
+                Some(dir) => format!("{dir}/{numbered}"),
+                None => numbered,
+            };
+            return (path, candidate);
+        }
+    }
+    unreachable!("some number is always free")
+}
+
+/// `report (n).pdf` for `report.pdf`, the number goes before the extension
+fn numbered(name: &str, n: u32) -> String {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 => format!("{} ({n}){}", &name[..dot], &name[dot..]),
+        _ => format!("{name} ({n})"),
+    }
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{FileMeta, LinkEntry};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn entry(path: &str, mtime: Option<(i64, u32)>) -> FileEntry {
+        FileEntry {
+            path: path.into(),
+            size: 0,
+            chunks: Vec::new(),
+            holes: Vec::new(),
+            meta: FileMeta {
+                mtime,
+                ..Default::default()
+            },
+            hash: [0; 32],
+        }
+    }
+
+    fn manifest(files: Vec<FileEntry>, links: &[&str]) -> Manifest {
+        Manifest {
+            files,
+            dirs: Vec::new(),
+            links: links
+                .iter()
+                .map(|path| LinkEntry {
+                    path: path.to_string(),
+                    target: "elsewhere".into(),
+                })
+                .collect(),
+            delta: false,
+            conflict: ConflictPolicy::default(),
+        }
+    }
+
+    #[test]
+    fn numbers_go_before_the_extension() {
+        assert_eq!(numbered("report.pdf", 1), "report (1).pdf");
+        assert_eq!(numbered("archive.tar.gz", 2), "archive.tar (2).gz");
+        assert_eq!(numbered(".bashrc", 1), ".bashrc (1)");
+        assert_eq!(numbered("README", 3), "README (3)");
+    }
+
+    #[tokio::test]
+    async fn each_policy_places_taken_names_its_own_way() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("docs")).unwrap();
+        std::fs::write(dir.path().join("docs/a.txt"), b"theirs").unwrap();
+        std::fs::write(dir.path().join("docs/a (1).txt"), b"also theirs").unwrap();
+        let old = SystemTime::now() - Duration::from_secs(3600);
+        std::fs::File::options()
+            .write(true)
+            .open(dir.path().join("docs/a.txt"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        let sandbox = Sandbox::open(dir.path()).unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let files = manifest(
+            vec![
+                entry("docs/a.txt", Some((now.as_secs() as i64, 0))),
+                entry("docs/b.txt", None),
+            ],
+            &[],
+        );
+        let targets = [PathBuf::from("docs/a.txt"), PathBuf::from("docs/b.txt")];
+        // The transfer itself creates `a (2).txt`, so renaming has to go further
+        let dirs = [PathBuf::from("docs/a (2).txt")];
+        let with = async |policy| {
+            place(&sandbox, &files, &targets, &[], &dirs, policy)
+                .await
+                .map(|(files, _)| files)
+        };
+
+        let placements = |placed: Vec<Placed>| -> Vec<Placement> {
+            placed.into_iter().map(|p| p.placement).collect()
+        };
+        let overwrite = with(ConflictPolicy::Overwrite).await.unwrap();
+        assert!(overwrite[0].replace && !overwrite[1].replace);
+        assert_eq!(
+            placements(with(ConflictPolicy::Skip).await.unwrap()),
+            [Placement::Kept, Placement::AsSent]
+        );
+        let renamed = with(ConflictPolicy::Rename).await.unwrap();
+        assert_eq!(renamed[0].target, Path::new("docs/a (3).txt"));
+        assert_eq!(
+            placements(renamed),
+            [
+                Placement::Renamed("docs/a (3).txt".into()),
+                Placement::AsSent
+            ]
+        );
+        assert_eq!(
+            placements(with(ConflictPolicy::NewerWins).await.unwrap()),
+            [Placement::AsSent, Placement::AsSent]
+        );
+        let err = with(ConflictPolicy::Fail).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::AlreadyExists);
+        assert!(err.message.contains("docs/a.txt"));
+
+        // Without a time to compare, what is there stays
+        let files = manifest(vec![entry("docs/a.txt", None)], &[]);
+        let (placed, _) = place(
+            &sandbox,
+            &files,
+            &targets[..1],
+            &[],
+            &[],
+            ConflictPolicy::NewerWins,
+        )
+        .await
+        .unwrap();
+        assert_eq!(placed[0].placement, Placement::Kept);
+
+        // A directory can't be replaced by a file, better to say so before any data moves
+        let files = manifest(vec![entry("docs", None)], &[]);
+        let targets = [PathBuf::from("docs")];
+        let err = place(
+            &sandbox,
+            &files,
+            &targets,
+            &[],
+            &[],
+            ConflictPolicy::Overwrite,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code, ErrorCode::NotAFile);
+        let (placed, _) = place(&sandbox, &files, &targets, &[], &[], ConflictPolicy::Skip)
+            .await
+            .unwrap();
+        assert_eq!(placed[0].placement, Placement::Kept);
+    }
+
+    #[tokio::test]
+    async fn links_follow_the_policy_too() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), b"theirs").unwrap();
+        let sandbox = Sandbox::open(dir.path()).unwrap();
+        let links = manifest(Vec::new(), &["a.txt"]);
+        let targets = [PathBuf::from("a.txt")];
+        let with = async |policy| {
+            place(&sandbox, &links, &[], &targets, &[], policy)
+                .await
+                .map(|(_, links)| links)
+        };
+
+        let err = with(ConflictPolicy::Fail).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::AlreadyExists);
+        assert_eq!(
+            with(ConflictPolicy::Skip).await.unwrap()[0].placement,
+            Placement::Kept
+        );
+        // Links have no time to be newer with
+        assert_eq!(
+            with(ConflictPolicy::NewerWins).await.unwrap()[0].placement,
+            Placement::Kept
+        );
+        assert_eq!(
+            with(ConflictPolicy::Rename).await.unwrap()[0].placement,
+            Placement::Renamed("a (1).txt".into())
+        );
+        assert!(with(ConflictPolicy::Overwrite).await.unwrap()[0].replace);
+    }
+}
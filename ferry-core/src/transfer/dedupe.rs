@@ -0,0 +1,157 @@
+use crate::chunker;
+use crate::protocol::{ChunkInfo, Hash};
+use crate::sandbox::{self, Sandbox};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::Mutex;
+
+/// Bumped when the index file changes shape, older ones are started over
+const INDEX_FORMAT: u16 = 1;
+/// Beyond this many chunks new files are no longer indexed, an index entry costs about 100 bytes
+const MAX_ENTRIES: usize = 1 << 20;
+
+/// Where chunks of the files received into a directory are, keyed by hash, so identical chunks
+/// are copied locally instead of sent again. Entries are hints only: a chunk is used once its
+/// hash checks out, and forgotten when it doesn't.
+pub(crate) struct ChunkIndex {
+    /// Kept outside the directory it describes, which may be shared read-only later
+    file: PathBuf,
+    chunks: Mutex<HashMap<Hash, Location>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Location {
+    /// '/' separated path relative to the directory, like [`crate::protocol::FileEntry::path`]
+    path: String,
+    offset: u64,
+    len: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    format: u16,
+    chunks: HashMap<Hash, Location>,
+}
+
+impl ChunkIndex {
+    /// The index of `root` kept in `dir`, empty when there is none yet or it can't be read
+    pub(crate) fn open(dir: &Path, root: &Path) -> Self {
+        let root = std::fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+        let id = blake3::hash(root.as_os_str().as_encoded_bytes());
+        let file = dir.join(format!("{}.index", &id.to_hex()[..32]));
+        let chunks = match std::fs::read(&file) {
+            Ok(bytes) => match bincode::deserialize::<Stored>(&bytes) {
+                Ok(stored) if stored.format == INDEX_FORMAT => stored.chunks,
+                Ok(_) => HashMap::new(),
+                Err(e) => {
+                    log::warn!("ignoring damaged chunk index {}: {e}", file.display());
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+        Self {
+            file,
+            chunks: Mutex::new(chunks),
+        }
+    }
+
+    /// Reads a chunk matching `info` from wherever under `sandbox` one was last seen
+    pub(crate) async fn read(&self, sandbox: &Sandbox, info: &ChunkInfo) -> Option<Vec<u8>> {
+        let location = self.chunks.lock().await.get(&info.hash).cloned()?;
+        match read_at(sandbox, &location).await {
+            Ok(data) if chunker::verify(info, &data) => Some(data),
+            _ => {
+                // Changed or gone since, don't look there again
+                let mut chunks = self.chunks.lock().await;
+                if chunks.get(&info.hash) == Some(&location) {
+                    chunks.remove(&info.hash);
+                }
+                None
+            }
+        }
+    }
+
+    /// Remembers the chunks of a file that now sits at `path`
+    pub(crate) async fn add(&self, path: &str, chunks: &[ChunkInfo]) {
+        let mut index = self.chunks.lock().await;
+        for chunk in chunks {
+            if index.len() >= MAX_ENTRIES && !index.contains_key(&chunk.hash) {
+                break;
+            }
+            index.insert(
+                chunk.hash,
+                Location {
+                    path: path.to_string(),
+                    offset: chunk.offset,
+                    len: chunk.len,
+                },
+            );
+        }
+    }
+
+    /// Writes the index out, replacing the previous one in one go
+    pub(crate) async fn save(&self) -> anyhow::Result<()> {
+        // Held until the file is in place, so concurrent saves don't trip over each other
+        let chunks = self.chunks.lock().await;
+        let stored = Stored {
+            format: INDEX_FORMAT,
+            chunks: chunks.clone(),
+        };
+        let bytes = bincode::serialize(&stored)?;
+        if let Some(dir) = self.file.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let tmp = self.file.with_extension("index.tmp");
+        tokio::fs::write(&tmp, bytes)
+            .await
+            .with_context(|| format!("write {}", tmp.display()))?;
+        tokio::fs::rename(&tmp, &self.file).await?;
+        Ok(())
+    }
+}
+
+async fn read_at(sandbox: &Sandbox, location: &Location) -> anyhow::Result<Vec<u8>> {
+    let path = sandbox::normalize(&location.path).map_err(anyhow::Error::msg)?;
+    let mut file = sandbox.open_file(&path).await?;
+    file.seek(SeekFrom::Start(location.offset)).await?;
+    let mut data = vec![0u8; location.len as usize];
+    file.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn chunks_are_found_until_they_change() {
+        let root = tempfile::tempdir().unwrap();
+        let config = tempfile::tempdir().unwrap();
+        let sandbox = Sandbox::open(root.path()).unwrap();
+        std::fs::create_dir(root.path().join("docs")).unwrap();
+        std::fs::write(root.path().join("docs/a.txt"), b"hello world").unwrap();
+        let info = ChunkInfo {
+            offset: 6,
+            len: 5,
+            hash: chunker::hash(b"world"),
+        };
+
+        let index = ChunkIndex::open(config.path(), root.path());
+        assert_eq!(index.read(&sandbox, &info).await, None);
+        index.add("docs/a.txt", std::slice::from_ref(&info)).await;
+        index.save().await.unwrap();
+
+        let index = ChunkIndex::open(config.path(), root.path());
+        assert_eq!(index.read(&sandbox, &info).await.unwrap(), b"world");
+
+        std::fs::write(root.path().join("docs/a.txt"), b"hello there").unwrap();
+        assert_eq!(index.read(&sandbox, &info).await, None);
+        assert!(index.chunks.lock().await.is_empty());
+    }
+}
@@ -0,0 +1,389 @@
+// rsync style delta transfer. The receiver sends a `Signature` of its copy of a file: a weak
+// rolling checksum and a strong hash per block. The sender slides a window over its own file, and
+// wherever both checksums match a block it sends a reference to that block instead of the data.
+
+use crate::protocol::{BlockSignature, Delta, DeltaOp, Message, MessageTransport, Signature};
+use crate::transfer::RateLimiter;
+use anyhow::{Context, bail};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::Path;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+const MIN_BLOCK: u64 = 1024;
+const MAX_BLOCK: u64 = 128 * 1024;
+/// Keeps a signature well below the frame limit, larger files get larger blocks
+const MAX_BLOCKS: u64 = 1 << 20;
+/// Literal data gathered before it goes out, roughly what one `Delta` carries at most
+const MAX_LITERAL: usize = 1024 * 1024;
+const MAX_OPS: usize = 8192;
+/// How much of the sender's file is read at once
+const READ_SIZE: usize = 256 * 1024;
+
+/// Block size for a file of `size` bytes, about its square root like rsync.
+/// `None` when the file is too small to bother or too large for a signature.
+fn block_size(size: u64) -> Option<u32> {
+    if size < MIN_BLOCK {
+        return None;
+    }
+    let block = size
+        .isqrt()
+        .clamp(MIN_BLOCK, MAX_BLOCK)
+        .max(size.div_ceil(MAX_BLOCKS));
+    u32::try_from(block).ok()
+}
+
+/// rsync's rolling checksum: the byte sum and the position weighted byte sum, 16 bits each
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Self {
+        let len = window.len() as u32;
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, &byte) in window.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(byte as u32));
+        }
+        Self { a, b, len }
+    }
+
+    /// Moves the window one byte on, dropping `out` and taking in `next`
+    fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(next as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.b << 16) | (self.a & 0xffff)
+    }
+}
+
+fn strong(block: &[u8]) -> [u8; 16] {
+    let hash = blake3::hash(block);
+    let mut strong = [0; 16];
+    strong.copy_from_slice(&hash.as_bytes()[..16]);
+    strong
+}
+
+/// Signature of `copy` for the file at `index` in the manifest, `None` if it isn't worth one
+pub(crate) async fn signature(
+    copy: &mut tokio::fs::File,
+    index: u32,
+) -> anyhow::Result<Option<Signature>> {
+    let size = copy.metadata().await?.len();
+    let Some(block_size) = block_size(size) else {
+        return Ok(None);
+    };
+    copy.seek(SeekFrom::Start(0)).await?;
+    let mut blocks = Vec::with_capacity((size / block_size as u64) as usize);
+    let mut block = vec![0u8; block_size as usize];
+    for _ in 0..size / block_size as u64 {
+        copy.read_exact(&mut block).await?;
+        blocks.push(BlockSignature {
+            weak: Rolling::new(&block).digest(),
+            strong: strong(&block),
+        });
+    }
+    Ok(Some(Signature {
+        file: index,
+        block_size,
+        blocks,
+    }))
+}
+
+/// What a delta consisted of
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct DeltaStats {
+    /// Sent as is
+    pub(crate) literal: u64,
+    /// Taken from the receiver's copy
+    pub(crate) matched: u64,
+}
+
+/// Collects ops into `Delta` messages of a sensible size
+struct Outbox<'a, T: ?Sized> {
+    transport: &'a mut T,
+    limiter: Option<&'a RateLimiter>,
+    file: u32,
+    ops: Vec<DeltaOp>,
+    literal: usize,
+    stats: DeltaStats,
+}
+
+impl<T: MessageTransport + Send + ?Sized> Outbox<'_, T> {
+    async fn literal(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.ops.push(DeltaOp::Literal(data.to_vec()));
+        self.literal += data.len();
+        self.stats.literal += data.len() as u64;
+        self.flush_if_full().await
+    }
+
+    async fn copy(&mut self, block: u32, block_size: u32) -> anyhow::Result<()> {
+        self.stats.matched += block_size as u64;
+        match self.ops.last_mut() {
+            Some(DeltaOp::Copy { first, count }) if *first + *count == block => *count += 1,
+            _ => self.ops.push(DeltaOp::Copy {
+                first: block,
+                count: 1,
+            }),
+        }
+        self.flush_if_full().await
+    }
+
+    async fn flush_if_full(&mut self) -> anyhow::Result<()> {
+        if self.literal >= MAX_LITERAL || self.ops.len() >= MAX_OPS {
+            self.send(false).await?;
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, done: bool) -> anyhow::Result<()> {
+        if let Some(limiter) = self.limiter {
+            limiter.acquire(self.literal as u64).await;
+        }
+        let delta = Delta {
+            file: self.file,
+            ops: std::mem::take(&mut self.ops),
+            done,
+        };
+        self.literal = 0;
+        self.transport.send_message(&Message::Delta(delta)).await
+    }
+}
+
+/// Streams the delta of the local file at `path` against `signature` to the receiver
+pub(crate) async fn send_delta<T>(
+    transport: &mut T,
+    path: &Path,
+    signature: &Signature,
+    limiter: Option<&RateLimiter>,
+) -> anyhow::Result<DeltaStats>
+where
+    T: MessageTransport + Send + ?Sized,
+{
+    let block_size = signature.block_size as usize;
+    if block_size == 0 {
+        bail!("receiver sent a signature with empty blocks");
+    }
+    let mut blocks: HashMap<u32, Vec<(u32, [u8; 16])>> = HashMap::new();
+    for (index, block) in signature.blocks.iter().enumerate() {
+        blocks
+            .entry(block.weak)
+            .or_default()
+            .push((index as u32, block.strong));
+    }
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("open {}", path.display()))?;
+    let mut out = Outbox {
+        transport,
+        limiter,
+        file: signature.file,
+        ops: Vec::new(),
+        literal: 0,
+        stats: DeltaStats::default(),
+    };
+
+    // `buf[head..pos]` is literal data not sent yet, the window starts at `pos`
+    let mut buf = Vec::new();
+    let (mut head, mut pos) = (0, 0);
+    let mut rolling: Option<Rolling> = None;
+    let mut eof = false;
+    loop {
+        while buf.len() < pos + block_size + 1 && !eof {
+            let start = buf.len();
+            buf.resize(start + READ_SIZE, 0);
+            let n = file.read(&mut buf[start..]).await?;
+            buf.truncate(start + n);
+            eof = n == 0;
+        }
+        if buf.len() < pos + block_size {
+            break;
+        }
+        let window = &buf[pos..pos + block_size];
+        let weak = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+        let matched = blocks.get(&weak).and_then(|candidates| {
+            let strong = strong(window);
+            candidates
+                .iter()
+                .find(|(_, s)| *s == strong)
+                .map(|(index, _)| *index)
+        });
+        match matched {
+            Some(block) => {
+                out.literal(&buf[head..pos]).await?;
+                out.copy(block, signature.block_size).await?;
+                pos += block_size;
+                head = pos;
+                rolling = None;
+            }
+            None => {
+                match (&mut rolling, buf.get(pos + block_size)) {
+                    (Some(rolling), Some(&next)) => rolling.roll(buf[pos], next),
+                    _ => rolling = None,
+                }
+                pos += 1;
+                if pos - head >= MAX_LITERAL {
+                    out.literal(&buf[head..pos]).await?;
+                    head = pos;
+                }
+            }
+        }
+        // Forget what has been sent
+        if head >= READ_SIZE {
+            buf.drain(..head);
+            pos -= head;
+            head = 0;
+        }
+    }
+    out.literal(&buf[head..]).await?;
+    out.send(true).await?;
+    Ok(out.stats)
+}
+
+/// Rebuilds a file into `part` from `Delta`s against `copy`, whose signature was sent with
+/// `block_size`. Writing stops at `size`, anything beyond is a protocol violation.
+pub(crate) struct Rebuild<'a> {
+    copy: &'a mut tokio::fs::File,
+    block_size: u32,
+    blocks: u32,
+    size: u64,
+    /// Where the next op's data goes
+    pos: u64,
+    pub(crate) stats: DeltaStats,
+}
+
+impl<'a> Rebuild<'a> {
+    pub(crate) fn new(copy: &'a mut tokio::fs::File, signature: &Signature, size: u64) -> Self {
+        Self {
+            copy,
+            block_size: signature.block_size,
+            blocks: signature.blocks.len() as u32,
+            size,
+            pos: 0,
+            stats: DeltaStats::default(),
+        }
+    }
+
+    pub(crate) async fn apply(
+        &mut self,
+        part: &mut tokio::fs::File,
+        ops: &[DeltaOp],
+    ) -> anyhow::Result<()> {
+        let mut block = vec![0u8; self.block_size as usize];
+        for op in ops {
+            match op {
+                DeltaOp::Copy { first, count } => {
+                    if first
+                        .checked_add(*count)
+                        .is_none_or(|end| end > self.blocks)
+                    {
+                        bail!("delta refers to blocks {first}+{count} of {}", self.blocks);
+                    }
+                    self.copy
+                        .seek(SeekFrom::Start(*first as u64 * self.block_size as u64))
+                        .await?;
+                    for _ in 0..*count {
+                        self.copy.read_exact(&mut block).await?;
+                        self.write(part, &block).await?;
+                        self.stats.matched += block.len() as u64;
+                    }
+                }
+                DeltaOp::Literal(data) => {
+                    self.write(part, data).await?;
+                    self.stats.literal += data.len() as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, part: &mut tokio::fs::File, data: &[u8]) -> anyhow::Result<()> {
+        if self.pos + data.len() as u64 > self.size {
+            bail!("delta runs past the end of the file");
+        }
+        part.seek(SeekFrom::Start(self.pos)).await?;
+        part.write_all(data).await?;
+        self.pos += data.len() as u64;
+        Ok(())
+    }
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::memory;
+
+    #[test]
+    fn rolling_matches_a_fresh_checksum() {
+        let data: Vec<u8> = (0..5000u32).map(|i| (i * 7919 % 251) as u8).collect();
+        let mut rolling = Rolling::new(&data[..1024]);
+        for start in 1..=data.len() - 1024 {
+            rolling.roll(data[start - 1], data[start + 1023]);
+            assert_eq!(
+                rolling.digest(),
+                Rolling::new(&data[start..start + 1024]).digest()
+            );
+        }
+    }
+
+    #[test]
+    fn blocks_grow_with_the_file() {
+        assert_eq!(block_size(100), None);
+        assert_eq!(block_size(4096), Some(1024));
+        assert_eq!(block_size(100 << 20), Some(10240));
+        assert_eq!(block_size(1 << 40), Some(1 << 20));
+    }
+
+    #[tokio::test]
+    async fn an_edited_file_is_rebuilt_from_the_old_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let old: Vec<u8> = (0..300_000u64)
+            .map(|i| (i.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 56) as u8)
+            .collect();
+        let mut new = old.clone();
+        new.splice(100_000..100_010, *b"replaced, and then some");
+        new.truncate(280_000);
+        let (old_path, new_path) = (dir.path().join("old"), dir.path().join("new"));
+        std::fs::write(&old_path, &old).unwrap();
+        std::fs::write(&new_path, &new).unwrap();
+
+        let mut copy = tokio::fs::File::open(&old_path).await.unwrap();
+        let signature = signature(&mut copy, 0).await.unwrap().unwrap();
+        let (mut sender, mut receiver) = memory::pair();
+        let sent = send_delta(&mut sender, &new_path, &signature, None)
+            .await
+            .unwrap();
+
+        let mut part = tokio::fs::File::create(dir.path().join("part"))
+            .await
+            .unwrap();
+        let mut rebuild = Rebuild::new(&mut copy, &signature, new.len() as u64);
+        loop {
+            let Message::Delta(delta) = receiver.receive_message().await.unwrap() else {
+                panic!("expected a delta");
+            };
+            rebuild.apply(&mut part, &delta.ops).await.unwrap();
+            if delta.done {
+                break;
+            }
+        }
+        assert_eq!(rebuild.stats, sent);
+        assert_eq!(sent.literal + sent.matched, new.len() as u64);
+        assert!(sent.literal < 5000, "{sent:?}");
+        part.flush().await.unwrap();
+        assert_eq!(std::fs::read(dir.path().join("part")).unwrap(), new);
+    }
+}
@@ -0,0 +1,244 @@
+use crate::protocol::FileMeta;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Which file attributes a transfer carries over besides the content. The sender leaves out
+/// what it doesn't preserve, the receiver ignores what it doesn't.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Preserve {
+    /// Unix permission bits, setuid, setgid and sticky are never applied
+    pub permissions: bool,
+    /// Modification times
+    pub times: bool,
+    /// Symlinks in directories travel as links, they are left out otherwise
+    pub symlinks: bool,
+    /// Extended attributes in the `user.` namespace, needs the `xattr` feature
+    pub xattrs: bool,
+}
+
+impl Default for Preserve {
+    fn default() -> Self {
+        Self {
+            permissions: true,
+            times: true,
+            symlinks: true,
+            xattrs: false,
+        }
+    }
+}
+
+/// Permission bits a peer may set, read, write and execute for owner, group and others
+const MODE_MASK: u32 = 0o777;
+const NANOS_PER_SEC: u32 = 1_000_000_000;
+
+/// What `preserve` asks to carry over about the file at `path`, described by `meta`
+pub(crate) fn read(
+    path: &Path,
+    meta: &std::fs::Metadata,
+    preserve: &Preserve,
+) -> anyhow::Result<FileMeta> {
+    Ok(FileMeta {
+        mode: preserve.permissions.then(|| mode(meta)).flatten(),
+        mtime: match preserve.times {
+            true => meta.modified().ok().and_then(to_unix),
+            false => None,
+        },
+        xattrs: match preserve.xattrs {
+            true => xattrs::read(path)?,
+            false => Vec::new(),
+        },
+    })
+}
+
+/// Applies what `preserve` allows of `meta` to a received file. Failures only warn, the content
+/// is what matters and not every filesystem takes everything.
+pub(crate) fn apply(file: &std::fs::File, meta: &FileMeta, preserve: &Preserve, shown: &Path) {
+    if preserve.times
+        && let Some(mtime) = meta.mtime.and_then(from_unix)
+        && let Err(e) = file.set_modified(mtime)
+    {
+        log::warn!(
+            "could not set the modification time of {}: {e}",
+            shown.display()
+        );
+    }
+    if preserve.xattrs {
+        xattrs::apply(file, &meta.xattrs, shown);
+    }
+    // Last, a read-only mode must not get in the way of the rest
+    if preserve.permissions
+        && let Some(mode) = meta.mode
+        && let Err(e) = set_mode(file, mode & MODE_MASK)
+    {
+        log::warn!("could not set the permissions of {}: {e}", shown.display());
+    }
+}
+
+#[cfg(unix)]
+fn mode(meta: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(meta.permissions().mode() & MODE_MASK)
+}
+
+#[cfg(not(unix))]
+fn mode(_meta: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn set_mode(file: &std::fs::File, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    file.set_permissions(std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_file: &std::fs::File, _mode: u32) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Seconds and nanoseconds since the epoch, the nanoseconds always counting forward
+fn to_unix(time: SystemTime) -> Option<(i64, u32)> {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => Some((i64::try_from(after.as_secs()).ok()?, after.subsec_nanos())),
+        Err(before) => {
+            let before = before.duration();
+            let secs = -i64::try_from(before.as_secs()).ok()?;
+            match before.subsec_nanos() {
+                0 => Some((secs, 0)),
+                nanos => Some((secs - 1, NANOS_PER_SEC - nanos)),
+            }
+        }
+    }
+}
+
+pub(super) fn from_unix((secs, nanos): (i64, u32)) -> Option<SystemTime> {
+    if nanos >= NANOS_PER_SEC {
+        return None;
+    }
+    let whole = match secs >= 0 {
+        true => UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64))?,
+        false => UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs()))?,
+    };
+    whole.checked_add(Duration::from_nanos(nanos as u64))
+}
+
+#[cfg(all(unix, feature = "xattr"))]
+mod xattrs {
+    use std::path::Path;
+    use xattr::FileExt;
+
+    /// Other namespaces need privileges or mean something to the system, e.g. ACLs
+    const NAMESPACE: &str = "user.";
+
+    pub(super) fn read(path: &Path) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+        let mut attrs = Vec::new();
+        for name in xattr::list(path)? {
+            let Some(name) = name.to_str().filter(|n| n.starts_with(NAMESPACE)) else {
+                continue;
+            };
+            if let Some(value) = xattr::get(path, name)? {
+                attrs.push((name.to_string(), value));
+            }
+        }
+        Ok(attrs)
+    }
+
+    pub(super) fn apply(file: &std::fs::File, attrs: &[(String, Vec<u8>)], shown: &Path) {
+        for (name, value) in attrs.iter().filter(|(n, _)| n.starts_with(NAMESPACE)) {
+            if let Err(e) = file.set_xattr(name, value) {
+                log::warn!("could not set {name} on {}: {e}", shown.display());
+            }
+        }
+    }
+}
+
+#[cfg(not(all(unix, feature = "xattr")))]
+mod xattrs {
+    use std::path::Path;
+
+    pub(super) fn read(_path: &Path) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+        anyhow::bail!("extended attributes need ferry built with the `xattr` feature on Unix")
+    }
+
+    pub(super) fn apply(_file: &std::fs::File, attrs: &[(String, Vec<u8>)], shown: &Path) {
+        if !attrs.is_empty() {
+            log::warn!(
+                "ignoring extended attributes of {}, built without the `xattr` feature",
+                shown.display()
+            );
+        }
+    }
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times_survive_the_round_trip() {
+        for time in [
+            UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789),
+            UNIX_EPOCH - Duration::new(86_400, 250_000_000),
+            UNIX_EPOCH,
+        ] {
+            let unix = to_unix(time).unwrap();
+            assert!(unix.1 < NANOS_PER_SEC);
+            assert_eq!(from_unix(unix), Some(time));
+        }
+        assert_eq!(from_unix((0, NANOS_PER_SEC)), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn special_mode_bits_are_never_applied() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tool");
+        let file = std::fs::File::create(&path).unwrap();
+        let meta = FileMeta {
+            mode: Some(0o4755),
+            mtime: Some((1_600_000_000, 0)),
+            xattrs: Vec::new(),
+        };
+        apply(&file, &meta, &Preserve::default(), &path);
+        drop(file);
+
+        let applied = std::fs::metadata(&path).unwrap();
+        assert_eq!(applied.permissions().mode() & 0o7777, 0o755);
+        assert_eq!(to_unix(applied.modified().unwrap()), meta.mtime);
+        let read = read(&path, &applied, &Preserve::default()).unwrap();
+        assert_eq!(
+            read,
+            FileMeta {
+                mode: Some(0o755),
+                ..meta
+            }
+        );
+    }
+
+    #[cfg(all(unix, feature = "xattr"))]
+    #[test]
+    fn user_xattrs_are_carried() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("src"), dir.path().join("dst"));
+        std::fs::write(&src, b"x").unwrap();
+        if xattr::set(&src, "user.color", b"blue").is_err() {
+            // This filesystem doesn't do user xattrs
+            return;
+        }
+        let preserve = Preserve {
+            xattrs: true,
+            ..Preserve::default()
+        };
+        let meta = read(&src, &std::fs::metadata(&src).unwrap(), &preserve).unwrap();
+        assert_eq!(meta.xattrs, [("user.color".to_string(), b"blue".to_vec())]);
+
+        let file = std::fs::File::create(&dst).unwrap();
+        apply(&file, &meta, &preserve, &dst);
+        assert_eq!(
+            xattr::get(&dst, "user.color").unwrap(),
+            Some(b"blue".to_vec())
+        );
+    }
+}
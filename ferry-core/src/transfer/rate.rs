@@ -0,0 +1,88 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Share of a second's worth of bytes that may go out in one burst after being idle
+const BURST: f64 = 0.25;
+
+/// Token bucket capping bytes per second, shared by everything that sends or receives under
+/// the same limit. Callers taking more than is left go into debt and wait it off, so chunks
+/// larger than the bucket still pass at the right average rate.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec.max(1) as f64;
+        let burst = rate * BURST;
+        Self {
+            rate,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until `bytes` fit under the limit
+    pub(crate) async fn acquire(&self, bytes: u64) {
+        let wait = self.take(bytes, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Takes `bytes` out of the bucket, returns how long the caller owes
+    fn take(&self, bytes: u64, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let elapsed = now.saturating_duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.refilled = now;
+        bucket.tokens -= bytes as f64;
+        match bucket.tokens < 0.0 {
+            true => Duration::from_secs_f64(-bucket.tokens / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_is_free_then_debt_is_paid_in_time() {
+        let limiter = RateLimiter::new(1_000);
+        let start = Instant::now();
+        assert_eq!(limiter.take(250, start), Duration::ZERO);
+        assert_eq!(limiter.take(500, start), Duration::from_millis(500));
+        // Concurrent takers queue up behind the debt
+        assert_eq!(limiter.take(500, start), Duration::from_secs(1));
+        // Paying it off refills the bucket, but never past the burst
+        let later = start + Duration::from_secs(10);
+        assert_eq!(limiter.take(250, later), Duration::ZERO);
+        assert_eq!(limiter.take(1, later), Duration::from_millis(1));
+    }
+
+    #[tokio::test]
+    async fn acquire_holds_the_average_rate() {
+        let limiter = RateLimiter::new(100_000);
+        let start = Instant::now();
+        for _ in 0..10 {
+            limiter.acquire(10_000).await;
+        }
+        // 25KB burst, the other 75KB take 750ms
+        assert!(start.elapsed() >= Duration::from_millis(700));
+    }
+}
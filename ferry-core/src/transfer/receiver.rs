@@ -0,0 +1,715 @@
+use crate::chunker;
+use crate::protocol::{
+    Ack, ChunkId, ConflictPolicy, ErrorCode, ErrorMessage, FileEntry, Hash, Manifest, Message,
+    MessageTransport, Placement, decode,
+};
+use crate::resume::{Partial, PartialState};
+use crate::sandbox::{self, Sandbox};
+use crate::transfer::compress;
+use crate::transfer::conflict::{self, Placed};
+use crate::transfer::dedupe::ChunkIndex;
+use crate::transfer::delta::{self, DeltaStats, Rebuild};
+use crate::transfer::metadata::{self, Preserve};
+use crate::transfer::{RateLimiter, TransferReport};
+use crate::transport::{DataReceiver, DataStreams, Transport};
+use crate::utils::size::format_size;
+use anyhow::Context;
+use std::collections::{HashMap, HashSet};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+/// How often we ask for the same damaged chunk before giving up on the transfer
+const MAX_RESEND_REQUESTS: u32 = 3;
+/// Resume state is written after this many new chunks or this much time, whichever comes first
+const SAVE_EVERY_CHUNKS: usize = 32;
+const SAVE_EVERY: Duration = Duration::from_secs(2);
+/// Chunks read off data streams but not written yet
+const DATA_BACKLOG: usize = 64;
+
+/// A file being received into its part file
+struct Incoming<'a> {
+    entry: &'a FileEntry,
+    sandbox: &'a Sandbox,
+    preserve: &'a Preserve,
+    /// Where identical chunks may already be, and where this file's go once it is complete
+    index: Option<&'a ChunkIndex>,
+    partial: Partial,
+    /// Where the file ends up, '/' separated like manifest paths
+    path: String,
+    /// Left out, the receiver keeps the file that has its name
+    kept: bool,
+    /// May replace what is at its target
+    replace: bool,
+    /// `None` once the file has been moved into place, or when it is kept
+    file: Option<tokio::fs::File>,
+    state: PartialState,
+    unsaved: usize,
+    last_save: Instant,
+}
+
+/// What an earlier attempt left of `entry` at `partial`, or a fresh start. Nothing is created yet.
+async fn resume_state(
+    sandbox: &Sandbox,
+    manifest_id: Hash,
+    entry: &FileEntry,
+    partial: &Partial,
+) -> PartialState {
+    match partial.load(sandbox, manifest_id, entry).await {
+        Some(state) => state,
+        None => PartialState::new(manifest_id, entry),
+    }
+}
+
+/// Bytes of `entry` that `state` says are already on disk
+fn completed_bytes(entry: &FileEntry, state: &PartialState) -> u64 {
+    entry
+        .chunks
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| state.completed.get(*index))
+        .map(|(_, c)| c.len as u64)
+        .sum()
+}
+
+impl<'a> Incoming<'a> {
+    /// Opens the part file for `entry`, continuing from `state`. Extending it to its full size
+    /// leaves holes wherever nothing gets written.
+    async fn open(
+        sandbox: &'a Sandbox,
+        preserve: &'a Preserve,
+        index: Option<&'a ChunkIndex>,
+        entry: &'a FileEntry,
+        partial: Partial,
+        placed: &Placed,
+        state: PartialState,
+    ) -> anyhow::Result<Self> {
+        let resuming = state.completed.count() > 0;
+        let file = sandbox
+            .create(&partial.part, !resuming)
+            .await
+            .with_context(|| format!("create {}", sandbox.display(&partial.part).display()))?;
+        file.set_len(entry.size).await?;
+        Ok(Self {
+            entry,
+            sandbox,
+            preserve,
+            index,
+            partial,
+            path: placed.path(entry).to_string(),
+            kept: false,
+            replace: placed.replace,
+            file: Some(file),
+            state,
+            unsaved: 0,
+            last_save: Instant::now(),
+        })
+    }
+
+    /// Stands in for a file that is left out, counting as complete from the start
+    fn kept(
+        sandbox: &'a Sandbox,
+        preserve: &'a Preserve,
+        entry: &'a FileEntry,
+        partial: Partial,
+        manifest_id: Hash,
+    ) -> Self {
+        let mut state = PartialState::new(manifest_id, entry);
+        for index in 0..entry.chunks.len() {
+            state.completed.set(index);
+        }
+        Self {
+            entry,
+            sandbox,
+            preserve,
+            index: None,
+            partial,
+            path: entry.path.clone(),
+            kept: true,
+            replace: false,
+            file: None,
+            state,
+            unsaved: 0,
+            last_save: Instant::now(),
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.file.is_none()
+    }
+
+    /// Rebuilds the file from the sender's delta against the copy already at its target, if
+    /// there is one and nothing of the file was received yet. Chunks that come out right count
+    /// as received, the rest is asked for as usual.
+    async fn rebuild<T>(&mut self, transport: &mut T, index: u32) -> anyhow::Result<DeltaStats>
+    where
+        T: Transport + Send + ?Sized,
+    {
+        // Deltas write every byte, holes would be filled in
+        if self.kept || self.state.completed.count() > 0 || !self.entry.holes.is_empty() {
+            return Ok(DeltaStats::default());
+        }
+        let Ok(mut copy) = self.sandbox.open_file(&self.partial.target).await else {
+            return Ok(DeltaStats::default());
+        };
+        if !copy.metadata().await?.is_file() {
+            return Ok(DeltaStats::default());
+        }
+        let Some(signature) = delta::signature(&mut copy, index).await? else {
+            return Ok(DeltaStats::default());
+        };
+        let mut rebuild = Rebuild::new(&mut copy, &signature, self.entry.size);
+        transport
+            .send_message(&Message::Signature(signature))
+            .await?;
+        let part = self.file.as_mut().context("file already finished")?;
+        loop {
+            match transport.receive_message().await? {
+                Message::Delta(delta) if delta.file == index => {
+                    rebuild.apply(part, &delta.ops).await?;
+                    if delta.done {
+                        break;
+                    }
+                }
+                other => return Err(other.unexpected("Delta")),
+            }
+        }
+        let stats = rebuild.stats;
+        self.keep_verified().await?;
+        Ok(stats)
+    }
+
+    /// Marks the chunks the part file already holds intact as received
+    async fn keep_verified(&mut self) -> anyhow::Result<()> {
+        let file = self.file.as_mut().context("file already finished")?;
+        file.flush().await?;
+        let mut data = Vec::new();
+        for (index, info) in self.entry.chunks.iter().enumerate() {
+            data.resize(info.len as usize, 0);
+            file.seek(SeekFrom::Start(info.offset)).await?;
+            file.read_exact(&mut data).await?;
+            if chunker::verify(info, &data) {
+                self.state.completed.set(index);
+            }
+        }
+        self.save().await
+    }
+
+    /// Fills in missing chunks from identical ones the index knows about, returns how many
+    /// bytes that saved sending
+    async fn copy_known(&mut self) -> anyhow::Result<u64> {
+        let Some(index) = self.index else {
+            return Ok(0);
+        };
+        let mut copied = 0;
+        for (i, info) in self.entry.chunks.iter().enumerate() {
+            if self.state.completed.get(i) {
+                continue;
+            }
+            if let Some(data) = index.read(self.sandbox, info).await {
+                self.write(i, &data).await?;
+                copied += data.len() as u64;
+            }
+        }
+        Ok(copied)
+    }
+
+    async fn write(&mut self, index: usize, data: &[u8]) -> anyhow::Result<()> {
+        let offset = self.entry.chunks[index].offset;
+        let file = self.file.as_mut().context("file already finished")?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        file.write_all(data).await.with_context(|| {
+            format!(
+                "write {}",
+                self.sandbox.display(&self.partial.part).display()
+            )
+        })?;
+        self.state.completed.set(index);
+        self.unsaved += 1;
+        if self.unsaved >= SAVE_EVERY_CHUNKS || self.last_save.elapsed() >= SAVE_EVERY {
+            self.save().await?;
+        }
+        Ok(())
+    }
+
+    /// Persists which chunks made it, so a new connection can continue from here
+    async fn save(&mut self) -> anyhow::Result<()> {
+        let Some(file) = self.file.as_mut() else {
+            return Ok(());
+        };
+        file.flush().await?;
+        self.partial.save(self.sandbox, &self.state).await?;
+        self.unsaved = 0;
+        self.last_save = Instant::now();
+        Ok(())
+    }
+
+    /// Closes the part file and checks it against the sender's whole file hash, then gives it
+    /// the sender's metadata and moves it into place. A file that doesn't match is thrown away.
+    async fn finish(&mut self) -> anyhow::Result<()> {
+        if self.kept {
+            return Ok(());
+        }
+        if let Some(mut file) = self.file.take() {
+            file.flush().await?;
+            let hash = chunker::hash_file(&mut file, self.entry.size).await?;
+            if hash != self.entry.hash {
+                drop(file);
+                self.partial.discard(self.sandbox).await;
+                let err = ErrorMessage::new(
+                    ErrorCode::IntegrityFailed,
+                    format!(
+                        "{} does not match the hash of the file that was sent",
+                        self.entry.path
+                    ),
+                );
+                return Err(err.into());
+            }
+            let file = file.into_std().await;
+            let (meta, preserve) = (self.entry.meta.clone(), *self.preserve);
+            let shown = self.sandbox.display(&self.partial.target);
+            tokio::task::spawn_blocking(move || {
+                metadata::apply(&file, &meta, &preserve, &shown);
+                file.sync_all()
+            })
+            .await??;
+        }
+        self.partial.finish(self.sandbox, self.replace).await?;
+        if let Some(index) = self.index {
+            index.add(&self.path, &self.entry.chunks).await;
+        }
+        Ok(())
+    }
+}
+
+/// Receives the files announced in `manifest` and writes them under `root`, never outside it.
+/// Every chunk is checked against its hash before it touches the disk, damaged ones are
+/// requested again. Chunks left over from an interrupted attempt are kept, only the missing
+/// ones are asked for, and so are chunks `index` finds identical copies of under `root`.
+/// Transfers larger than `max_size` or than the free space under `root` are
+/// refused up front. `limiter` caps how fast chunks are taken off the connection. Of the
+/// metadata and symlinks the sender carries, only what `preserve` allows is applied.
+/// Files whose name is taken are dealt with as `conflict` says, whatever the manifest asks for.
+/// Returns once every file has been written and acknowledged.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn receive_files<T>(
+    transport: &mut T,
+    root: &Path,
+    manifest: &Manifest,
+    max_size: Option<u64>,
+    limiter: Option<&RateLimiter>,
+    preserve: &Preserve,
+    index: Option<&ChunkIndex>,
+    conflict: ConflictPolicy,
+) -> anyhow::Result<TransferReport>
+where
+    T: Transport + Send + ?Sized,
+{
+    let start = Instant::now();
+    let sandbox = Sandbox::open(root).with_context(|| format!("open {}", root.display()))?;
+    let (targets, dirs, links) = match check_manifest(manifest) {
+        Ok(checked) => checked,
+        Err(reason) => {
+            let err = ErrorMessage::new(ErrorCode::InvalidManifest, reason);
+            transport.send_message(&Message::Error(err.clone())).await?;
+            return Err(err.into());
+        }
+    };
+
+    let (placed, placed_links) =
+        match conflict::place(&sandbox, manifest, &targets, &links, &dirs, conflict).await {
+            Ok(placed) => placed,
+            Err(err) => {
+                transport.send_message(&Message::Error(err.clone())).await?;
+                return Err(err.into());
+            }
+        };
+
+    let manifest_id = manifest.id();
+    let mut resumed = Vec::with_capacity(targets.len());
+    for (placed, entry) in placed.iter().zip(&manifest.files) {
+        let partial = Partial::for_target(&placed.target);
+        let state = resume_state(&sandbox, manifest_id, entry, &partial).await;
+        resumed.push((entry, placed, partial, state));
+    }
+    let logical: u64 = manifest.files.iter().map(|f| f.size).sum();
+    let holes: u64 = manifest.files.iter().map(|f| f.hole_len()).sum();
+    // Refuse now rather than run out of room halfway, holes and kept files take no room
+    let needed: u64 = resumed
+        .iter()
+        .filter(|(_, placed, _, _)| placed.placement != Placement::Kept)
+        .map(|(entry, _, _, state)| entry.size - entry.hole_len() - completed_bytes(entry, state))
+        .sum();
+    if let Err(err) = check_space(sandbox.root(), logical, needed, max_size) {
+        transport.send_message(&Message::Error(err.clone())).await?;
+        return Err(err.into());
+    }
+
+    for dir in &dirs {
+        sandbox
+            .create_dir_all(dir)
+            .await
+            .with_context(|| format!("create {}", sandbox.display(dir).display()))?;
+    }
+    if preserve.symlinks {
+        let links = placed_links.iter().zip(&manifest.links);
+        for (placed, entry) in links.filter(|(placed, _)| placed.placement != Placement::Kept) {
+            let link = &placed.target;
+            sandbox
+                .symlink(&entry.target, link, placed.replace)
+                .await
+                .with_context(|| format!("create link {}", sandbox.display(link).display()))?;
+        }
+    }
+    let mut incoming = Vec::with_capacity(resumed.len());
+    for (entry, placed, partial, state) in resumed {
+        incoming.push(match placed.placement {
+            Placement::Kept => Incoming::kept(&sandbox, preserve, entry, partial, manifest_id),
+            _ => Incoming::open(&sandbox, preserve, index, entry, partial, placed, state).await?,
+        });
+    }
+    let mut delta = DeltaStats::default();
+    if manifest.delta {
+        for (index, file) in incoming.iter_mut().enumerate() {
+            let stats = file.rebuild(transport, index as u32).await?;
+            delta.literal += stats.literal;
+            delta.matched += stats.matched;
+        }
+    }
+    let mut deduped = 0;
+    for file in &mut incoming {
+        deduped += file.copy_known().await?;
+    }
+    let skipped = incoming
+        .iter()
+        .filter(|f| !f.kept)
+        .map(|f| completed_bytes(f.entry, &f.state))
+        .sum();
+    let missing = incoming
+        .iter()
+        .map(|f| f.state.completed.missing_ranges())
+        .collect();
+    let placements: Vec<_> = placed.into_iter().map(|p| p.placement).collect();
+    transport
+        .send_message(&Message::Ack(Ack::Manifest {
+            missing,
+            placements: placements.clone(),
+        }))
+        .await?;
+
+    let result = receive_chunks(transport, &mut incoming, limiter).await;
+    if result.is_err() {
+        // Remember what made it so the next attempt only needs the rest
+        for file in incoming.iter_mut().filter(|f| !f.is_done()) {
+            if let Err(e) = file.save().await {
+                log::warn!("could not save resume state for {}: {e:#}", file.entry.path);
+            }
+        }
+    }
+    if let Some(index) = index
+        && let Err(e) = index.save().await
+    {
+        log::warn!("could not save the chunk index: {e:#}");
+    }
+    let (received, wire) = result?;
+
+    Ok(TransferReport {
+        files: manifest.files.len(),
+        bytes: received + delta.literal,
+        wire: wire + delta.literal,
+        skipped,
+        deduped,
+        matched: delta.matched,
+        logical,
+        holes,
+        elapsed: start.elapsed(),
+        placements,
+        receipt: None,
+    })
+}
+
+/// Returns the number of bytes received, once decompressed and as they came. Chunks may come on
+/// the main stream or on any data stream the sender opens.
+async fn receive_chunks<T>(
+    transport: &mut T,
+    incoming: &mut [Incoming<'_>],
+    limiter: Option<&RateLimiter>,
+) -> anyhow::Result<(u64, u64)>
+where
+    T: Transport + Send + ?Sized,
+{
+    let (data_tx, mut data) = mpsc::channel(DATA_BACKLOG);
+    // Dropping the set stops the acceptor and every stream reader with it
+    let mut acceptor = JoinSet::new();
+    if let Some(streams) = transport.data_streams() {
+        acceptor.spawn(accept_data_streams(streams, data_tx));
+    } else {
+        drop(data_tx);
+    }
+
+    let mut unfinished = incoming.len();
+    for (index, file) in incoming.iter_mut().enumerate() {
+        if file.state.completed.is_complete() {
+            complete(transport, file, index as u32).await?;
+            unfinished -= 1;
+        }
+    }
+
+    let codecs = compress::supported();
+    let (mut received, mut wire) = (0u64, 0u64);
+    let mut resend_requests: HashMap<ChunkId, u32> = HashMap::new();
+    while unfinished > 0 {
+        let message = tokio::select! {
+            message = transport.receive_message() => message?,
+            Some(message) = data.recv() => message?,
+        };
+        let chunk = match message {
+            Message::Chunk(chunk) => chunk,
+            other => return Err(other.unexpected("Chunk")),
+        };
+        let chunk_len = chunk.data.len() as u64;
+        if let Some(limiter) = limiter {
+            // Holding back here backs up the streams, which slows the sender down
+            limiter.acquire(chunk_len).await;
+        }
+        let id = ChunkId {
+            file: chunk.file,
+            index: chunk.index,
+        };
+        let Some(file) = incoming.get_mut(chunk.file as usize) else {
+            return Err(protocol_violation(
+                transport,
+                format!("chunk for unknown file {}", chunk.file),
+            )
+            .await);
+        };
+        let Some(info) = file.entry.chunks.get(chunk.index as usize) else {
+            return Err(protocol_violation(transport, format!("unknown chunk {id:?}")).await);
+        };
+        if file.is_done() || file.state.completed.get(chunk.index as usize) {
+            // A resend raced with the original, nothing to do
+            continue;
+        }
+
+        let data = match chunk.compression {
+            None => Some(chunk.data),
+            Some(codec) if !codecs.contains(&codec) => {
+                let reason =
+                    format!("chunk {id:?} is compressed with {codec}, which we don't take");
+                return Err(protocol_violation(transport, reason).await);
+            }
+            Some(codec) => compress::decompress(codec, &chunk.data, info.len as usize)
+                .inspect_err(|e| {
+                    log::warn!(
+                        "chunk {} of {} did not decompress: {e:#}",
+                        id.index,
+                        file.entry.path
+                    )
+                })
+                .ok(),
+        };
+        let Some(data) = data.filter(|data| chunker::verify(info, data)) else {
+            let count = resend_requests.entry(id).or_default();
+            *count += 1;
+            if *count > MAX_RESEND_REQUESTS {
+                let err = ErrorMessage::new(
+                    ErrorCode::Internal,
+                    format!(
+                        "chunk {} of {} failed verification {count} times",
+                        id.index, file.entry.path
+                    ),
+                );
+                transport.send_message(&Message::Error(err.clone())).await?;
+                return Err(err.into());
+            }
+            log::warn!(
+                "chunk {} of {} failed verification, asking again",
+                id.index,
+                file.entry.path
+            );
+            transport.send_message(&Message::Resend(id)).await?;
+            continue;
+        };
+
+        wire += chunk_len;
+        file.write(chunk.index as usize, &data).await?;
+        received += data.len() as u64;
+        if file.state.completed.is_complete() {
+            complete(transport, file, chunk.file).await?;
+            unfinished -= 1;
+        }
+    }
+    Ok((received, wire))
+}
+
+/// Moves a file whose chunks are all there into place and acknowledges it, or tells the sender
+/// it came out wrong
+async fn complete<T>(transport: &mut T, file: &mut Incoming<'_>, index: u32) -> anyhow::Result<()>
+where
+    T: Transport + Send + ?Sized,
+{
+    if let Err(e) = file.finish().await {
+        if let Some(err) = e.downcast_ref::<ErrorMessage>() {
+            transport.send_message(&Message::Error(err.clone())).await?;
+        }
+        return Err(e);
+    }
+    transport
+        .send_message(&Message::Ack(Ack::File { file: index }))
+        .await
+}
+
+/// Hands every message arriving on the peer's data streams to `data`
+async fn accept_data_streams(
+    streams: Arc<dyn DataStreams>,
+    data: mpsc::Sender<anyhow::Result<Message>>,
+) {
+    let mut readers = JoinSet::new();
+    loop {
+        let stream = match streams.accept().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                let _ = data.send(Err(e)).await;
+                return;
+            }
+        };
+        readers.spawn(read_data_stream(stream, data.clone()));
+        // Forget the readers that are done
+        while readers.try_join_next().is_some() {}
+    }
+}
+
+async fn read_data_stream(
+    mut stream: Box<dyn DataReceiver>,
+    data: mpsc::Sender<anyhow::Result<Message>>,
+) {
+    loop {
+        let message = match stream.receive_data().await {
+            Ok(Some(payload)) => decode(&payload),
+            Ok(None) => return,
+            Err(e) => Err(e),
+        };
+        let failed = message.is_err();
+        if data.send(message).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+async fn protocol_violation<T>(transport: &mut T, reason: String) -> anyhow::Error
+where
+    T: MessageTransport + Send + ?Sized,
+{
+    let err = ErrorMessage::new(ErrorCode::UnexpectedMessage, reason);
+    let _ = transport.send_message(&Message::Error(err.clone())).await;
+    err.into()
+}
+
+/// Makes sure `needed` more bytes fit under `root`, and that the transfer's `total` is allowed
+fn check_space(
+    root: &Path,
+    total: u64,
+    needed: u64,
+    max_size: Option<u64>,
+) -> Result<(), ErrorMessage> {
+    if let Some(max) = max_size
+        && total > max
+    {
+        return Err(ErrorMessage::new(
+            ErrorCode::TooLarge,
+            format!(
+                "{} is more than the {} this server accepts",
+                format_size(total),
+                format_size(max)
+            ),
+        ));
+    }
+    let available = match fs4::available_space(root) {
+        Ok(available) => available,
+        Err(e) => {
+            // Not knowing is no reason to refuse, the writes will tell
+            log::warn!("could not check free space on {}: {e}", root.display());
+            return Ok(());
+        }
+    };
+    if needed > available {
+        return Err(ErrorMessage::new(
+            ErrorCode::InsufficientSpace,
+            format!(
+                "{} needed but only {} free on the server",
+                format_size(needed),
+                format_size(available)
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Where the files, directories and links of a manifest go below the root
+type Targets = (Vec<PathBuf>, Vec<PathBuf>, Vec<PathBuf>);
+
+/// Validates every entry and works out where it, every directory and every link go below the
+/// root, or explains why the manifest is refused. Nothing may go through one of the links, and
+/// no two entries may end up at the same path.
+fn check_manifest(manifest: &Manifest) -> Result<Targets, String> {
+    let refuse = |why| format!("refusing to write: {why}");
+    let links: Vec<PathBuf> = manifest
+        .links
+        .iter()
+        .map(|link| {
+            let path = sandbox::normalize(&link.path).map_err(refuse)?;
+            sandbox::check_link(&path, &link.target).map_err(refuse)?;
+            Ok(path)
+        })
+        .collect::<Result<_, String>>()?;
+    let through: HashSet<PathBuf> = links.iter().cloned().collect();
+    let placed = |path: PathBuf| {
+        sandbox::check_parents(&path, &through).map_err(refuse)?;
+        Ok(path)
+    };
+    let targets = manifest
+        .files
+        .iter()
+        .map(|entry| {
+            chunker::validate_layout(entry)?;
+            placed(sandbox::normalize(&entry.path).map_err(refuse)?)
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let dirs = manifest
+        .dirs
+        .iter()
+        .map(|dir| placed(sandbox::normalize(dir).map_err(refuse)?))
+        .collect::<Result<Vec<_>, String>>()?;
+    let links = links
+        .into_iter()
+        .map(placed)
+        .collect::<Result<Vec<_>, String>>()?;
+    let mut seen = HashSet::new();
+    for path in targets.iter().chain(&dirs).chain(&links) {
+        if !seen.insert(path) {
+            return Err(refuse(format!("{} appears more than once", path.display())));
+        }
+    }
+    Ok((targets, dirs, links))
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn space_is_checked_against_what_is_still_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let free = fs4::available_space(dir.path()).unwrap();
+        assert!(check_space(dir.path(), free * 2, 1, None).is_ok());
+        let err = check_space(dir.path(), free * 2, free * 2, None).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InsufficientSpace);
+        let err = check_space(dir.path(), 10, 10, Some(9)).unwrap_err();
+        assert_eq!(err.code, ErrorCode::TooLarge);
+    }
+}
@@ -0,0 +1,529 @@
+use crate::chunker::{self, Chunker};
+use crate::protocol::{
+    Ack, Chunk, ChunkId, Compression, ConflictPolicy, FileEntry, LinkEntry, Manifest, Message,
+    MessageTransport, Placement, encode,
+};
+use crate::transfer::TransferReport;
+use crate::transfer::compress;
+use crate::transfer::concurrency::{Controller, SAMPLE_EVERY};
+use crate::transfer::delta::{self, DeltaStats};
+use crate::transfer::metadata::{self, Preserve};
+use crate::transfer::rate::RateLimiter;
+use crate::transfer::walk::{self, Filters, Tree};
+use crate::transport::{DataStreams, Transport};
+use anyhow::{Context, bail};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use tokio::sync::{Mutex, mpsc, watch};
+use tokio::task::JoinSet;
+
+/// How often the receiver may ask for the same chunk before we give up
+const MAX_RESENDS: u32 = 3;
+
+/// A local file paired with the manifest entry announcing it
+#[derive(Debug, Clone)]
+pub(crate) struct Source {
+    pub(crate) local: PathBuf,
+    pub(crate) entry: FileEntry,
+}
+
+/// Everything one transfer announces
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Outgoing {
+    pub(crate) files: Vec<Source>,
+    /// Directories created on the receiver even though no file goes into them
+    pub(crate) dirs: Vec<String>,
+    pub(crate) links: Vec<LinkEntry>,
+    /// Send files the receiver has a copy of as deltas against it
+    pub(crate) delta: bool,
+    /// Codec chunks are compressed with, agreed on with the receiver in the hellos
+    pub(crate) compression: Option<Compression>,
+    /// What we ask the receiver to do with files whose name is taken
+    pub(crate) conflict: ConflictPolicy,
+}
+
+/// Turns the paths given on the command line into transfer sources, reading every file once to
+/// work out its chunks. Files and directories keep their own name on the receiver, directories
+/// are walked with `filters` and arrive with the same structure. `preserve` picks the metadata
+/// that goes along.
+pub(crate) fn collect_sources(
+    paths: &[PathBuf],
+    filters: &Filters,
+    preserve: &Preserve,
+    chunker: &Chunker,
+) -> anyhow::Result<Outgoing> {
+    let mut tree = Tree::default();
+    for path in paths {
+        tree.extend(walk::walk(
+            path,
+            &file_name(path)?,
+            filters,
+            preserve.symlinks,
+        )?);
+    }
+    collect_tree(tree, preserve, chunker)
+}
+
+/// Like [`collect_sources`], with the '/' separated path each file gets on the receiver picked
+/// by the caller
+pub(crate) fn collect_tree(
+    tree: Tree,
+    preserve: &Preserve,
+    chunker: &Chunker,
+) -> anyhow::Result<Outgoing> {
+    let Tree {
+        files,
+        links,
+        empty_dirs,
+    } = tree;
+    let mut names = HashSet::new();
+    let mut sources = Vec::with_capacity(files.len());
+    for (path, name) in files {
+        let meta =
+            std::fs::metadata(&path).with_context(|| format!("cannot read {}", path.display()))?;
+        if !meta.is_file() {
+            bail!("{} is not a regular file", path.display());
+        }
+        if !names.insert(name.clone()) {
+            bail!("more than one file is named {name}");
+        }
+        let layout = chunker.chunk_file(&path)?;
+        let meta = metadata::read(&path, &meta, preserve)
+            .with_context(|| format!("cannot read the metadata of {}", path.display()))?;
+        sources.push(Source {
+            entry: FileEntry {
+                path: name,
+                size: layout.size,
+                chunks: layout.chunks,
+                holes: layout.holes,
+                meta,
+                hash: layout.hash,
+            },
+            local: path,
+        });
+    }
+    let mut link_entries = Vec::with_capacity(links.len());
+    for (path, name) in links {
+        if !names.insert(name.clone()) {
+            bail!("more than one file is named {name}");
+        }
+        let target = std::fs::read_link(&path)
+            .with_context(|| format!("cannot read link {}", path.display()))?;
+        let target = target.to_str().with_context(|| {
+            format!(
+                "{} points to a path that is not valid UTF-8",
+                path.display()
+            )
+        })?;
+        link_entries.push(LinkEntry {
+            path: name,
+            target: target.to_string(),
+        });
+    }
+    let mut dirs = Vec::with_capacity(empty_dirs.len());
+    for dir in empty_dirs {
+        if !names.insert(dir.clone()) {
+            bail!("more than one file or directory is named {dir}");
+        }
+        dirs.push(dir);
+    }
+    Ok(Outgoing {
+        files: sources,
+        dirs,
+        links: link_entries,
+        delta: false,
+        compression: None,
+        conflict: ConflictPolicy::default(),
+    })
+}
+
+fn file_name(path: &Path) -> anyhow::Result<String> {
+    let name = path
+        .file_name()
+        .with_context(|| format!("{} has no file name", path.display()))?;
+    name.to_str()
+        .map(str::to_string)
+        .with_context(|| format!("{} is not valid UTF-8", path.display()))
+}
+
+/// Announces `outgoing` in a manifest, streams the chunks the receiver doesn't have yet and
+/// waits until it confirms every file was written, resending chunks that arrived damaged.
+/// With `streams` above one and a transport that has data streams, chunks are spread over up to
+/// that many streams, otherwise they follow the control messages on the main stream.
+/// `limiter` caps how fast chunks go out.
+pub(crate) async fn send_files<T>(
+    transport: &mut T,
+    outgoing: &Outgoing,
+    streams: usize,
+    limiter: Option<Arc<RateLimiter>>,
+) -> anyhow::Result<TransferReport>
+where
+    T: Transport + Send + ?Sized,
+{
+    let start = Instant::now();
+    let sources = &outgoing.files;
+    let manifest = Manifest {
+        files: sources.iter().map(|s| s.entry.clone()).collect(),
+        dirs: outgoing.dirs.clone(),
+        links: outgoing.links.clone(),
+        delta: outgoing.delta,
+        conflict: outgoing.conflict,
+    };
+    transport.send_message(&Message::Manifest(manifest)).await?;
+    let mut delta = DeltaStats::default();
+    let (missing, placements) = loop {
+        match transport.receive_message().await? {
+            Message::Ack(Ack::Manifest {
+                missing,
+                placements,
+            }) => break (missing, placements),
+            Message::Signature(signature) if outgoing.delta => {
+                let source = sources
+                    .get(signature.file as usize)
+                    .context("receiver sent a signature for an unknown file")?;
+                let stats =
+                    delta::send_delta(transport, &source.local, &signature, limiter.as_deref())
+                        .await?;
+                delta.literal += stats.literal;
+                delta.matched += stats.matched;
+            }
+            other => return Err(other.unexpected("manifest Ack")),
+        }
+    };
+    if missing.len() != sources.len() || placements.len() != sources.len() {
+        bail!(
+            "receiver answered for {} files, we sent {}",
+            missing.len().min(placements.len()),
+            sources.len()
+        );
+    }
+
+    let logical: u64 = sources.iter().map(|s| s.entry.size).sum();
+    let holes: u64 = sources.iter().map(|s| s.entry.hole_len()).sum();
+    let kept: u64 = sources
+        .iter()
+        .zip(&placements)
+        .filter(|(_, placement)| **placement == Placement::Kept)
+        .map(|(s, _)| s.entry.size - s.entry.hole_len())
+        .sum();
+    let total = logical - holes - kept;
+    let mut needed = 0u64;
+    let mut plan = Vec::new();
+    let answers = sources.iter().zip(&missing).zip(&placements);
+    for (file, ((source, ranges), placement)) in answers.enumerate() {
+        if *placement == Placement::Kept && !ranges.is_empty() {
+            bail!(
+                "receiver asked for chunks of {}, which it keeps",
+                source.entry.path
+            );
+        }
+        let mut asked = HashSet::new();
+        for index in ranges.iter().flat_map(|r| r.clone()) {
+            let Some(info) = source.entry.chunks.get(index as usize) else {
+                bail!(
+                    "receiver asked for unknown chunk {index} of {}",
+                    source.entry.path
+                );
+            };
+            if !asked.insert(index) {
+                bail!(
+                    "receiver asked for chunk {index} of {} twice",
+                    source.entry.path
+                );
+            }
+            needed += info.len as u64;
+            plan.push(ChunkId {
+                file: file as u32,
+                index,
+            });
+        }
+    }
+
+    let compression = outgoing.compression;
+    let (bytes, wire) = match transport.data_streams() {
+        Some(data) if streams > 1 => {
+            send_parallel(
+                transport,
+                data,
+                sources,
+                plan,
+                streams,
+                limiter,
+                compression,
+            )
+            .await?
+        }
+        _ => send_sequential(transport, sources, plan, limiter.as_deref(), compression).await?,
+    };
+
+    Ok(TransferReport {
+        files: sources.len(),
+        bytes: bytes + delta.literal,
+        wire: wire + delta.literal,
+        skipped: total - needed,
+        deduped: 0,
+        matched: delta.matched,
+        logical,
+        holes,
+        elapsed: start.elapsed(),
+        placements,
+        receipt: None,
+    })
+}
+
+/// Everything over the main stream, returns the number of bytes sent before and after
+/// compression. Acks and resend requests are read between chunks, a receiver stuck on writing
+/// them would stop reading chunks too.
+async fn send_sequential<T>(
+    transport: &mut T,
+    sources: &[Source],
+    plan: Vec<ChunkId>,
+    limiter: Option<&RateLimiter>,
+    compression: Option<Compression>,
+) -> anyhow::Result<(u64, u64)>
+where
+    T: Transport + Send + ?Sized,
+{
+    let mut readers = Readers::new(compression);
+    let (mut bytes, mut wire) = (0u64, 0u64);
+    let mut queue = VecDeque::from(plan);
+    let mut resends = Resends::default();
+    let mut pending: HashSet<u32> = (0..sources.len() as u32).collect();
+    while !pending.is_empty() {
+        let more = !queue.is_empty();
+        tokio::select! {
+            // Whatever the receiver already said goes first
+            biased;
+            message = transport.receive_message() => match message? {
+                Message::Ack(Ack::File { file }) => {
+                    pending.remove(&file);
+                }
+                Message::Resend(id) => {
+                    resends.allow(sources, id)?;
+                    queue.push_back(id);
+                }
+                other => return Err(other.unexpected("file Ack")),
+            },
+            _ = std::future::ready(()), if more => {
+                let Some(id) = queue.pop_front() else {
+                    continue;
+                };
+                let (chunk, len) = readers.read(sources, id).await?;
+                bytes += len;
+                wire += chunk.data.len() as u64;
+                if let Some(limiter) = limiter {
+                    limiter.acquire(chunk.data.len() as u64).await;
+                }
+                transport.send_message(&Message::Chunk(chunk)).await?;
+            }
+        }
+    }
+    Ok((bytes, wire))
+}
+
+/// Chunks spread over up to `streams` data streams while acks and resend requests keep coming
+/// in on the main one. How many streams are busy at once follows the connection's throughput
+/// and round trip time. Returns the number of bytes sent before and after compression.
+async fn send_parallel<T>(
+    transport: &mut T,
+    data: Arc<dyn DataStreams>,
+    sources: &[Source],
+    plan: Vec<ChunkId>,
+    streams: usize,
+    limiter: Option<Arc<RateLimiter>>,
+    compression: Option<Compression>,
+) -> anyhow::Result<(u64, u64)>
+where
+    T: Transport + Send + ?Sized,
+{
+    let (queue, work) = mpsc::unbounded_channel();
+    for id in plan {
+        queue.send(id)?;
+    }
+    let mut controller = Controller::new(streams);
+    let (active, active_rx) = watch::channel(controller.limit());
+    let shared = Arc::new(Workers {
+        data: data.clone(),
+        sources: sources.to_vec().into(),
+        work: Mutex::new(work),
+        sent: AtomicU64::new(0),
+        wire: AtomicU64::new(0),
+        limiter,
+        compression,
+    });
+    let mut workers = JoinSet::new();
+    for index in 0..streams {
+        workers.spawn(stream_worker(index, shared.clone(), active_rx.clone()));
+    }
+
+    let mut resends = Resends::default();
+    let mut pending: HashSet<u32> = (0..sources.len() as u32).collect();
+    let mut ticks = tokio::time::interval(SAMPLE_EVERY);
+    let mut sampled = Instant::now();
+    while !pending.is_empty() {
+        tokio::select! {
+            message = transport.receive_message() => match message? {
+                Message::Ack(Ack::File { file }) => {
+                    pending.remove(&file);
+                }
+                Message::Resend(id) => {
+                    resends.allow(sources, id)?;
+                    queue.send(id)?;
+                }
+                other => return Err(other.unexpected("file Ack")),
+            },
+            Some(res) = workers.join_next() => {
+                // Workers only stop on their own when something went wrong
+                res??;
+                bail!("data stream closed before the transfer finished");
+            }
+            _ = ticks.tick() => {
+                let limit = controller.update(data.stats(), sampled.elapsed());
+                sampled = Instant::now();
+                active.send_if_modified(|active| {
+                    let changed = *active != limit;
+                    if changed {
+                        log::debug!("sending on {limit} streams");
+                    }
+                    *active = limit;
+                    changed
+                });
+            }
+        }
+    }
+
+    // No more work, wake every worker so they finish their streams
+    drop(queue);
+    active.send_replace(usize::MAX);
+    while let Some(res) = workers.join_next().await {
+        res??;
+    }
+    Ok((
+        shared.sent.load(Ordering::Relaxed),
+        shared.wire.load(Ordering::Relaxed),
+    ))
+}
+
+/// State every stream worker shares
+struct Workers {
+    data: Arc<dyn DataStreams>,
+    sources: Arc<[Source]>,
+    work: Mutex<mpsc::UnboundedReceiver<ChunkId>>,
+    sent: AtomicU64,
+    /// What `sent` took once compressed
+    wire: AtomicU64,
+    limiter: Option<Arc<RateLimiter>>,
+    compression: Option<Compression>,
+}
+
+/// Sends queued chunks on its own data stream until the queue is closed. Worker `index` only
+/// takes work while fewer than `active` streams are wanted.
+async fn stream_worker(
+    index: usize,
+    shared: Arc<Workers>,
+    mut active: watch::Receiver<usize>,
+) -> anyhow::Result<()> {
+    let mut readers = Readers::new(shared.compression);
+    // Opened on first use, so idle workers don't cost the receiver a stream
+    let mut stream = None;
+    loop {
+        active.wait_for(|active| *active > index).await?;
+        let next = shared.work.lock().await.recv().await;
+        let Some(id) = next else {
+            break;
+        };
+        let (chunk, len) = readers.read(&shared.sources, id).await?;
+        let wire = chunk.data.len() as u64;
+        if let Some(limiter) = &shared.limiter {
+            limiter.acquire(wire).await;
+        }
+        let stream = match &mut stream {
+            Some(stream) => stream,
+            None => stream.insert(shared.data.open().await?),
+        };
+        stream.send_data(&encode(&Message::Chunk(chunk))?).await?;
+        shared.sent.fetch_add(len, Ordering::Relaxed);
+        shared.wire.fetch_add(wire, Ordering::Relaxed);
+    }
+    if let Some(mut stream) = stream {
+        // Every file is acknowledged by now, the receiver may already have stopped reading
+        let _ = stream.finish().await;
+    }
+    Ok(())
+}
+
+/// Keeps the last source file open, chunks mostly come in file order, and compresses what it
+/// reads
+struct Readers {
+    open: Option<(u32, tokio::fs::File)>,
+    compression: Option<Compression>,
+}
+
+impl Readers {
+    fn new(compression: Option<Compression>) -> Self {
+        Self {
+            open: None,
+            compression,
+        }
+    }
+
+    /// The chunk ready to send and its size before compression
+    async fn read(&mut self, sources: &[Source], id: ChunkId) -> anyhow::Result<(Chunk, u64)> {
+        let source = &sources[id.file as usize];
+        let file = match &mut self.open {
+            Some((file, reader)) if *file == id.file => reader,
+            open => {
+                let reader = tokio::fs::File::open(&source.local)
+                    .await
+                    .with_context(|| format!("open {}", source.local.display()))?;
+                &mut open.insert((id.file, reader)).1
+            }
+        };
+        let info = &source.entry.chunks[id.index as usize];
+        let data = chunker::read_chunk(file, info)
+            .await
+            .with_context(|| format!("read {}", source.local.display()))?;
+        // Chunks that don't shrink, like media, go as they are
+        let packed = self
+            .compression
+            .and_then(|codec| Some((codec, compress::compress(codec, &data)?)));
+        let (data, compression) = match packed {
+            Some((codec, packed)) => (packed, Some(codec)),
+            None => (data, None),
+        };
+        let chunk = Chunk {
+            file: id.file,
+            index: id.index,
+            data,
+            compression,
+        };
+        Ok((chunk, info.len as u64))
+    }
+}
+
+/// Counts resend requests so a chunk that keeps arriving damaged ends the transfer
+#[derive(Default)]
+struct Resends(HashMap<ChunkId, u32>);
+
+impl Resends {
+    fn allow(&mut self, sources: &[Source], id: ChunkId) -> anyhow::Result<()> {
+        let source = sources
+            .get(id.file as usize)
+            .filter(|s| (id.index as usize) < s.entry.chunks.len())
+            .with_context(|| format!("receiver asked for unknown chunk {id:?}"))?;
+        let count = self.0.entry(id).or_default();
+        *count += 1;
+        if *count > MAX_RESENDS {
+            bail!(
+                "chunk {} of {} keeps arriving damaged",
+                id.index,
+                source.entry.path
+            );
+        }
+        log::warn!("resending chunk {} of {}", id.index, source.entry.path);
+        Ok(())
+    }
+}
@@ -0,0 +1,285 @@
+use crate::resume;
+use anyhow::Context;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// What to leave out when walking a directory. Globs are matched against paths relative to the
+/// directory being sent, `*` crosses '/' so `*.o` matches at any depth.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Filters {
+    /// When set, only files matching one of these are sent
+    include: Option<GlobSet>,
+    /// Files and whole directories matching one of these are skipped
+    exclude: GlobSet,
+    /// Honour `.gitignore` files (and `.git/info/exclude`), `.git` itself is skipped too
+    gitignore: bool,
+}
+
+impl Filters {
+    pub(crate) fn new(
+        include: &[String],
+        exclude: &[String],
+        gitignore: bool,
+    ) -> anyhow::Result<Self> {
+        let include = match include.is_empty() {
+            true => None,
+            false => Some(glob_set(include)?),
+        };
+        Ok(Self {
+            include,
+            exclude: glob_set(exclude)?,
+            gitignore,
+        })
+    }
+
+    fn included(&self, relative: &str) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|set| set.is_match(relative))
+    }
+}
+
+fn glob_set(globs: &[String]) -> anyhow::Result<GlobSet> {
+    let mut set = GlobSetBuilder::new();
+    for glob in globs {
+        set.add(Glob::new(glob).with_context(|| format!("invalid glob {glob:?}"))?);
+    }
+    Ok(set.build()?)
+}
+
+/// Everything to send for one path: local files and symlinks with the '/' separated path each
+/// gets on the receiver, and the directories that would otherwise not exist there because
+/// nothing is sent into them
+#[derive(Debug, Default)]
+pub(crate) struct Tree {
+    pub(crate) files: Vec<(PathBuf, String)>,
+    pub(crate) links: Vec<(PathBuf, String)>,
+    pub(crate) empty_dirs: Vec<String>,
+}
+
+impl Tree {
+    pub(crate) fn extend(&mut self, other: Tree) {
+        self.files.extend(other.files);
+        self.links.extend(other.links);
+        self.empty_dirs.extend(other.empty_dirs);
+    }
+}
+
+/// Walks `path` into a [`Tree`] whose paths start with `name` (nothing when it's empty).
+/// A file is taken as it is, filters only apply below a directory. Symlinks are kept as links
+/// with `symlinks` and skipped otherwise, so are the leftovers of transfers in progress.
+pub(crate) fn walk(
+    path: &Path,
+    name: &str,
+    filters: &Filters,
+    symlinks: bool,
+) -> anyhow::Result<Tree> {
+    let cannot_read = || format!("cannot read {}", path.display());
+    if symlinks
+        && std::fs::symlink_metadata(path)
+            .with_context(cannot_read)?
+            .is_symlink()
+    {
+        return Ok(Tree {
+            links: vec![(path.to_path_buf(), name.to_string())],
+            ..Tree::default()
+        });
+    }
+    let meta = std::fs::metadata(path).with_context(cannot_read)?;
+    if !meta.is_dir() {
+        return Ok(Tree {
+            files: vec![(path.to_path_buf(), name.to_string())],
+            ..Tree::default()
+        });
+    }
+
+    let root = path.to_path_buf();
+    let exclude = filters.exclude.clone();
+    let gitignore = filters.gitignore;
+    let mut builder = WalkBuilder::new(path);
+    builder
+        .standard_filters(false)
+        .git_ignore(gitignore)
+        .git_exclude(gitignore)
+        .parents(gitignore)
+        .require_git(false)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .filter_entry(move |entry| {
+            let file_name = entry.file_name().to_string_lossy();
+            let relative = entry.path().strip_prefix(&root).unwrap_or(entry.path());
+            entry.depth() == 0
+                || !(resume::is_partial(&file_name)
+                    || (gitignore && file_name == ".git")
+                    || exclude.is_match(relative))
+        });
+
+    let mut tree = Tree::default();
+    let mut dirs = vec![String::new()];
+    // Directories something is sent into
+    let mut filled = HashSet::new();
+    for entry in builder.build() {
+        let entry = entry.with_context(|| format!("cannot walk {}", path.display()))?;
+        if entry.depth() == 0 {
+            continue;
+        }
+        let relative = relative_name(entry.path().strip_prefix(path)?)
+            .with_context(|| format!("{} is not valid UTF-8", entry.path().display()))?;
+        match entry.file_type() {
+            Some(kind) if kind.is_dir() => dirs.push(relative),
+            Some(kind) if kind.is_file() && filters.included(&relative) => {
+                fill_parents(&relative, &mut filled);
+                tree.files.push((entry.into_path(), join(name, &relative)));
+            }
+            Some(kind) if kind.is_symlink() && symlinks && filters.included(&relative) => {
+                fill_parents(&relative, &mut filled);
+                tree.links.push((entry.into_path(), join(name, &relative)));
+            }
+            _ => {}
+        }
+    }
+
+    // A directory needs sending when nothing ends up inside it. Deepest first, so a directory
+    // holding only empty ones is covered by them.
+    dirs.sort_by_key(|dir| std::cmp::Reverse(depth(dir)));
+    for dir in dirs {
+        let wanted = dir.is_empty() || filters.included(&dir);
+        if filled.contains(&dir) || !wanted {
+            continue;
+        }
+        fill_parents(&dir, &mut filled);
+        let sent = join(name, &dir);
+        if !sent.is_empty() {
+            tree.empty_dirs.push(sent);
+        }
+    }
+    tree.empty_dirs.sort();
+    Ok(tree)
+}
+
+/// Marks every directory above `relative` as having something in it, the root included
+fn fill_parents(relative: &str, filled: &mut HashSet<String>) {
+    let mut path = relative;
+    while let Some((parent, _)) = path.rsplit_once('/') {
+        filled.insert(parent.to_string());
+        path = parent;
+    }
+    filled.insert(String::new());
+}
+
+fn depth(relative: &str) -> usize {
+    match relative.is_empty() {
+        true => 0,
+        false => relative.matches('/').count() + 1,
+    }
+}
+
+fn relative_name(relative: &Path) -> Option<String> {
+    let parts = relative
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()?;
+    Some(parts.join("/"))
+}
+
+fn join(name: &str, relative: &str) -> String {
+    match (name.is_empty(), relative.is_empty()) {
+        (true, _) => relative.to_string(),
+        (false, true) => name.to_string(),
+        (false, false) => format!("{name}/{relative}"),
+    }
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree_in(dir: &Path) {
+        for file in [
+            "src/main.rs",
+            "src/lib.rs",
+            "target/debug/app",
+            "README.md",
+            ".gitignore",
+        ] {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, file).unwrap();
+        }
+        std::fs::write(dir.join(".gitignore"), "target/\n").unwrap();
+        std::fs::create_dir_all(dir.join("assets/empty")).unwrap();
+        std::fs::create_dir_all(dir.join("docs")).unwrap();
+        std::fs::write(dir.join("docs/.guide.md.ferry-part"), b"").unwrap();
+    }
+
+    fn names(tree: &Tree) -> Vec<&str> {
+        let mut names: Vec<_> = tree.files.iter().map(|(_, n)| n.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn directories_keep_their_structure_and_empty_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        tree_in(dir.path());
+        let tree = walk(dir.path(), "proj", &Filters::default(), false).unwrap();
+        assert_eq!(
+            names(&tree),
+            [
+                "proj/.gitignore",
+                "proj/README.md",
+                "proj/src/lib.rs",
+                "proj/src/main.rs",
+                "proj/target/debug/app"
+            ]
+        );
+        assert_eq!(tree.empty_dirs, ["proj/assets/empty", "proj/docs"]);
+    }
+
+    #[test]
+    fn globs_and_gitignore_filter_the_walk() {
+        let dir = tempfile::tempdir().unwrap();
+        tree_in(dir.path());
+
+        let filters = Filters::new(&["src/**".into()], &["*.md".into()], false).unwrap();
+        let tree = walk(dir.path(), "", &filters, false).unwrap();
+        assert_eq!(names(&tree), ["src/lib.rs", "src/main.rs"]);
+        assert!(tree.empty_dirs.is_empty(), "{:?}", tree.empty_dirs);
+
+        let filters = Filters::new(&[], &["assets".into()], true).unwrap();
+        let tree = walk(dir.path(), "", &filters, false).unwrap();
+        assert_eq!(
+            names(&tree),
+            [".gitignore", "README.md", "src/lib.rs", "src/main.rs"]
+        );
+        assert_eq!(tree.empty_dirs, ["docs"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_kept_only_when_asked() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("only-link")).unwrap();
+        std::fs::write(dir.path().join("target.txt"), b"t").unwrap();
+        std::os::unix::fs::symlink("../target.txt", dir.path().join("only-link/link")).unwrap();
+
+        let tree = walk(dir.path(), "", &Filters::default(), true).unwrap();
+        let links: Vec<_> = tree.links.iter().map(|(_, n)| n.as_str()).collect();
+        assert_eq!(links, ["only-link/link"]);
+        assert!(tree.empty_dirs.is_empty());
+
+        let tree = walk(dir.path(), "", &Filters::default(), false).unwrap();
+        assert!(tree.links.is_empty());
+        assert_eq!(tree.empty_dirs, ["only-link"]);
+    }
+
+    #[test]
+    fn an_empty_directory_is_sent_as_itself() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = walk(dir.path(), "out", &Filters::default(), false).unwrap();
+        assert!(tree.files.is_empty());
+        assert_eq!(tree.empty_dirs, ["out"]);
+    }
+}
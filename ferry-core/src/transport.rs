@@ -1,16 +1,94 @@
 mod tcp;
-mod quic;
+pub(crate) mod quic;
+mod cert_utils;
 pub mod factory;
+pub(crate) mod dual;
+pub(crate) mod race;
+#[cfg(test)]
+pub(crate) mod memory;
 
+use rustls::pki_types::CertificateDer;
 use std::net::SocketAddr;
-use anyhow;
+use std::sync::Arc;
+use std::time::Duration;
 
-/// Transport when there is a connection established
+/// Transport when there is a connection established.
+/// `send_data` and `receive_data` work on whole frames: every payload sent arrives as exactly one
+/// `receive_data` on the other side, so many messages can share one connection.
+/// `receive_data` is cancel safe, a frame half read when its future is dropped is kept for the next call.
 #[async_trait::async_trait]
 pub trait Transport {
     async fn send_data(&mut self, data: &[u8]) -> anyhow::Result<()>;
     async fn receive_data(&mut self,) -> anyhow::Result<Vec<u8>>;
     async fn close(&mut self) -> anyhow::Result<()>;
+    /// RFC 5705 keying material from the secure channel underneath, identical on both ends of
+    /// the same session and different for anyone relaying between two sessions
+    fn export_keying_material(&self, output: &mut [u8], label: &[u8], context: &[u8]) -> anyhow::Result<()>;
+    /// End-entity certificate the peer presented, if the channel has one
+    fn peer_certificate(&self) -> Option<CertificateDer<'static>>;
+    /// Extra one-way streams for bulk data, `None` when the transport only has the one stream
+    fn data_streams(&self) -> Option<Arc<dyn DataStreams>>;
+}
+
+/// Opens and accepts one-way streams next to the one `send_data` / `receive_data` use, so bulk data
+/// can flow over many at once without holding up control messages. Usable from any task.
+#[async_trait::async_trait]
+pub trait DataStreams: Send + Sync {
+    async fn open(&self) -> anyhow::Result<Box<dyn DataSender>>;
+    /// Waits for the peer to open its next stream
+    async fn accept(&self) -> anyhow::Result<Box<dyn DataReceiver>>;
+    /// What the connection underneath has measured so far
+    fn stats(&self) -> LinkStats;
+}
+
+/// Connection counters bulk transfers are paced by
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkStats {
+    /// Smoothed round trip time
+    pub rtt: Duration,
+    /// Everything put on the wire so far, headers and retransmissions included
+    pub bytes_sent: u64,
+}
+
+#[async_trait::async_trait]
+pub trait DataSender: Send {
+    async fn send_data(&mut self, data: &[u8]) -> anyhow::Result<()>;
+    /// Ends the stream once everything sent so far has been delivered
+    async fn finish(&mut self) -> anyhow::Result<()>;
+}
+
+#[async_trait::async_trait]
+pub trait DataReceiver: Send {
+    /// Next frame, `None` once the peer has finished the stream
+    async fn receive_data(&mut self) -> anyhow::Result<Option<Vec<u8>>>;
+}
+
+/// Lets callers pick a backend at runtime
+#[async_trait::async_trait]
+impl<T: Transport + Send + ?Sized> Transport for Box<T> {
+    async fn send_data(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        (**self).send_data(data).await
+    }
+
+    async fn receive_data(&mut self) -> anyhow::Result<Vec<u8>> {
+        (**self).receive_data().await
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        (**self).close().await
+    }
+
+    fn export_keying_material(&self, output: &mut [u8], label: &[u8], context: &[u8]) -> anyhow::Result<()> {
+        (**self).export_keying_material(output, label, context)
+    }
+
+    fn peer_certificate(&self) -> Option<CertificateDer<'static>> {
+        (**self).peer_certificate()
+    }
+
+    fn data_streams(&self) -> Option<Arc<dyn DataStreams>> {
+        (**self).data_streams()
+    }
 }
 
 /// Transport layer for establishing a connection
@@ -23,7 +101,18 @@ pub trait TransportClient{
 /// Transport layer for listening for a connection
 #[async_trait::async_trait]
 pub trait TransportServer {
-    type Conn: Transport;
+    type Conn: Transport + Send;
+    type Pending: PendingConnection<Conn = Self::Conn>;
     fn bind(&mut self,) -> anyhow::Result<()>;
-    async fn accept(&mut self,) -> anyhow::Result<Self::Conn>;
+    /// Waits for the next peer. Handshakes are left to [`PendingConnection::establish`] so a slow
+    /// peer can't hold up the accept loop.
+    async fn accept(&mut self,) -> anyhow::Result<Self::Pending>;
+}
+
+/// A peer that knocked but hasn't finished its handshake yet
+#[async_trait::async_trait]
+pub trait PendingConnection: Send + 'static {
+    type Conn: Transport + Send;
+    fn remote_addr(&self) -> SocketAddr;
+    async fn establish(self) -> anyhow::Result<Self::Conn>;
 }
\ No newline at end of file
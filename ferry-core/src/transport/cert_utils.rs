@@ -3,7 +3,9 @@ use rustls::client::danger;
 use rustls::{DigitallySignedStruct, SignatureScheme};
 use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
 
-// TODO: Plug this in
+/// Accepts self-signed certificates, servers are recognised by their pinned fingerprint once the
+/// handshake is done (see `identity.rs`). Handshake signatures are still checked so the peer has
+/// to hold the key of the certificate it shows.
 #[derive(Debug)]
 pub struct SkipServerVerification(Arc<rustls::crypto::CryptoProvider>);
 
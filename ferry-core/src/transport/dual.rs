@@ -0,0 +1,60 @@
+use crate::transport::{PendingConnection, Transport, TransportServer};
+use anyhow::Result;
+use std::net::SocketAddr;
+
+/// Listens with two transports at once, handing out connections from whichever has one
+pub(crate) struct Both<A, B>(pub A, pub B);
+
+#[async_trait::async_trait]
+impl<A, B> TransportServer for Both<A, B>
+where
+    A: TransportServer + Send,
+    B: TransportServer + Send,
+    A::Conn: 'static,
+    B::Conn: 'static,
+{
+    type Conn = Box<dyn Transport + Send>;
+    type Pending = Either<A::Pending, B::Pending>;
+
+    fn bind(&mut self) -> Result<()> {
+        self.0.bind()?;
+        self.1.bind()
+    }
+
+    async fn accept(&mut self) -> Result<Self::Pending> {
+        tokio::select! {
+            pending = self.0.accept() => Ok(Either::Left(pending?)),
+            pending = self.1.accept() => Ok(Either::Right(pending?)),
+        }
+    }
+}
+
+pub(crate) enum Either<L, R> {
+    Left(L),
+    Right(R),
+}
+
+#[async_trait::async_trait]
+impl<L, R> PendingConnection for Either<L, R>
+where
+    L: PendingConnection,
+    R: PendingConnection,
+    L::Conn: 'static,
+    R::Conn: 'static,
+{
+    type Conn = Box<dyn Transport + Send>;
+
+    fn remote_addr(&self) -> SocketAddr {
+        match self {
+            Either::Left(pending) => pending.remote_addr(),
+            Either::Right(pending) => pending.remote_addr(),
+        }
+    }
+
+    async fn establish(self) -> Result<Self::Conn> {
+        Ok(match self {
+            Either::Left(pending) => Box::new(pending.establish().await?),
+            Either::Right(pending) => Box::new(pending.establish().await?),
+        })
+    }
+}
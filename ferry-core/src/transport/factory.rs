@@ -1,14 +1,48 @@
 use std::net::SocketAddr;
 use quinn::ServerConfig;
+use crate::identity::ServerIdentity;
 use crate::transport::quic::server::make_server_config;
-use crate::transport::{TransportClient, TransportServer};
-use super::quic;
+use crate::transport::{Transport, TransportClient, TransportServer};
+use super::{quic, tcp};
 use anyhow::Result;
 use crate::transport::quic::client::QuicClient;
+use crate::transport::tcp::client::TcpClient;
 
-pub(crate) enum TransportType {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportType {
     Quic,
-    TCP
+    Tcp
+}
+
+impl TransportType {
+    /// Name used in the mDNS TXT record
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransportType::Quic => "quic",
+            TransportType::Tcp => "tcp",
+        }
+    }
+}
+
+impl std::fmt::Display for TransportType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransportType::Quic => write!(f, "QUIC"),
+            TransportType::Tcp => write!(f, "TCP"),
+        }
+    }
+}
+
+impl std::str::FromStr for TransportType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "quic" => Ok(TransportType::Quic),
+            "tcp" => Ok(TransportType::Tcp),
+            other => anyhow::bail!("unknown transport {other}"),
+        }
+    }
 }
 
 pub fn make_quic_server(ip_address:SocketAddr, server_config: Option<ServerConfig>)-> Result<impl TransportServer>{
@@ -20,11 +54,29 @@ pub fn make_quic_server(ip_address:SocketAddr, server_config: Option<ServerConfi
     Ok(quic_server)
 }
 
-pub fn make_tcp_server()-> Result<()>{
-    anyhow::bail!("TCP Server is not supported yet")
+pub(crate) fn make_quic_server_config(identity: &ServerIdentity, max_streams: u32) -> Result<ServerConfig> {
+    quic::server::make_server_config_for(identity, max_streams)
+}
+
+pub(crate) fn make_tcp_server(ip_address: SocketAddr, identity: &ServerIdentity) -> Result<impl TransportServer + use<>> {
+    let server_config = tcp::server::make_server_config_for(identity)?;
+    Ok(tcp::server::TcpServer::new(ip_address, server_config))
 }
 
-pub fn make_quic_client()->impl TransportClient{
-    let quic_client = QuicClient::new();
-    quic_client
-}
\ No newline at end of file
+pub fn make_tcp_client() -> impl TransportClient {
+    TcpClient::new()
+}
+
+/// Connects with the given transport, boxed so callers can pick one at runtime.
+/// QUIC connections go out from `quic`'s endpoint.
+pub(crate) async fn connect_with(
+    transport_type: TransportType,
+    quic: &QuicClient,
+    server_addr: SocketAddr,
+    server_name: &str,
+) -> Result<Box<dyn Transport + Send>> {
+    Ok(match transport_type {
+        TransportType::Quic => Box::new(quic.clone().connect(server_addr, server_name).await?),
+        TransportType::Tcp => Box::new(make_tcp_client().connect(server_addr, server_name).await?),
+    })
+}
@@ -0,0 +1,82 @@
+use crate::protocol::{FrameReader, write_frame};
+use crate::transport::DataStreams;
+use crate::transport::Transport;
+use anyhow::Context;
+use rustls::pki_types::CertificateDer;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+
+/// In-process transport over a tokio duplex pipe, for tests that don't need a real network
+pub(crate) struct MemoryTransport {
+    read: ReadHalf<DuplexStream>,
+    write: WriteHalf<DuplexStream>,
+    reader: FrameReader,
+    /// Stands in for the TLS session secret, shared by the two ends of a pair only
+    session: [u8; 32],
+}
+
+/// Two connected ends of an in-memory connection
+pub(crate) fn pair() -> (MemoryTransport, MemoryTransport) {
+    pair_buffered(1024 * 1024)
+}
+
+/// Like [`pair`], but each direction holds at most `buffer` bytes the other end hasn't read
+pub(crate) fn pair_buffered(buffer: usize) -> (MemoryTransport, MemoryTransport) {
+    static SESSIONS: AtomicU64 = AtomicU64::new(0);
+    let session = *blake3::hash(&SESSIONS.fetch_add(1, Ordering::SeqCst).to_le_bytes()).as_bytes();
+    let (a, b) = tokio::io::duplex(buffer);
+    let (a_read, a_write) = tokio::io::split(a);
+    let (b_read, b_write) = tokio::io::split(b);
+    (
+        MemoryTransport {
+            read: a_read,
+            write: a_write,
+            reader: FrameReader::default(),
+            session,
+        },
+        MemoryTransport {
+            read: b_read,
+            write: b_write,
+            reader: FrameReader::default(),
+            session,
+        },
+    )
+}
+
+#[async_trait::async_trait]
+impl Transport for MemoryTransport {
+    async fn send_data(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        write_frame(&mut self.write, data).await
+    }
+
+    async fn receive_data(&mut self) -> anyhow::Result<Vec<u8>> {
+        let frame = self.reader.next(&mut self.read).await?;
+        frame.context("stream closed by peer")
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        self.write.shutdown().await?;
+        Ok(())
+    }
+
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: &[u8],
+    ) -> anyhow::Result<()> {
+        let mut hasher = blake3::Hasher::new_keyed(&self.session);
+        hasher.update(label).update(context);
+        hasher.finalize_xof().fill(output);
+        Ok(())
+    }
+
+    fn peer_certificate(&self) -> Option<CertificateDer<'static>> {
+        None
+    }
+
+    fn data_streams(&self) -> Option<Arc<dyn DataStreams>> {
+        None
+    }
+}
@@ -2,7 +2,6 @@ pub mod client;
 pub mod server;
 pub mod connection;
 mod test;
-mod cert_utils;
 
 
 
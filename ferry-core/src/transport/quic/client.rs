@@ -1,26 +1,41 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use crate::transport::quic::connection::QuicTransport;
 use crate::transport::TransportClient;
 use anyhow::Result;
 use quinn::ClientConfig;
 use quinn::crypto::rustls::QuicClientConfig;
-use crate::transport::quic::cert_utils::SkipServerVerification;
+use crate::transport::cert_utils::SkipServerVerification;
 
+const KEEP_ALIVE: Duration = Duration::from_secs(5);
+
+/// Clones share the endpoint, so many connection attempts can go out from one UDP socket
+#[derive(Clone)]
 pub struct QuicClient {
-    server_addr: Option<SocketAddr>,
-    server_name: Option<String>, // for TLS? TODO: decide what to do with this
     endpoint: Option<quinn::Endpoint>,
 }
 
 impl QuicClient {
     pub fn new() -> Self {
         Self{
-            server_addr: None,
-            server_name: None,
             endpoint: None,
         }
     }
+
+    /// Client with its endpoint bound up front, for sharing it between clones
+    pub fn bound() -> Result<Self> {
+        Ok(Self {
+            endpoint: Some(make_endpoint()?),
+        })
+    }
+}
+
+fn make_endpoint() -> Result<quinn::Endpoint> {
+    let bind_addr: SocketAddr = "[::]:0".parse().unwrap();
+    let mut endpoint = quinn::Endpoint::client(bind_addr)?;
+    endpoint.set_default_client_config(make_insecure_client_config()?);
+    Ok(endpoint)
 }
 
 #[async_trait::async_trait]
@@ -29,12 +44,7 @@ impl TransportClient for QuicClient {
 
     async fn connect(&mut self, server_addr: SocketAddr, server_name: &str) -> Result<Self::Conn> {
         if self.endpoint.is_none() {
-            let bind_addr: SocketAddr = "[::]:0".parse().unwrap();
-
-            let mut endpoint = quinn::Endpoint::client(bind_addr)?;
-            let client_config = make_insecure_client_config()?;
-            endpoint.set_default_client_config(client_config);
-            self.endpoint = Some(endpoint);
+            self.endpoint = Some(make_endpoint()?);
         }
 
         let endpoint = self.endpoint.as_ref().expect("endpoint just set above");
@@ -58,6 +68,11 @@ fn make_insecure_client_config() -> std::result::Result<ClientConfig, quinn::cry
         .with_no_client_auth();
 
     let quic_crypto = QuicClientConfig::try_from(crypto)?;
-    Ok(ClientConfig::new(Arc::new(quic_crypto)))
+    let mut config = ClientConfig::new(Arc::new(quic_crypto));
+    // Quiet stretches, like a receiver deciding whether to take our files, must not time out
+    let mut transport = quinn::TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE));
+    config.transport_config(Arc::new(transport));
+    Ok(config)
 }
 
@@ -1,32 +1,99 @@
-use crate::transport::Transport;
+use std::time::Duration;
+use std::sync::Arc;
+use crate::protocol::{FrameReader, write_frame};
+use anyhow::Context;
+use crate::transport::{DataReceiver, DataSender, DataStreams, LinkStats, Transport};
+use rustls::pki_types::CertificateDer;
+
+/// How long `close` waits for the peer to acknowledge our last frames
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
 
 pub struct QuicTransport{
     connection: quinn::Connection,
     send: quinn::SendStream,
     recv: quinn::RecvStream,
+    reader: FrameReader,
 }
 
 impl QuicTransport {
     pub fn new(connection: quinn::Connection, send: quinn::SendStream, recv: quinn::RecvStream) -> Self {
-        Self { connection, send, recv }
+        Self { connection, send, recv, reader: FrameReader::default() }
     }
 }
 
 #[async_trait::async_trait]
 impl Transport for QuicTransport{
     async fn send_data(&mut self, data: &[u8]) -> anyhow::Result<()> {
-        self.send.write_all(data).await?;
-        self.send.finish()?;
-        Ok(())
+        write_frame(&mut self.send, data).await
     }
 
     async fn receive_data(&mut self) -> anyhow::Result<Vec<u8>> {
-        let buf = self.recv.read_to_end(usize::MAX).await?;
-        Ok(buf)
+        let frame = self.reader.next(&mut self.recv).await?;
+        frame.context("stream closed by peer")
     }
 
     async fn close(&mut self) -> anyhow::Result<()> {
+        // Closing the connection straight away would discard frames still in flight
+        let _ = self.send.finish();
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, self.send.stopped()).await;
         self.connection.close(0u32.into(), b"");
         Ok(())
     }
+
+    fn export_keying_material(&self, output: &mut [u8], label: &[u8], context: &[u8]) -> anyhow::Result<()> {
+        self.connection
+            .export_keying_material(output, label, context)
+            .map_err(|_| anyhow::anyhow!("TLS session cannot export keying material"))
+    }
+
+    fn peer_certificate(&self) -> Option<CertificateDer<'static>> {
+        let chain = self.connection.peer_identity()?.downcast::<Vec<CertificateDer<'static>>>().ok()?;
+        chain.into_iter().next()
+    }
+
+    fn data_streams(&self) -> Option<Arc<dyn DataStreams>> {
+        Some(Arc::new(QuicDataStreams(self.connection.clone())))
+    }
+}
+
+/// Unidirectional QUIC streams on the same connection, each with its own flow control
+struct QuicDataStreams(quinn::Connection);
+
+#[async_trait::async_trait]
+impl DataStreams for QuicDataStreams {
+    async fn open(&self) -> anyhow::Result<Box<dyn DataSender>> {
+        Ok(Box::new(QuicDataSender(self.0.open_uni().await?)))
+    }
+
+    async fn accept(&self) -> anyhow::Result<Box<dyn DataReceiver>> {
+        Ok(Box::new(QuicDataReceiver(self.0.accept_uni().await?, FrameReader::default())))
+    }
+
+    fn stats(&self) -> LinkStats {
+        let stats = self.0.stats();
+        LinkStats { rtt: stats.path.rtt, bytes_sent: stats.udp_tx.bytes }
+    }
+}
+
+struct QuicDataSender(quinn::SendStream);
+
+#[async_trait::async_trait]
+impl DataSender for QuicDataSender {
+    async fn send_data(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        write_frame(&mut self.0, data).await
+    }
+
+    async fn finish(&mut self) -> anyhow::Result<()> {
+        self.0.finish()?;
+        Ok(())
+    }
+}
+
+struct QuicDataReceiver(quinn::RecvStream, FrameReader);
+
+#[async_trait::async_trait]
+impl DataReceiver for QuicDataReceiver {
+    async fn receive_data(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        self.1.next(&mut self.0).await
+    }
 }
\ No newline at end of file
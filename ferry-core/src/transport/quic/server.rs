@@ -1,6 +1,8 @@
 use std::net::SocketAddr;
+use std::sync::Arc;
+use crate::identity::ServerIdentity;
 use crate::transport::quic::connection::QuicTransport;
-use crate::transport::TransportServer;
+use crate::transport::{PendingConnection, TransportServer};
 use anyhow::{anyhow, Result};
 use quinn::ServerConfig;
 use rcgen::generate_simple_self_signed;
@@ -25,6 +27,7 @@ impl QuicServer {
 #[async_trait::async_trait]
 impl TransportServer for QuicServer {
     type Conn = QuicTransport;
+    type Pending = QuicIncoming;
 
     fn bind(&mut self) -> Result<()>{
         if self.endpoint.is_some(){
@@ -36,18 +39,33 @@ impl TransportServer for QuicServer {
         Ok(())
     }
 
-    async fn accept(&mut self) -> Result<Self::Conn> {
+    async fn accept(&mut self) -> Result<Self::Pending> {
         let endpoint = self
             .endpoint
             .as_ref()
             .ok_or_else(|| anyhow!("listen() must be called before accept()"))?;
         let incoming_opt = endpoint.accept().await;
         let incoming = incoming_opt.ok_or_else(|| anyhow!("endpoint closed"))?;
-        let connection = incoming.await?;
+        Ok(QuicIncoming(incoming))
+    }
+
+}
+
+pub struct QuicIncoming(quinn::Incoming);
+
+#[async_trait::async_trait]
+impl PendingConnection for QuicIncoming {
+    type Conn = QuicTransport;
+
+    fn remote_addr(&self) -> SocketAddr {
+        self.0.remote_address()
+    }
+
+    async fn establish(self) -> Result<Self::Conn> {
+        let connection = self.0.await?;
         let (send, recv) = connection.accept_bi().await?;
         Ok(QuicTransport::new(connection, send, recv))
     }
-
 }
 
 pub fn generate_self_signed_cert() -> Result<(CertificateDer<'static>, PrivatePkcs8KeyDer<'static>)> {
@@ -62,3 +80,14 @@ pub fn make_server_config() -> Result<ServerConfig> {
     let server_config = ServerConfig::with_single_cert(vec![cert], rustls::pki_types::PrivateKeyDer::Pkcs8(key))?;
     Ok(server_config)
 }
+
+/// Server config presenting the server's long lived certificate, letting each client open up to
+/// `max_streams` data streams at once
+pub(crate) fn make_server_config_for(identity: &ServerIdentity, max_streams: u32) -> Result<ServerConfig> {
+    let key = rustls::pki_types::PrivateKeyDer::Pkcs8(identity.key.clone_key());
+    let mut server_config = ServerConfig::with_single_cert(vec![identity.cert.clone()], key)?;
+    let mut transport = quinn::TransportConfig::default();
+    transport.max_concurrent_uni_streams(max_streams.into());
+    server_config.transport_config(Arc::new(transport));
+    Ok(server_config)
+}
@@ -1,11 +1,11 @@
 #[cfg(test)]
-mod test {
+mod tests {
     use std::net::SocketAddr;
     use std::time::Duration;
     use tokio::time::sleep;
     use crate::transport::quic::client::QuicClient;
-    use crate::transport::quic::server::{generate_self_signed_cert, make_server_config, QuicServer};
-    use crate::transport::{TransportClient, TransportServer};
+    use crate::transport::quic::server::{make_server_config, QuicServer};
+    use crate::transport::{PendingConnection, TransportClient, TransportServer};
     use crate::transport::Transport; // or the correct path to your trait
 
     #[tokio::test(flavor = "multi_thread")]
@@ -24,7 +24,7 @@ mod test {
             .expect("server addr");
 
         let server_task = tokio::spawn(async move {
-            let mut conn = server.accept().await?;
+            let mut conn = server.accept().await?.establish().await?;
 
             let data = conn.receive_data().await?;
             conn.send_data(&data).await?;
@@ -59,13 +59,42 @@ mod test {
         }
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn quic_many_messages_on_one_connection() -> anyhow::Result<()> {
+        let server_cfg = make_server_config().expect("Failed to make server config");
 
+        let bind_addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut server = QuicServer::new(bind_addr, server_cfg);
+        server.bind()?;
+        let server_addr = server.endpoint.as_ref().unwrap().local_addr()?;
 
+        let server_task = tokio::spawn(async move {
+            let mut conn = server.accept().await?.establish().await?;
+            let mut received = Vec::new();
+            loop {
+                let data = conn.receive_data().await?;
+                if data.is_empty() {
+                    break;
+                }
+                received.push(data);
+            }
+            conn.send_data(&(received.len() as u32).to_be_bytes()).await?;
+            conn.close().await?;
+            Ok::<Vec<Vec<u8>>, anyhow::Error>(received)
+        });
 
+        let mut client = QuicClient::new();
+        let mut conn = client.connect(server_addr, "localhost").await?;
+        let msgs: Vec<Vec<u8>> = (1..=10u8).map(|i| vec![i; i as usize * 1000]).collect();
+        for msg in &msgs {
+            conn.send_data(msg).await?;
+        }
+        conn.send_data(&[]).await?;
+        let count = conn.receive_data().await?;
+        assert_eq!(count, 10u32.to_be_bytes());
+        conn.close().await?;
 
-
-
-
-
-
+        assert_eq!(server_task.await??, msgs);
+        Ok(())
+    }
 }
\ No newline at end of file
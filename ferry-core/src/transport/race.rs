@@ -0,0 +1,188 @@
+use crate::transport::Transport;
+use crate::transport::quic::client::QuicClient;
+use crate::transport::factory::{self, TransportType};
+use anyhow::Result;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::task::JoinSet;
+
+/// Head start each attempt gets before the next one joins the race (RFC 8305 suggests 250ms)
+pub(crate) const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// A connection that won the race, and how it was made
+pub(crate) struct Connected {
+    pub(crate) transport: Box<dyn Transport + Send>,
+    pub(crate) addr: SocketAddr,
+    pub(crate) transport_type: TransportType,
+}
+
+/// Every address with every transport, addresses in order of preference. Each address gets all
+/// its transports tried before moving on, so a dead address costs one delay per transport.
+pub(crate) fn plan(
+    addrs: &[SocketAddr],
+    transports: &[TransportType],
+) -> Vec<(SocketAddr, TransportType)> {
+    addrs
+        .iter()
+        .flat_map(|addr| transports.iter().map(move |t| (*addr, *t)))
+        .collect()
+}
+
+/// Happy eyeballs (RFC 8305): tries `attempts` in order, starting the next one whenever the
+/// previous hasn't connected within [`ATTEMPT_DELAY`] or has failed. The first handshake to
+/// complete wins and the rest are cancelled. QUIC attempts all share one endpoint.
+pub(crate) async fn connect_first(
+    attempts: &[(SocketAddr, TransportType)],
+    server_name: &str,
+) -> Result<Connected> {
+    let quic = match attempts.iter().any(|(_, t)| *t == TransportType::Quic) {
+        true => QuicClient::bound()?,
+        false => QuicClient::new(),
+    };
+    let mut pending = attempts.iter().copied();
+    let mut running = JoinSet::new();
+    let mut errors = Vec::new();
+    loop {
+        if let Some((addr, transport_type)) = pending.next() {
+            let server_name = server_name.to_string();
+            let quic = quic.clone();
+            running.spawn(async move {
+                let res = factory::connect_with(transport_type, &quic, addr, &server_name).await;
+                (addr, transport_type, res)
+            });
+        } else if running.is_empty() {
+            break;
+        }
+
+        // Wait for a winner, a failure (start the next attempt right away) or the delay to pass
+        tokio::select! {
+            Some(done) = running.join_next() => {
+                let (addr, transport_type, res) = done?;
+                match res {
+                    Ok(transport) => {
+                        return Ok(Connected {
+                            transport,
+                            addr,
+                            transport_type,
+                        });
+                    }
+                    Err(e) => {
+                        log::debug!("{transport_type} to {addr} failed: {e:#}");
+                        errors.push(format!("{transport_type} {addr}: {e:#}"));
+                    }
+                }
+            }
+            _ = tokio::time::sleep(ATTEMPT_DELAY), if pending.len() > 0 => {}
+            else => {}
+        }
+    }
+    match errors.is_empty() {
+        true => anyhow::bail!("nothing to connect to"),
+        false => anyhow::bail!("could not connect ({})", errors.join("; ")),
+    }
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::ServerIdentity;
+    use crate::transport::dual::Both;
+    use crate::transport::{PendingConnection, TransportClient, TransportServer};
+
+    /// Serves whatever connects on `server` until the test ends
+    fn serve<S: TransportServer + Send + 'static>(mut server: S) {
+        tokio::spawn(async move {
+            while let Ok(pending) = server.accept().await {
+                tokio::spawn(async move {
+                    if let Ok(mut conn) = pending.establish().await {
+                        let _ = conn.receive_data().await;
+                    }
+                });
+            }
+        });
+    }
+
+    fn free_port() -> SocketAddr {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        // Same number free on TCP too, or bind fails below
+        std::net::TcpListener::bind(addr)
+            .map(|_| addr)
+            .unwrap_or_else(|_| free_port())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn quic_wins_when_both_answer() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let identity = ServerIdentity::load_or_create(dir.path())?;
+        let addr = free_port();
+        let quic =
+            factory::make_quic_server(addr, Some(factory::make_quic_server_config(&identity, 8)?))?;
+        let mut server = Both(quic, factory::make_tcp_server(addr, &identity)?);
+        server.bind()?;
+        serve(server);
+
+        let attempts = [(addr, TransportType::Quic), (addr, TransportType::Tcp)];
+        let mut connected = connect_first(&attempts, "localhost").await?;
+        assert_eq!(connected.transport_type, TransportType::Quic);
+        connected.transport.send_data(b"hi").await?;
+
+        // The server takes TCP on the same port too
+        let mut tcp = factory::make_tcp_client().connect(addr, "localhost").await?;
+        tcp.send_data(b"hi").await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn falls_back_to_tcp_when_quic_is_silent() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let identity = ServerIdentity::load_or_create(dir.path())?;
+        let addr = free_port();
+        let mut server = factory::make_tcp_server(addr, &identity)?;
+        server.bind()?;
+        serve(server);
+
+        let attempts = [(addr, TransportType::Quic), (addr, TransportType::Tcp)];
+        let connected = tokio::time::timeout(
+            Duration::from_secs(5),
+            connect_first(&attempts, "localhost"),
+        )
+        .await??;
+        assert_eq!(connected.transport_type, TransportType::Tcp);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn every_failure_is_reported() {
+        let addr = free_port();
+        let err = connect_first(&[(addr, TransportType::Tcp)], "localhost")
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains(&format!("TCP {addr}")), "{err}");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dead_addresses_are_skipped() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let identity = ServerIdentity::load_or_create(dir.path())?;
+        let addr = free_port();
+        let mut server = factory::make_tcp_server(addr, &identity)?;
+        server.bind()?;
+        serve(server);
+
+        // Completes the TCP handshake in the kernel but never answers TLS
+        let silent = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let dead = silent.local_addr()?;
+        let attempts = plan(&[dead, addr], &[TransportType::Tcp]);
+        assert_eq!(attempts, vec![(dead, TransportType::Tcp), (addr, TransportType::Tcp)]);
+        let connected = tokio::time::timeout(
+            Duration::from_secs(2),
+            connect_first(&attempts, "localhost"),
+        )
+        .await??;
+        assert_eq!(connected.addr, addr);
+        Ok(())
+    }
+}
@@ -0,0 +1,4 @@
+pub mod client;
+pub mod connection;
+pub mod server;
+mod test;
@@ -0,0 +1,49 @@
+use crate::transport::TransportClient;
+use crate::transport::cert_utils::SkipServerVerification;
+use crate::transport::tcp::connection::TcpTransport;
+use anyhow::Result;
+use rustls::pki_types::ServerName;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::{TlsConnector, TlsStream};
+
+pub struct TcpClient {
+    connector: Option<TlsConnector>,
+}
+
+impl TcpClient {
+    pub fn new() -> Self {
+        Self { connector: None }
+    }
+}
+
+#[async_trait::async_trait]
+impl TransportClient for TcpClient {
+    type Conn = TcpTransport;
+
+    async fn connect(&mut self, server_addr: SocketAddr, server_name: &str) -> Result<Self::Conn> {
+        if self.connector.is_none() {
+            self.connector = Some(TlsConnector::from(Arc::new(make_insecure_client_config()?)));
+        }
+        let connector = self.connector.as_ref().expect("connector just set above");
+
+        let stream = TcpStream::connect(server_addr).await?;
+        stream.set_nodelay(true)?;
+        // Certificates aren't checked against the name, any valid DNS name will do for SNI
+        let name = ServerName::try_from(server_name.to_string())
+            .unwrap_or_else(|_| ServerName::try_from("localhost").expect("valid DNS name"));
+        let stream = connector.connect(name, stream).await?;
+        Ok(TcpTransport::new(TlsStream::Client(stream)))
+    }
+}
+
+fn make_insecure_client_config() -> Result<rustls::ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(SkipServerVerification::new())
+        .with_no_client_auth();
+    Ok(config)
+}
@@ -0,0 +1,75 @@
+use crate::protocol::{FrameReader, write_frame};
+use crate::transport::{DataStreams, Transport};
+use anyhow::Context;
+use rustls::pki_types::CertificateDer;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsStream;
+
+/// Frames over a TLS protected TCP stream, same layout as on a QUIC stream
+pub struct TcpTransport {
+    stream: TlsStream<TcpStream>,
+    reader: FrameReader,
+}
+
+impl TcpTransport {
+    pub fn new(stream: TlsStream<TcpStream>) -> Self {
+        Self {
+            stream,
+            reader: FrameReader::default(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Transport for TcpTransport {
+    async fn send_data(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        write_frame(&mut self.stream, data).await?;
+        // TLS buffers records until flushed
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    async fn receive_data(&mut self) -> anyhow::Result<Vec<u8>> {
+        let frame = self.reader.next(&mut self.stream).await?;
+        frame.context("stream closed by peer")
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        // Sends close_notify, then the FIN
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: &[u8],
+    ) -> anyhow::Result<()> {
+        let res = match &self.stream {
+            TlsStream::Client(s) => {
+                s.get_ref()
+                    .1
+                    .export_keying_material(output, label, Some(context))
+            }
+            TlsStream::Server(s) => {
+                s.get_ref()
+                    .1
+                    .export_keying_material(output, label, Some(context))
+            }
+        };
+        res.map(|_| ())
+            .map_err(|e| anyhow::anyhow!("TLS session cannot export keying material: {e}"))
+    }
+
+    fn peer_certificate(&self) -> Option<CertificateDer<'static>> {
+        let (_, session) = self.stream.get_ref();
+        session.peer_certificates()?.first().cloned()
+    }
+
+    fn data_streams(&self) -> Option<Arc<dyn DataStreams>> {
+        None
+    }
+}
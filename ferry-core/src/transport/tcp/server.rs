@@ -0,0 +1,93 @@
+use crate::identity::ServerIdentity;
+use crate::transport::tcp::connection::TcpTransport;
+use crate::transport::{PendingConnection, TransportServer};
+use anyhow::{Context, Result, anyhow};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{TlsAcceptor, TlsStream};
+
+/// A peer that connects and never finishes the TLS handshake is dropped after this long
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct TcpServer {
+    bind_addr: SocketAddr,
+    acceptor: TlsAcceptor,
+    pub(crate) listener: Option<TcpListener>,
+}
+
+impl TcpServer {
+    pub fn new(bind_addr: SocketAddr, server_config: Arc<rustls::ServerConfig>) -> Self {
+        Self {
+            bind_addr,
+            acceptor: TlsAcceptor::from(server_config),
+            listener: None,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl TransportServer for TcpServer {
+    type Conn = TcpTransport;
+    type Pending = TcpIncoming;
+
+    fn bind(&mut self) -> Result<()> {
+        if self.listener.is_some() {
+            return Ok(());
+        }
+        let listener = std::net::TcpListener::bind(self.bind_addr)?;
+        listener.set_nonblocking(true)?;
+        self.listener = Some(TcpListener::from_std(listener)?);
+        Ok(())
+    }
+
+    async fn accept(&mut self) -> Result<Self::Pending> {
+        let listener = self
+            .listener
+            .as_ref()
+            .ok_or_else(|| anyhow!("listen() must be called before accept()"))?;
+        let (stream, remote) = listener.accept().await?;
+        Ok(TcpIncoming {
+            stream,
+            remote,
+            acceptor: self.acceptor.clone(),
+        })
+    }
+}
+
+pub struct TcpIncoming {
+    stream: TcpStream,
+    remote: SocketAddr,
+    acceptor: TlsAcceptor,
+}
+
+#[async_trait::async_trait]
+impl PendingConnection for TcpIncoming {
+    type Conn = TcpTransport;
+
+    fn remote_addr(&self) -> SocketAddr {
+        self.remote
+    }
+
+    async fn establish(self) -> Result<Self::Conn> {
+        self.stream.set_nodelay(true)?;
+        let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, self.acceptor.accept(self.stream))
+            .await
+            .context("TLS handshake timed out")??;
+        Ok(TcpTransport::new(TlsStream::Server(stream)))
+    }
+}
+
+/// TLS config presenting the server's long lived certificate
+pub(crate) fn make_server_config_for(
+    identity: &ServerIdentity,
+) -> Result<Arc<rustls::ServerConfig>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let key = rustls::pki_types::PrivateKeyDer::Pkcs8(identity.key.clone_key());
+    let config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(vec![identity.cert.clone()], key)?;
+    Ok(Arc::new(config))
+}
@@ -0,0 +1,71 @@
+#[cfg(test)]
+mod tests {
+    use crate::identity::{Fingerprint, ServerIdentity};
+    use crate::transport::tcp::client::TcpClient;
+    use crate::transport::tcp::server::{TcpServer, make_server_config_for};
+    use crate::transport::{PendingConnection, Transport, TransportClient, TransportServer};
+    use std::net::SocketAddr;
+
+    async fn bound_server()
+    -> anyhow::Result<(TcpServer, SocketAddr, Fingerprint, tempfile::TempDir)> {
+        let dir = tempfile::tempdir()?;
+        let identity = ServerIdentity::load_or_create(dir.path())?;
+        let bind_addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut server = TcpServer::new(bind_addr, make_server_config_for(&identity)?);
+        server.bind()?;
+        let server_addr = server.listener.as_ref().unwrap().local_addr()?;
+        Ok((server, server_addr, identity.fingerprint(), dir))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tcp_many_messages_on_one_connection() -> anyhow::Result<()> {
+        let (mut server, server_addr, _, _dir) = bound_server().await?;
+
+        let server_task = tokio::spawn(async move {
+            let mut conn = server.accept().await?.establish().await?;
+            loop {
+                let data = conn.receive_data().await?;
+                if data.is_empty() {
+                    break;
+                }
+                conn.send_data(&data).await?;
+            }
+            conn.close().await?;
+            Ok::<(), anyhow::Error>(())
+        });
+
+        let mut client = TcpClient::new();
+        let mut conn = client.connect(server_addr, "localhost").await?;
+        for i in 0..100u32 {
+            let msg = vec![i as u8; i as usize * 100 + 1];
+            conn.send_data(&msg).await?;
+            assert_eq!(conn.receive_data().await?, msg);
+        }
+        conn.send_data(&[]).await?;
+        server_task.await??;
+        assert!(conn.receive_data().await.is_err(), "server hung up");
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tcp_sessions_agree_on_keys_and_show_the_server_certificate() -> anyhow::Result<()> {
+        let (mut server, server_addr, fingerprint, _dir) = bound_server().await?;
+
+        let server_task = tokio::spawn(async move {
+            let conn = server.accept().await?.establish().await?;
+            let mut key = [0u8; 32];
+            conn.export_keying_material(&mut key, b"label", b"")?;
+            Ok::<_, anyhow::Error>(key)
+        });
+
+        let conn = TcpClient::new()
+            .connect(server_addr, "not a dns name")
+            .await?;
+        let mut key = [0u8; 32];
+        conn.export_keying_material(&mut key, b"label", b"")?;
+        assert_eq!(server_task.await??, key);
+        let cert = conn.peer_certificate().expect("server certificate");
+        assert_eq!(Fingerprint::of(&cert), fingerprint);
+        Ok(())
+    }
+}
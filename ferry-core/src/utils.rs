@@ -1 +1,2 @@
 pub(super) mod name_generator;
+pub(crate) mod size;
\ No newline at end of file
@@ -0,0 +1,118 @@
+use anyhow::{Context, bail};
+
+const UNITS: [(&str, u64); 13] = [
+    ("b", 1),
+    ("k", 1 << 10),
+    ("kib", 1 << 10),
+    ("kb", 1_000),
+    ("m", 1 << 20),
+    ("mib", 1 << 20),
+    ("mb", 1_000_000),
+    ("g", 1 << 30),
+    ("gib", 1 << 30),
+    ("gb", 1_000_000_000),
+    ("t", 1 << 40),
+    ("tib", 1 << 40),
+    ("tb", 1_000_000_000_000),
+];
+
+/// Parses a human size like `512`, `64KiB`, `20MB` or `1.5G` into bytes.
+/// SI suffixes (KB, MB, ...) are powers of 1000, IEC ones (KiB, MiB, ...) and bare letters powers of 1024.
+pub fn parse_size(input: &str) -> anyhow::Result<u64> {
+    let s = input.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    if number.is_empty() {
+        bail!("{input:?} is not a size, expected something like 20MB");
+    }
+    let number: f64 = number
+        .parse()
+        .with_context(|| format!("{input:?} is not a size, expected something like 20MB"))?;
+    let unit = unit.trim().to_ascii_lowercase();
+    let Some((_, multiplier)) = UNITS
+        .iter()
+        .find(|(name, _)| unit.is_empty() || *name == unit)
+    else {
+        bail!("unknown size unit {unit:?} in {input:?}");
+    };
+    let bytes = number * *multiplier as f64;
+    if bytes > u64::MAX as f64 {
+        bail!("{input:?} is too large");
+    }
+    Ok(bytes.round() as u64)
+}
+
+/// Parses a transfer rate like `20MB/s` or `512KiB` into bytes per second
+pub fn parse_rate(input: &str) -> anyhow::Result<u64> {
+    let s = input.trim();
+    let size = s.strip_suffix("/s").unwrap_or(s);
+    let bytes = parse_size(size)?;
+    if bytes == 0 {
+        bail!("{input:?} would never send anything");
+    }
+    Ok(bytes)
+}
+
+/// Formats bytes for humans, e.g. `1.5 MiB`
+pub fn format_size(bytes: u64) -> String {
+    const NAMES: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < NAMES.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{bytes} B"),
+        _ => format!("{value:.1} {}", NAMES[unit]),
+    }
+}
+
+// inline tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_plain_and_suffixed_sizes() {
+        let cases = [
+            ("512", 512),
+            ("64K", 64 * 1024),
+            ("64KiB", 64 * 1024),
+            ("64kb", 64_000),
+            ("20MB", 20_000_000),
+            ("20 MiB", 20 * 1024 * 1024),
+            ("2GB", 2_000_000_000),
+            ("1.5G", 1024 * 1024 * 1024 * 3 / 2),
+            ("1tb", 1_000_000_000_000),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(parse_size(input).unwrap(), expected, "failed for {input}");
+        }
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        for input in ["", "MB", "12XB", "1..2K", "-5"] {
+            assert!(parse_size(input).is_err(), "{input} should not parse");
+        }
+    }
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("20MB/s").unwrap(), 20_000_000);
+        assert_eq!(parse_rate("512KiB").unwrap(), 512 * 1024);
+        assert!(parse_rate("0/s").is_err());
+        assert!(parse_rate("fast").is_err());
+    }
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+}